@@ -21,10 +21,10 @@ use std::{
 #[cfg(target_os = "android")]
 use {
     android_activity::AndroidApp,
+    crusader_lib::plot::PlotFormat,
     crusader_lib::test::PlotConfig,
     eframe::{NativeOptions, Renderer, Theme},
     log::Level,
-    std::fs,
     winit::platform::android::EventLoopBuilderExtAndroid,
 };
 
@@ -74,8 +74,10 @@ impl eframe::App for App {
                 });
 
                 LOADED_FILE.lock().unwrap().take().map(|(name, data)| {
-                    RawResult::load_from_reader(Cursor::new(data))
-                        .map(|data| self.tester.load_file(name, data));
+                    match RawResult::load_from_reader(Cursor::new(data)) {
+                        Ok(data) => self.tester.load_file(name, data),
+                        Err(error) => self.tester.load_failed(name, error),
+                    }
                 });
 
                 self.tester.show(ctx, ui);
@@ -169,8 +171,6 @@ fn android_main(app: AndroidApp) {
         .internal_data_path()
         .map(|path| path.join("settings.toml"));
 
-    let temp_plot = app.internal_data_path().map(|path| path.join("plot.png"));
-
     let mut options = NativeOptions::default();
     options.follow_system_theme = false;
     options.default_theme = Theme::Light;
@@ -180,17 +180,14 @@ fn android_main(app: AndroidApp) {
     }));
     let mut tester = Tester::new(settings);
     tester.file_loader = Some(Box::new(|_| load_file().unwrap()));
-    tester.plot_saver = Some(Box::new(move |result| {
-        let path = temp_plot.as_deref().unwrap();
-        crusader_lib::plot::save_graph_to_path(path, &PlotConfig::default(), result);
-        let data = fs::read(path).unwrap();
-        fs::remove_file(path).unwrap();
+    tester.plot_saver = Some(Box::new(|result| {
+        let data = crusader_lib::plot::render(&PlotConfig::default(), result, PlotFormat::Png);
         let name = format!("{}.png", crusader_lib::test::timed("plot"));
         save_file(true, name, data).unwrap();
     }));
     tester.raw_saver = Some(Box::new(|result| {
         let mut writer = Cursor::new(Vec::new());
-        result.save_to_writer(&mut writer);
+        result.save_to_writer(&mut writer).unwrap();
         let data = writer.into_inner();
         let name = format!("{}.crr", crusader_lib::test::timed("data"));
         save_file(false, name, data).unwrap();
@@ -9,21 +9,24 @@ use std::{
     fs, mem,
     path::{Path, PathBuf},
     sync::Arc,
+    thread,
     time::Duration,
 };
 
 use crusader_lib::{
-    file_format::RawResult,
+    discovery,
+    file_format::{LoadError, RawMetadata, RawResult},
     latency,
-    plot::{self, float_max, to_rates},
-    protocol, serve,
+    plot::{self, float_max, RateFilter, Smoothing},
+    protocol::{self, PreSharedKey},
+    serve,
     test::{self, Config, PlotConfig},
 };
 use eframe::{
     egui::{
         self,
         plot::{Legend, Line, LinkedAxisGroup, LinkedCursorsGroup, Plot, PlotPoints},
-        Grid, Layout, ScrollArea, TextEdit, TextStyle, Ui,
+        ComboBox, Grid, Layout, ScrollArea, TextEdit, TextStyle, Ui,
     },
     emath::{vec2, Align},
     epaint::Color32,
@@ -83,16 +86,21 @@ enum Tab {
 #[serde(default)]
 pub struct LatencyMonitorSettings {
     pub server: String,
+    pub psk: String,
     pub history: f64,
     pub latency_sample_rate: u64,
+    /// Save every ping of a session to a file, beyond the history shown.
+    pub record: bool,
 }
 
 impl Default for LatencyMonitorSettings {
     fn default() -> Self {
         Self {
             server: "localhost".to_owned(),
+            psk: String::new(),
             history: 60.0,
             latency_sample_rate: 5,
+            record: false,
         }
     }
 }
@@ -101,6 +109,7 @@ impl Default for LatencyMonitorSettings {
 #[serde(default)]
 pub struct ClientSettings {
     pub server: String,
+    pub psk: String,
     pub download: bool,
     pub upload: bool,
     pub both: bool,
@@ -110,12 +119,15 @@ pub struct ClientSettings {
     pub stream_stagger: f64,
     pub latency_sample_rate: u64,
     pub bandwidth_sample_rate: u64,
+    pub label: String,
+    pub notes: String,
 }
 
 impl Default for ClientSettings {
     fn default() -> Self {
         Self {
             server: "localhost".to_owned(),
+            psk: String::new(),
             download: true,
             upload: true,
             both: true,
@@ -125,6 +137,62 @@ impl Default for ClientSettings {
             stream_stagger: 0.0,
             latency_sample_rate: 5,
             bandwidth_sample_rate: 20,
+            label: String::new(),
+            notes: String::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SmoothingMethod {
+    None,
+    MovingAverage,
+    Ewma,
+}
+
+impl SmoothingMethod {
+    fn name(&self) -> &'static str {
+        match self {
+            SmoothingMethod::None => "None",
+            SmoothingMethod::MovingAverage => "Moving average",
+            SmoothingMethod::Ewma => "EWMA",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ResultSettings {
+    pub smoothing: SmoothingMethod,
+    /// The moving average window or EWMA time constant in milliseconds.
+    pub smoothing_window: f64,
+    pub resample: bool,
+    pub resample_interval: f64,
+}
+
+impl Default for ResultSettings {
+    fn default() -> Self {
+        Self {
+            smoothing: SmoothingMethod::None,
+            smoothing_window: 200.0,
+            resample: false,
+            resample_interval: 100.0,
+        }
+    }
+}
+
+impl ResultSettings {
+    fn rate_filter(&self) -> RateFilter {
+        let window = Duration::from_secs_f64(self.smoothing_window / 1000.0);
+        RateFilter {
+            interval: self
+                .resample
+                .then(|| Duration::from_secs_f64(self.resample_interval / 1000.0)),
+            smoothing: match self.smoothing {
+                SmoothingMethod::None => Smoothing::None,
+                SmoothingMethod::MovingAverage => Smoothing::MovingAverage(window),
+                SmoothingMethod::Ewma => Smoothing::Ewma(window),
+            },
         }
     }
 }
@@ -134,6 +202,7 @@ impl Default for ClientSettings {
 pub struct Settings {
     pub client: ClientSettings,
     pub latency_monitor: LatencyMonitorSettings,
+    pub result: ResultSettings,
 }
 
 impl Settings {
@@ -157,6 +226,7 @@ pub struct Tester {
     result_plot_reset: bool,
     result: Option<TestResult>,
     result_saved: Option<String>,
+    result_load_error: Option<String>,
     raw_result: Option<RawResult>,
     raw_result_saved: Option<String>,
     msgs: Vec<String>,
@@ -175,10 +245,15 @@ pub struct Tester {
     latency_stop: Duration,
     latency_error: Option<String>,
     latency_plot_reset: bool,
+    latency_recording: Option<String>,
+
+    discovery: Option<oneshot::Receiver<Vec<discovery::Server>>>,
+    discovered: Option<Vec<discovery::Server>>,
 }
 
 pub struct TestResult {
     result: plot::TestResult,
+    filter: RateFilter,
     download: Vec<(f64, f64)>,
     upload: Vec<(f64, f64)>,
     both: Vec<(f64, f64)>,
@@ -191,11 +266,8 @@ pub struct TestResult {
 }
 
 impl TestResult {
-    fn new(result: plot::TestResult) -> Self {
+    fn new(result: plot::TestResult, filter: RateFilter) -> Self {
         let start = result.start.as_secs_f64();
-        let download = handle_bytes(&result.combined_download_bytes, start);
-        let upload = handle_bytes(&result.combined_upload_bytes, start);
-        let both = handle_bytes(result.both_bytes.as_deref().unwrap_or(&[]), start);
 
         let latency: Vec<_> = result
             .pings
@@ -243,42 +315,62 @@ impl TestResult {
             .filter(|p| p.sent >= result.start)
             .filter_map(|ping| {
                 if ping.latency.and_then(|latency| latency.total).is_none() {
-                    let down_loss =
-                        (result.raw_result.version >= 2).then_some(ping.latency.is_some());
+                    let down_loss = (!result.raw_result.inferred.loss_direction)
+                        .then_some(ping.latency.is_some());
                     Some((ping.sent.as_secs_f64() - start, down_loss))
                 } else {
                     None
                 }
             })
             .collect();
-        let download_max = float_max(download.iter().map(|v| v.1));
-        let upload_max = float_max(upload.iter().map(|v| v.1));
-        let both_max = float_max(both.iter().map(|v| v.1));
-        let bandwidth_max = float_max([download_max, upload_max, both_max].into_iter());
         let latency_max = float_max(latency.iter().map(|v| v.1));
 
-        TestResult {
+        let mut result = TestResult {
             result,
-            download,
-            upload,
-            both,
+            filter,
+            download: Vec::new(),
+            upload: Vec::new(),
+            both: Vec::new(),
             latency,
             up_latency,
             down_latency,
             loss,
-            bandwidth_max,
+            bandwidth_max: 0.0,
             latency_max,
-        }
+        };
+        result.set_filter(filter);
+        result
+    }
+
+    /// Recomputes the bandwidth series with `filter`.
+    fn set_filter(&mut self, filter: RateFilter) {
+        let result = &self.result;
+        let start = result.start.as_secs_f64();
+        self.download = handle_bytes(&result.combined_download_bytes, start, &filter);
+        self.upload = handle_bytes(&result.combined_upload_bytes, start, &filter);
+        self.both = handle_bytes(result.both_bytes.as_deref().unwrap_or(&[]), start, &filter);
+
+        let download_max = float_max(self.download.iter().map(|v| v.1));
+        let upload_max = float_max(self.upload.iter().map(|v| v.1));
+        let both_max = float_max(self.both.iter().map(|v| v.1));
+        self.bandwidth_max = float_max([download_max, upload_max, both_max].into_iter());
+        self.filter = filter;
     }
 }
 
-pub fn handle_bytes(data: &[(u64, f64)], start: f64) -> Vec<(f64, f64)> {
-    to_rates(data)
+pub fn handle_bytes(data: &[(u64, f64)], start: f64, filter: &RateFilter) -> Vec<(f64, f64)> {
+    filter
+        .rates(data)
         .into_iter()
         .map(|(time, speed)| (Duration::from_micros(time).as_secs_f64() - start, speed))
         .collect()
 }
 
+/// An empty key field means the server doesn't require one.
+fn psk(secret: &str) -> Option<PreSharedKey> {
+    (!secret.is_empty()).then(|| PreSharedKey::new(secret))
+}
+
 impl Drop for Tester {
     fn drop(&mut self) {
         self.save_settings();
@@ -329,6 +421,7 @@ impl Tester {
             client: None,
             result: None,
             result_saved: None,
+            result_load_error: None,
             result_plot_reset: false,
             raw_result: None,
             raw_result_saved: None,
@@ -349,16 +442,24 @@ impl Tester {
             latency_stop: Duration::from_secs(0),
             latency_error: None,
             latency_plot_reset: false,
+            latency_recording: None,
+            discovery: None,
+            discovered: None,
         }
     }
 
     pub fn load_file(&mut self, name: String, raw: RawResult) {
         let result = raw.to_test_result();
-        self.result = Some(TestResult::new(result));
+        self.result = Some(TestResult::new(result, self.settings.result.rate_filter()));
         self.result_saved = None;
         self.result_plot_reset = true;
         self.raw_result = Some(raw);
         self.raw_result_saved = Some(name);
+        self.result_load_error = None;
+    }
+
+    pub fn load_failed(&mut self, name: String, error: LoadError) {
+        self.result_load_error = Some(format!("Unable to load {name}: {error}"));
     }
 
     pub fn save_raw(&mut self, name: String) {
@@ -380,6 +481,58 @@ impl Tester {
         }
     }
 
+    fn discover(&mut self, ctx: &egui::Context) {
+        let (tx, rx) = oneshot::channel();
+        let ctx = ctx.clone();
+        thread::spawn(move || {
+            let servers =
+                discovery::discover(protocol::PORT, Duration::from_secs(1)).unwrap_or_default();
+            tx.send(servers).ok();
+            ctx.request_repaint();
+        });
+        self.discovery = Some(rx);
+    }
+
+    /// Shows servers found on the local network, returning the address of the one picked.
+    fn discovered_servers(&mut self, ctx: &egui::Context, ui: &mut Ui, id: &str) -> Option<String> {
+        if let Some(servers) = self.discovery.as_mut().and_then(|rx| rx.try_recv().ok()) {
+            self.discovered = Some(servers);
+            self.discovery = None;
+        }
+
+        if self.discovered.is_none() && self.discovery.is_none() {
+            self.discover(ctx);
+        }
+
+        let mut picked = None;
+        let mut refresh = false;
+
+        ComboBox::from_id_source(id)
+            .selected_text("Local servers")
+            .width(120.0)
+            .show_ui(ui, |ui| {
+                for server in self.discovered.iter().flatten() {
+                    if ui.selectable_label(false, server.to_string()).clicked() {
+                        picked = Some(server.host());
+                    }
+                }
+                if self.discovery.is_some() {
+                    ui.label("Searching..");
+                } else if self.discovered.iter().flatten().next().is_none() {
+                    ui.label("No servers found");
+                }
+                if ui.button("Search again").clicked() {
+                    refresh = true;
+                }
+            });
+
+        if refresh && self.discovery.is_none() {
+            self.discover(ctx);
+        }
+
+        picked
+    }
+
     fn config(&self) -> Config {
         Config {
             port: protocol::PORT,
@@ -392,6 +545,18 @@ impl Tester {
             both: self.settings.client.both,
             ping_interval: Duration::from_millis(self.settings.client.latency_sample_rate),
             bandwidth_interval: Duration::from_millis(self.settings.client.bandwidth_sample_rate),
+            psk: psk(&self.settings.client.psk),
+            tls: None,
+            incremental: false,
+        }
+    }
+
+    fn metadata(&self) -> RawMetadata {
+        let text = |text: &str| (!text.trim().is_empty()).then(|| text.trim().to_owned());
+        RawMetadata {
+            label: text(&self.settings.client.label),
+            notes: text(&self.settings.client.notes),
+            ..RawMetadata::client()
         }
     }
 
@@ -409,6 +574,7 @@ impl Tester {
         let abort = test::test_callback(
             self.config(),
             &self.settings.client.server,
+            self.metadata(),
             Arc::new(move |msg| {
                 tx.send(msg.to_string()).unwrap();
                 ctx.request_repaint();
@@ -440,16 +606,16 @@ impl Tester {
                 .add_filter("All files", &["*"])
                 .pick_file()
                 .map(|file| {
-                    RawResult::load(&file).map(|raw| {
-                        self.load_file(
-                            file.file_name()
-                                .unwrap_or_default()
-                                .to_str()
-                                .unwrap_or_default()
-                                .to_string(),
-                            raw,
-                        );
-                    })
+                    let name = file
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_str()
+                        .unwrap_or_default()
+                        .to_string();
+                    match RawResult::load(&file) {
+                        Ok(raw) => self.load_file(name, raw),
+                        Err(error) => self.load_failed(name, error),
+                    }
                 });
         }
         let file_loader = self.file_loader.take();
@@ -464,6 +630,15 @@ impl Tester {
             ui.add_enabled_ui(active, |ui| {
                 ui.label("Server address:");
                 ui.add(TextEdit::singleline(&mut self.settings.client.server));
+                if let Some(server) = self.discovered_servers(ctx, ui, "client_servers") {
+                    self.settings.client.server = server;
+                }
+                ui.label("Key:");
+                ui.add(
+                    TextEdit::singleline(&mut self.settings.client.psk)
+                        .password(true)
+                        .desired_width(100.0),
+                );
             });
 
             match self.client_state {
@@ -616,6 +791,18 @@ impl Tester {
                             ui.end_row();
                         });
                     }
+
+                    ui.separator();
+                    Grid::new("description").show(ui, |ui| {
+                        ui.label("Label:");
+                        ui.add(TextEdit::singleline(&mut self.settings.client.label));
+                        ui.end_row();
+                        ui.label("Notes:");
+                        ui.add(
+                            TextEdit::multiline(&mut self.settings.client.notes).desired_rows(2),
+                        );
+                        ui.end_row();
+                    });
                 });
 
                 if self.client_state == ClientState::Running
@@ -632,7 +819,10 @@ impl Tester {
                         match result {
                             Some(Ok(result)) => {
                                 self.msgs.push("Test complete.".to_owned());
-                                self.result = Some(TestResult::new(result.to_test_result()));
+                                self.result = Some(TestResult::new(
+                                    result.to_test_result(),
+                                    self.settings.result.rate_filter(),
+                                ));
                                 self.result_plot_reset = true;
                                 self.raw_result = Some(result);
                                 if self.tab == Tab::Client {
@@ -671,7 +861,10 @@ impl Tester {
                 self.load_result();
             }
             ui.separator();
-            ui.label("No result.");
+            match &self.result_load_error {
+                Some(error) => ui.label(error),
+                None => ui.label("No result."),
+            };
             return;
         }
 
@@ -683,8 +876,10 @@ impl Tester {
                             saver(&self.result.as_ref().unwrap().result);
                         }
                         None => {
+                            let mut config = PlotConfig::default();
+                            config.rates = self.result.as_ref().unwrap().filter;
                             self.result_saved = Some(plot::save_graph(
-                                &PlotConfig::default(),
+                                &config,
                                 &self.result.as_ref().unwrap().result,
                                 "plot",
                             ));
@@ -716,10 +911,52 @@ impl Tester {
             if ui.button("Load raw data").clicked() {
                 self.load_result();
             }
+            self.result_load_error.as_ref().map(|error| ui.label(error));
         });
         ui.separator();
 
-        let result = self.result.as_ref().unwrap();
+        ui.horizontal_wrapped(|ui| {
+            let settings = &mut self.settings.result;
+            ui.label("Bandwidth smoothing:");
+            ComboBox::from_id_source("smoothing")
+                .selected_text(settings.smoothing.name())
+                .show_ui(ui, |ui| {
+                    for method in [
+                        SmoothingMethod::None,
+                        SmoothingMethod::MovingAverage,
+                        SmoothingMethod::Ewma,
+                    ] {
+                        ui.selectable_value(&mut settings.smoothing, method, method.name());
+                    }
+                });
+            ui.add_enabled_ui(settings.smoothing != SmoothingMethod::None, |ui| {
+                ui.add(
+                    egui::DragValue::new(&mut settings.smoothing_window)
+                        .clamp_range(1.0..=10_000.0)
+                        .speed(1.0),
+                );
+                ui.label("milliseconds");
+            });
+            ui.add_space(10.0);
+            ui.checkbox(&mut settings.resample, "Resample to");
+            ui.add_enabled_ui(settings.resample, |ui| {
+                ui.add(
+                    egui::DragValue::new(&mut settings.resample_interval)
+                        .clamp_range(1.0..=10_000.0)
+                        .speed(1.0),
+                );
+                ui.label("milliseconds");
+            });
+        });
+        ui.separator();
+
+        let filter = self.settings.result.rate_filter();
+        let result = self.result.as_mut().unwrap();
+        if result.filter != filter {
+            result.set_filter(filter);
+            self.result_saved = None;
+        }
+        let result = &*result;
 
         if result.result.raw_result.server_overload {
             ui.label("Warning: Server overload detected during test. Result should be discarded.");
@@ -731,6 +968,13 @@ impl Tester {
             ui.separator();
         }
 
+        let metadata = &result.result.raw_result.metadata;
+        if metadata.label.is_some() || metadata.notes.is_some() {
+            metadata.label.as_ref().map(|label| ui.strong(label));
+            metadata.notes.as_ref().map(|notes| ui.label(notes));
+            ui.separator();
+        }
+
         ui.allocate_space(vec2(1.0, 15.0));
 
         ui.with_layout(Layout::bottom_up(Align::Min), |ui| {
@@ -812,7 +1056,7 @@ impl Tester {
             }
 
             plot.show(ui, |plot_ui| {
-                if result.result.raw_result.version >= 1 {
+                if !result.result.raw_result.inferred.up_latency {
                     let latency = result.up_latency.iter().map(|v| [v.0 as f64, v.1]);
                     let latency = Line::new(PlotPoints::from_iter(latency))
                         .color(Color32::from_rgb(37, 83, 169))
@@ -881,7 +1125,10 @@ impl Tester {
                         plot_ui.line(both);
                     }
                 });
-                ui.label("Bandwidth");
+                match result.filter.describe() {
+                    Some(description) => ui.label(format!("Bandwidth ({description})")),
+                    None => ui.label("Bandwidth"),
+                };
             }
         });
     }
@@ -909,7 +1156,11 @@ impl Tester {
                     let (signal_done, done) = oneshot::channel();
 
                     let stop = serve::serve_until(
-                        protocol::PORT,
+                        serve::Config {
+                            port: protocol::PORT,
+                            psk: None,
+                            tls: None,
+                        },
                         Box::new(move |msg| {
                             tx.send(msg.to_string()).unwrap();
                             ctx.request_repaint();
@@ -1022,6 +1273,12 @@ impl Tester {
             }),
         ));
 
+        let recording = self
+            .settings
+            .latency_monitor
+            .record
+            .then(latency::recording_name);
+
         let ctx_ = ctx.clone();
         let abort = latency::test_callback(
             latency::Config {
@@ -1029,9 +1286,13 @@ impl Tester {
                 ping_interval: Duration::from_millis(
                     self.settings.latency_monitor.latency_sample_rate,
                 ),
+                psk: psk(&self.settings.latency_monitor.psk),
+                tls: None,
             },
             &self.settings.latency_monitor.server,
             data.clone(),
+            RawMetadata::client(),
+            recording.as_ref().map(PathBuf::from),
             Box::new(move |result| {
                 signal_done.send(result).map_err(|_| ()).unwrap();
                 ctx_.request_repaint();
@@ -1046,6 +1307,16 @@ impl Tester {
         self.latency_data = data;
         self.latency_error = None;
         self.latency_plot_reset = true;
+        self.latency_recording = recording;
+    }
+
+    fn open_latency_recording(&mut self) {
+        let name = self.latency_recording.clone().unwrap();
+        match RawResult::load(Path::new(&name)) {
+            Ok(raw) => self.load_file(name, raw),
+            Err(error) => self.load_failed(name, error),
+        }
+        self.tab = Tab::Result;
     }
 
     fn latency(&mut self, ctx: &egui::Context, ui: &mut Ui) {
@@ -1057,6 +1328,15 @@ impl Tester {
                 ui.add(TextEdit::singleline(
                     &mut self.settings.latency_monitor.server,
                 ));
+                if let Some(server) = self.discovered_servers(ctx, ui, "latency_servers") {
+                    self.settings.latency_monitor.server = server;
+                }
+                ui.label("Key:");
+                ui.add(
+                    TextEdit::singleline(&mut self.settings.latency_monitor.psk)
+                        .password(true)
+                        .desired_width(100.0),
+                );
 
                 match self.latency_state {
                     ClientState::Running | ClientState::Stopping => {}
@@ -1086,7 +1366,24 @@ impl Tester {
                         .speed(0.05),
                 );
                 ui.label("milliseconds");
+                #[cfg(not(target_os = "android"))]
+                {
+                    ui.end_row();
+                    ui.label("Record session:");
+                    ui.checkbox(&mut self.settings.latency_monitor.record, "");
+                    ui.label("Save all pings to a file");
+                }
             });
+
+            if let Some(recording) = self.latency_recording.clone() {
+                ui.separator();
+                ui.horizontal_wrapped(|ui| {
+                    ui.label(format!("Recorded to: {recording}"));
+                    if ui.button("Open in Result tab").clicked() {
+                        self.open_latency_recording();
+                    }
+                });
+            }
         }
 
         if !active {
@@ -1116,6 +1413,10 @@ impl Tester {
                 };
                 ui.label(state);
 
+                if let Some(recording) = self.latency_recording.as_ref() {
+                    ui.label(format!("Recording to: {recording}"));
+                }
+
                 let latency = self.latency.as_mut().unwrap();
 
                 if let Ok(result) = latency.done.as_mut().unwrap().try_recv() {
@@ -1125,6 +1426,14 @@ impl Tester {
                         None => Some("Aborted...".to_owned()),
                     };
                     self.latency_stop = self.latency_data.start.elapsed();
+                    // Monitoring may have failed before the recording was created.
+                    if !self
+                        .latency_recording
+                        .as_ref()
+                        .is_some_and(|recording| Path::new(recording).exists())
+                    {
+                        self.latency_recording = None;
+                    }
                     self.latency = None;
                     self.latency_state = ClientState::Stopped;
                 }
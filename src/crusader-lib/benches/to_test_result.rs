@@ -0,0 +1,89 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use crusader_lib::file_format::{
+    RawConfig, RawInferred, RawMetadata, RawPing, RawPoint, RawResult, RawStream, RawStreamGroup,
+};
+use crusader_lib::timestamp::TimestampSource;
+use std::path::Path;
+use std::time::Duration;
+
+/// A result with `streams` streams in each of the download, upload and both loads,
+/// sampled every 20 ms for `load` seconds.
+fn synthetic(streams: u64, load: u64) -> RawResult {
+    let bandwidth_interval = Duration::from_millis(20);
+    let samples = load * 1000 / 20;
+
+    let group = |start: u64, download: bool, both: bool| RawStreamGroup {
+        download,
+        both,
+        streams: (0..streams)
+            .map(|i| RawStream {
+                data: (0..samples)
+                    .map(|sample| RawPoint {
+                        // Streams sample at slightly different times, as in real tests.
+                        time: Duration::from_micros(
+                            start * 1_000_000 + sample * 20_000 + i * 1_000 / streams,
+                        ),
+                        bytes: sample * (100_000 + i * 1_000),
+                    })
+                    .collect(),
+            })
+            .collect(),
+    };
+
+    RawResult {
+        version: 4,
+        generated_by: "benchmark".to_owned(),
+        config: RawConfig {
+            stagger: Duration::ZERO,
+            load_duration: Duration::from_secs(load),
+            grace_duration: Duration::from_secs(1),
+            ping_interval: Duration::from_millis(5),
+            bandwidth_interval,
+            tls: None,
+        },
+        ipv6: false,
+        load_termination_timeout: false,
+        server_overload: false,
+        server_latency: Duration::from_millis(1),
+        client_timestamps: TimestampSource::Software,
+        server_timestamps: TimestampSource::Software,
+        start: Duration::ZERO,
+        duration: Duration::from_secs(3 * (load + 2)),
+        stream_groups: vec![
+            group(1, true, false),
+            group(load + 2, false, false),
+            group(2 * load + 3, true, true),
+            group(2 * load + 3, false, true),
+        ],
+        pings: Vec::<RawPing>::new(),
+        metadata: RawMetadata::default(),
+        inferred: RawInferred::default(),
+    }
+}
+
+fn samples(c: &mut Criterion) {
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../data");
+    let mut group = c.benchmark_group("samples");
+    for name in ["v0", "v1", "v2"] {
+        let result = RawResult::load(&data.join(format!("{}.crr", name))).unwrap();
+        group.bench_function(name, |b| b.iter(|| result.to_test_result()));
+    }
+    group.finish();
+}
+
+fn large(c: &mut Criterion) {
+    let mut group = c.benchmark_group("synthetic");
+    group.sample_size(10);
+    for (streams, load) in [(16, 10), (64, 10), (64, 60)] {
+        let result = synthetic(streams, load);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{} streams, {} s", streams, load)),
+            &result,
+            |b, result| b.iter(|| result.to_test_result()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, samples, large);
+criterion_main!(benches);
@@ -0,0 +1,213 @@
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::join;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Instant};
+
+use crate::protocol::{self, Announcement, Discover, DISCOVER_MAGIC};
+
+/// A server which replied to a discovery request.
+#[derive(Debug, Clone)]
+pub struct Server {
+    /// The address the reply came from.
+    pub address: IpAddr,
+    pub hostname: String,
+    pub software_version: String,
+    pub protocol_version: u64,
+    /// Addresses of the server's network interfaces.
+    pub addresses: Vec<IpAddr>,
+    pub tls: bool,
+    pub psk: bool,
+    /// The interface the reply came in on, for IPv6 link-local addresses.
+    scope_id: u32,
+}
+
+impl Server {
+    /// The address to connect to the server with.
+    pub fn host(&self) -> String {
+        match self.address {
+            IpAddr::V6(address) if link_local(address) && self.scope_id != 0 => {
+                format!("{}%{}", address, self.scope_id)
+            }
+            address => address.to_string(),
+        }
+    }
+
+    /// Whether the server speaks the same protocol version as this client.
+    pub fn compatible(&self) -> bool {
+        self.protocol_version == protocol::VERSION
+    }
+
+    fn same_host(&self, other: &Server) -> bool {
+        self.hostname == other.hostname && self.addresses == other.addresses
+    }
+}
+
+impl fmt::Display for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}), Crusader {}",
+            self.hostname,
+            self.host(),
+            self.software_version
+        )?;
+        if self.tls {
+            write!(f, ", TLS")?;
+        }
+        if self.psk {
+            write!(f, ", key required")?;
+        }
+        if !self.compatible() {
+            write!(f, ", incompatible")?;
+        }
+        Ok(())
+    }
+}
+
+fn link_local(address: Ipv6Addr) -> bool {
+    address.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Whether `address` is on a local network, so discovery requests from it are answered.
+/// Replies aren't sent to other addresses to avoid revealing the server's details and
+/// being used to reflect traffic.
+pub(crate) fn local(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => {
+            address.is_private() || address.is_link_local() || address.is_loopback()
+        }
+        IpAddr::V6(address) => match address.to_ipv4_mapped() {
+            Some(address) => local(IpAddr::V4(address)),
+            None => {
+                address.is_loopback()
+                    || link_local(address)
+                    || address.segments()[0] & 0xfe00 == 0xfc00
+            }
+        },
+    }
+}
+
+pub(crate) fn announcement(tls: bool, psk: bool) -> Announcement {
+    Announcement {
+        magic: DISCOVER_MAGIC,
+        hostname: hostname::get()
+            .ok()
+            .and_then(|name| name.into_string().ok())
+            .unwrap_or_default(),
+        software_version: env!("CARGO_PKG_VERSION").to_owned(),
+        protocol_version: protocol::VERSION,
+        addresses: if_addrs::get_if_addrs()
+            .unwrap_or_default()
+            .iter()
+            .filter(|interface| !interface.is_loopback())
+            .map(|interface| interface.ip())
+            .collect(),
+        tls,
+        psk,
+    }
+}
+
+fn targets(port: u16) -> Vec<SocketAddr> {
+    let mut targets: Vec<SocketAddr> = vec![
+        (Ipv4Addr::BROADCAST, port).into(),
+        (Ipv4Addr::LOCALHOST, port).into(),
+        (Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1), port).into(),
+        (Ipv6Addr::LOCALHOST, port).into(),
+    ];
+
+    // The limited broadcast address may only go out of a single interface.
+    for interface in if_addrs::get_if_addrs().unwrap_or_default() {
+        if let if_addrs::IfAddr::V4(addr) = interface.addr {
+            if let Some(broadcast) = addr.broadcast {
+                targets.push((broadcast, port).into());
+            }
+        }
+    }
+
+    targets
+}
+
+async fn receive(socket: Option<UdpSocket>, deadline: Instant) -> Vec<Server> {
+    let mut servers = Vec::new();
+    let socket = match socket {
+        Some(socket) => socket,
+        None => return servers,
+    };
+    let mut buf = [0; 2048];
+
+    // Errors are skipped, as a probe to a port without a server can make the next receive
+    // fail on Windows.
+    while let Ok(received) = time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, src) = match received {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        match bincode::deserialize::<Announcement>(&buf[..len]) {
+            Ok(announcement) if announcement.magic == DISCOVER_MAGIC => servers.push(Server {
+                address: src.ip(),
+                hostname: announcement.hostname,
+                software_version: announcement.software_version,
+                protocol_version: announcement.protocol_version,
+                addresses: announcement.addresses,
+                tls: announcement.tls,
+                psk: announcement.psk,
+                scope_id: match src {
+                    SocketAddr::V6(src) => src.scope_id(),
+                    SocketAddr::V4(_) => 0,
+                },
+            }),
+            _ => (),
+        }
+    }
+
+    servers
+}
+
+async fn discover_async(port: u16, timeout: Duration) -> Result<Vec<Server>, Box<dyn Error>> {
+    let v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    v4.set_broadcast(true)?;
+
+    // IPv6 may not be available.
+    let v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok();
+
+    let request = bincode::serialize(&Discover::new())?;
+
+    for target in targets(port) {
+        let socket = if target.is_ipv4() {
+            Some(&v4)
+        } else {
+            v6.as_ref()
+        };
+        if let Some(socket) = socket {
+            socket.send_to(&request, target).await.ok();
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+    let (from_v4, from_v6) = join!(receive(Some(v4), deadline), receive(v6, deadline));
+
+    // Servers reply once for each way the request reached them, so keep one
+    // reply per server, preferring addresses usable from other hosts.
+    let mut servers: Vec<Server> = Vec::new();
+    for server in from_v4.into_iter().chain(from_v6) {
+        match servers.iter_mut().find(|known| known.same_host(&server)) {
+            Some(known) => {
+                if known.address.is_loopback() && !server.address.is_loopback() {
+                    *known = server;
+                }
+            }
+            None => servers.push(server),
+        }
+    }
+
+    Ok(servers)
+}
+
+/// Looks for servers on the local network, waiting `timeout` for replies.
+pub fn discover(port: u16, timeout: Duration) -> Result<Vec<Server>, Box<dyn Error>> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(discover_async(port, timeout))
+}
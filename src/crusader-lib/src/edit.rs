@@ -0,0 +1,360 @@
+//! Cropping, filtering, joining and anonymization of measured results.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::error::Error;
+use std::time::Duration;
+
+use crate::file_format::{RawPoint, RawResult, RawStreamGroup};
+use crate::plot::Phase;
+
+fn phase(group: &RawStreamGroup) -> Phase {
+    match (group.both, group.download) {
+        (true, _) => Phase::Both,
+        (false, true) => Phase::Download,
+        (false, false) => Phase::Upload,
+    }
+}
+
+/// Removes a both group whose counterpart in the other direction is gone, as the
+/// both phase is only plotted with both directions.
+pub(crate) fn drop_unpaired(groups: &mut Vec<RawStreamGroup>) {
+    if groups.iter().filter(|group| group.both).count() < 2 {
+        groups.retain(|group| !group.both);
+    }
+}
+
+/// How identifying details are treated by [`RawResult::anonymize`].
+#[derive(Clone, Default)]
+pub struct Anonymization {
+    /// Replace addresses, hostnames, labels and notes with hashes keyed by this secret
+    /// instead of removing them, so results sharing them can still be matched up.
+    pub hash_key: Option<Vec<u8>>,
+    /// Moves the start time, keeping the times of the measurements relative to it.
+    pub time_shift: Option<chrono::Duration>,
+}
+
+impl Anonymization {
+    /// A key for hashes which only match within the results anonymized with it.
+    pub fn random_key() -> Vec<u8> {
+        rand::random::<[u8; 32]>().to_vec()
+    }
+
+    fn hide(&self, value: &Option<String>) -> Option<String> {
+        let key = self.hash_key.as_ref()?;
+        value.as_ref().map(|value| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+            mac.update(value.as_bytes());
+            let hash = mac.finalize().into_bytes();
+            let hash: String = hash[0..6].iter().map(|b| format!("{:02x}", b)).collect();
+            format!("anon-{}", hash)
+        })
+    }
+}
+
+/// The first and last timestamps of a result, including pings sent outside of its duration.
+fn span(result: &RawResult) -> (Duration, Duration) {
+    let pings = result.pings.iter().map(|ping| ping.sent);
+    let samples = result
+        .stream_groups
+        .iter()
+        .flat_map(|group| &group.streams)
+        .flat_map(|stream| &stream.data)
+        .map(|point| point.time);
+    let times = pings.chain(samples);
+    let (first, last) = times.fold(
+        (result.start, result.start + result.duration),
+        |span, time| (span.0.min(time), span.1.max(time)),
+    );
+    (first, last)
+}
+
+impl RawResult {
+    /// Keeps the samples and pings between `from` and `to`, which are relative to `start`
+    /// like the times in plots. The window becomes the new timeline of the result.
+    pub fn crop(&self, from: Duration, to: Duration) -> Result<RawResult, Box<dyn Error>> {
+        let to = to.min(self.duration);
+        if from >= to {
+            return Err(format!(
+                "The window from {:.2} s to {:.2} s is empty",
+                from.as_secs_f64(),
+                to.as_secs_f64()
+            )
+            .into());
+        }
+        let (begin, end) = (self.start + from, self.start + to);
+
+        let mut result = self.clone();
+        for group in &mut result.stream_groups {
+            for stream in &mut group.streams {
+                stream
+                    .data
+                    .retain(|point| point.time >= begin && point.time <= end);
+            }
+        }
+        result
+            .stream_groups
+            .retain(|group| group.streams.iter().any(|stream| !stream.data.is_empty()));
+        drop_unpaired(&mut result.stream_groups);
+        result
+            .pings
+            .retain(|ping| ping.sent >= begin && ping.sent <= end);
+        if result.pings.is_empty() {
+            return Err("No pings are left in the window".into());
+        }
+
+        result.start = begin;
+        result.duration = to - from;
+        result.metadata.edits.push(format!(
+            "Cropped to {:.2} s - {:.2} s",
+            from.as_secs_f64(),
+            to.as_secs_f64()
+        ));
+        Ok(result)
+    }
+
+    /// Keeps the stream groups of the loads in `phases`. Pings are kept as they are.
+    pub fn keep_phases(&self, phases: &[Phase]) -> Result<RawResult, Box<dyn Error>> {
+        let mut result = self.clone();
+        result
+            .stream_groups
+            .retain(|group| phases.contains(&phase(group)));
+        if result.stream_groups.is_empty() {
+            return Err("No loads are left".into());
+        }
+
+        let kept: Vec<_> = Phase::ALL
+            .iter()
+            .filter(|phase| {
+                result
+                    .stream_groups
+                    .iter()
+                    .any(|group| self::phase(group) == **phase)
+            })
+            .map(|phase| phase.name().to_ascii_lowercase())
+            .collect();
+        result
+            .metadata
+            .edits
+            .push(format!("Kept the {} loads", kept.join(" and ")));
+        Ok(result)
+    }
+
+    /// Joins results from the same session into one timeline, in the order given.
+    ///
+    /// Results are placed by their start times if all are known, otherwise back to
+    /// back. Stream groups of the same load continue each other, so the streams of the
+    /// results must match. A load can only continue if no other load ran in between,
+    /// which allows joining a test split by cropping, but not repeated tests. The
+    /// configuration and metadata of the first result are kept.
+    pub fn concat(results: &[RawResult]) -> Result<RawResult, Box<dyn Error>> {
+        let (first, rest) = results.split_first().ok_or("No results to join")?;
+
+        let servers = |result: &RawResult| {
+            result
+                .metadata
+                .server_address
+                .clone()
+                .or_else(|| result.metadata.server.clone())
+        };
+        let timed = results
+            .iter()
+            .all(|result| result.metadata.start_time.is_some());
+
+        let mut joined = first.clone();
+        for (i, result) in rest.iter().enumerate() {
+            if servers(result).is_some()
+                && servers(first).is_some()
+                && servers(result) != servers(first)
+            {
+                return Err(format!("Result {} is from a different server", i + 2).into());
+            }
+            if result.streams() != first.streams() {
+                return Err(format!("Result {} has a different number of streams", i + 2).into());
+            }
+            let intervals = |result: &RawResult| {
+                (
+                    result.config.ping_interval,
+                    result.config.bandwidth_interval,
+                    result.config.stagger,
+                )
+            };
+            if intervals(result) != intervals(first) {
+                return Err(format!("Result {} has a different configuration", i + 2).into());
+            }
+
+            // The offset of the timestamps in `result` on the joined timeline.
+            let end = span(&joined).1;
+            let begin = span(result).0;
+            let offset = if timed {
+                let time = result.metadata.start_time.unwrap() - first.metadata.start_time.unwrap();
+                match time.to_std() {
+                    Ok(offset) if offset + begin >= end => offset,
+                    _ => {
+                        return Err(format!("Result {} overlaps the result before it", i + 2).into())
+                    }
+                }
+            } else {
+                end.saturating_sub(begin)
+            };
+
+            // Otherwise the span of a continued load would cover the loads in between.
+            let last_load = joined.to_test_result().phases().last().map(|load| load.0);
+            let first_load = result.to_test_result().phases().first().map(|load| load.0);
+
+            for group in &result.stream_groups {
+                let existing = joined.stream_groups.iter_mut().find(|existing| {
+                    existing.download == group.download && existing.both == group.both
+                });
+                match existing {
+                    Some(_)
+                        if last_load != Some(phase(group)) || first_load != Some(phase(group)) =>
+                    {
+                        return Err(format!(
+                            "Result {} repeats the {} load",
+                            i + 2,
+                            phase(group).name().to_lowercase()
+                        )
+                        .into());
+                    }
+                    Some(existing) => {
+                        let streams = group.streams.iter().zip(&mut existing.streams);
+                        for (stream, continued) in streams {
+                            // Byte counts continue from where the stream left off. Counts
+                            // of a cropped result already do.
+                            let last = continued.data.last().map_or(0, |point| point.bytes);
+                            let first = stream.data.first().map_or(0, |point| point.bytes);
+                            let bytes = last.saturating_sub(first);
+                            continued
+                                .data
+                                .extend(stream.data.iter().map(|point| RawPoint {
+                                    time: point.time + offset,
+                                    bytes: point.bytes + bytes,
+                                }));
+                        }
+                    }
+                    None => {
+                        let mut group = group.clone();
+                        for stream in &mut group.streams {
+                            for point in &mut stream.data {
+                                point.time += offset;
+                            }
+                        }
+                        joined.stream_groups.push(group);
+                    }
+                }
+            }
+
+            let index = joined.pings.last().map(|ping| ping.index + 1).unwrap_or(0);
+            let first_index = result.pings.first().map(|ping| ping.index).unwrap_or(0);
+            joined.pings.extend(result.pings.iter().map(|ping| {
+                let mut ping = ping.clone();
+                ping.index = ping.index - first_index + index;
+                ping.sent += offset;
+                ping
+            }));
+
+            joined.duration = offset + result.start + result.duration - joined.start;
+            joined.load_termination_timeout |= result.load_termination_timeout;
+            joined.server_overload |= result.server_overload;
+            let inferred = &mut joined.inferred;
+            inferred.connection |= result.inferred.connection;
+            inferred.up_latency |= result.inferred.up_latency;
+            inferred.loss_direction |= result.inferred.loss_direction;
+            inferred.warnings |= result.inferred.warnings;
+            inferred.timestamps |= result.inferred.timestamps;
+            inferred.metadata |= result.inferred.metadata;
+        }
+
+        joined.metadata.edits.push(format!(
+            "Joined {} results {}",
+            results.len(),
+            if timed {
+                "by their start times"
+            } else {
+                "back to back"
+            }
+        ));
+        Ok(joined)
+    }
+
+    /// Removes or hashes the server and client addresses and hostnames, the label and
+    /// the notes. The measurements are kept as they are.
+    pub fn anonymize(&self, anonymization: &Anonymization) -> Result<RawResult, Box<dyn Error>> {
+        let mut result = self.clone();
+        let metadata = &mut result.metadata;
+        metadata.server = anonymization.hide(&metadata.server);
+        metadata.server_address = anonymization.hide(&metadata.server_address);
+        metadata.client_hostname = anonymization.hide(&metadata.client_hostname);
+        metadata.label = anonymization.hide(&metadata.label);
+        metadata.notes = anonymization.hide(&metadata.notes);
+        if let (Some(shift), Some(time)) = (anonymization.time_shift, metadata.start_time) {
+            metadata.start_time = Some(
+                time.checked_add_signed(shift)
+                    .ok_or("The shifted start time is out of range")?,
+            );
+        }
+
+        metadata.edits.push(format!(
+            "Anonymized by {} identifying details{}",
+            if anonymization.hash_key.is_some() {
+                "hashing"
+            } else {
+                "removing"
+            },
+            if anonymization.time_shift.is_some() {
+                " and shifting the start time"
+            } else {
+                ""
+            }
+        ));
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn load() -> RawResult {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../data/v2.crr");
+        RawResult::load(Path::new(path)).unwrap()
+    }
+
+    #[test]
+    fn concat_rejects_repeated_loads() {
+        let result = load();
+        let error = RawResult::concat(&[result.clone(), result]).err().unwrap();
+        assert_eq!(error.to_string(), "Result 2 repeats the download load");
+    }
+
+    #[test]
+    fn concat_continues_split_load() {
+        let result = load();
+        let phases = result.to_test_result().phases();
+        let upload = phases
+            .iter()
+            .find(|phase| phase.0 == Phase::Upload)
+            .unwrap();
+        let cut = Duration::from_micros((upload.1 + upload.2) / 2) - result.start;
+        let first = result.crop(Duration::ZERO, cut).unwrap();
+        let second = result.crop(cut, result.duration).unwrap();
+
+        let joined = RawResult::concat(&[first, second]).unwrap();
+        let joined = joined.to_test_result().phases();
+        let loads: Vec<_> = joined.iter().map(|phase| phase.0).collect();
+        assert_eq!(loads, [Phase::Download, Phase::Upload, Phase::Both]);
+        assert!(joined.windows(2).all(|pair| pair[0].2 <= pair[1].1));
+        assert_eq!(joined, phases);
+    }
+
+    #[test]
+    fn concat_rejects_different_intervals() {
+        let result = load();
+        let mut other = result.clone();
+        other.config.ping_interval *= 2;
+        let error = RawResult::concat(&[result, other]).err().unwrap();
+        assert_eq!(error.to_string(), "Result 2 has a different configuration");
+    }
+}
@@ -1,5 +1,9 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::Read;
@@ -7,7 +11,9 @@ use std::io::Write;
 use std::path::Path;
 use std::time::Duration;
 
+use crate::journal;
 use crate::protocol;
+use crate::timestamp::TimestampSource;
 
 // Note that rmp_serde doesn't not use an enumerator when serializing Option.
 // Be careful about which types are inside Option.
@@ -59,6 +65,7 @@ impl RawConfigV0 {
             grace_duration: Duration::from_secs(self.grace_duration),
             ping_interval: Duration::from_millis(self.ping_interval),
             bandwidth_interval: Duration::from_millis(self.bandwidth_interval),
+            tls: None,
         }
     }
 }
@@ -80,12 +87,16 @@ impl RawResultV0 {
             config: self.config.to_v1(),
             start: self.start,
             server_latency: Duration::from_secs(0),
+            client_timestamps: TimestampSource::Software,
+            server_timestamps: TimestampSource::Software,
             ipv6: false,
             duration: self.duration,
             stream_groups: self.stream_groups.clone(),
             pings: self.pings.iter().map(|ping| ping.to_v1()).collect(),
             server_overload: false,
             load_termination_timeout: false,
+            metadata: RawMetadata::default(),
+            inferred: RawInferred::missing(0),
         }
     }
 }
@@ -117,6 +128,17 @@ pub struct RawStreamGroup {
     pub streams: Vec<RawStream>,
 }
 
+impl RawStreamGroup {
+    pub fn name(&self) -> &'static str {
+        match (self.both, self.download) {
+            (true, true) => "Both download",
+            (true, false) => "Both upload",
+            (false, true) => "Download",
+            (false, false) => "Upload",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Copy, Clone)]
 pub struct RawLatency {
     // Changed from Duration to Option<Duration> in v2.
@@ -145,6 +167,173 @@ pub struct RawConfig {
     pub grace_duration: Duration,
     pub ping_interval: Duration,
     pub bandwidth_interval: Duration,
+    #[serde(default)]
+    pub tls: Option<RawTls>, // Added in V4
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RawTls {
+    pub protocol: String,
+    pub cipher_suite: String,
+    // Bytes added to each TLS record, which carries up to 16 KiB of payload.
+    pub record_overhead: u64,
+}
+
+impl RawTls {
+    /// The fraction of extra bytes on the wire for bulk transfers, on top of the measured bytes.
+    pub fn overhead(&self) -> f64 {
+        self.record_overhead as f64 / (16 * 1024) as f64
+    }
+}
+
+/// Where and when a test ran, along with the user's description of it.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RawMetadata {
+    /// The wall-clock time timestamps in the result are relative to.
+    pub start_time: Option<DateTime<Utc>>,
+    /// The server as given by the user.
+    pub server: Option<String>,
+    /// The address the server resolved to.
+    pub server_address: Option<String>,
+    pub server_version: Option<String>,
+    pub client_hostname: Option<String>,
+    pub client_os: Option<String>,
+    pub client_version: Option<String>,
+    pub label: Option<String>,
+    pub notes: Option<String>,
+    /// Changes made to the result after it was measured, oldest first.
+    pub edits: Vec<String>,
+}
+
+impl RawMetadata {
+    /// The hostname, OS and version of this machine.
+    pub fn client() -> Self {
+        RawMetadata {
+            client_hostname: hostname::get()
+                .ok()
+                .and_then(|name| name.into_string().ok()),
+            client_os: Some(format!(
+                "{} {}",
+                std::env::consts::OS,
+                std::env::consts::ARCH
+            )),
+            client_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Fields which weren't recorded in the format version a result was measured with,
+/// so they were filled in with defaults when migrating it to a newer version.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RawInferred {
+    /// The format version the result was recorded in, if it was migrated.
+    pub original_version: Option<u64>,
+    /// `generated_by`, `ipv6`, `server_latency` and the stream stagger are unknown.
+    pub connection: bool,
+    /// Pings only have a round trip latency, with the up latency set to zero.
+    pub up_latency: bool,
+    /// Lost pings have no latency, so the direction they were lost in is unknown.
+    pub loss_direction: bool,
+    /// Server overload and load termination timeouts weren't detected.
+    pub warnings: bool,
+    /// Timestamp sources are unknown and assumed to be in software.
+    pub timestamps: bool,
+    /// The start time, hosts and versions are unknown.
+    pub metadata: bool,
+    /// The test was interrupted, so its end and warnings are missing.
+    pub interrupted: bool,
+}
+
+impl RawInferred {
+    /// The fields a result in format `version` lacks.
+    fn missing(version: u64) -> Self {
+        RawInferred {
+            original_version: Some(version),
+            connection: version < 1,
+            up_latency: version < 1,
+            loss_direction: version < 2,
+            warnings: version < 2,
+            timestamps: version < 3,
+            metadata: version < 5,
+            interrupted: false,
+        }
+    }
+
+    /// Names of the inferred fields.
+    pub fn fields(&self) -> Vec<&'static str> {
+        [
+            (self.connection, "connection details"),
+            (self.up_latency, "up and down latency"),
+            (self.loss_direction, "packet loss direction"),
+            (
+                self.warnings,
+                "server overload and load termination warnings",
+            ),
+            (self.timestamps, "timestamp sources"),
+            (self.metadata, "start time, hosts and versions"),
+            (self.interrupted, "end of the test, which was interrupted"),
+        ]
+        .into_iter()
+        .filter_map(|(inferred, name)| inferred.then_some(name))
+        .collect()
+    }
+}
+
+/// Why a result file couldn't be loaded, by the layer of the file that failed.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The file couldn't be opened or read.
+    Io(io::Error),
+    /// The header is missing or truncated.
+    Header(bincode::Error),
+    /// The header doesn't start with the magic number, so it's not a result file.
+    Magic(u64),
+    /// The file is from a newer version of Crusader.
+    UnsupportedVersion(u64),
+    /// The compressed data is corrupt or truncated.
+    Decompression(io::Error),
+    /// The data doesn't decode as a result of the version in the header.
+    Decode { version: u64, error: String },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(error) => write!(f, "Unable to read file: {}", error),
+            LoadError::Header(error) => write!(f, "Unable to read header: {}", error),
+            LoadError::Magic(magic) => write!(
+                f,
+                "Not a Crusader result file (magic number {:#x}, expected {:#x})",
+                magic,
+                RawHeader::default().magic
+            ),
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported file format version {}, the latest supported is {}",
+                version,
+                RawHeader::default().version
+            ),
+            LoadError::Decompression(error) => write!(f, "Unable to decompress data: {}", error),
+            LoadError::Decode { version, error } => write!(
+                f,
+                "Unable to decode data as format version {}: {}",
+                version, error
+            ),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Io(error) | LoadError::Decompression(error) => Some(error),
+            LoadError::Header(error) => Some(error),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq)]
@@ -157,7 +346,7 @@ impl Default for RawHeader {
     fn default() -> Self {
         Self {
             magic: protocol::MAGIC,
-            version: 2,
+            version: 5,
         }
     }
 }
@@ -173,10 +362,18 @@ pub struct RawResult {
     #[serde(default)]
     pub server_overload: bool, // Added in V2
     pub server_latency: Duration,
+    #[serde(default)]
+    pub client_timestamps: TimestampSource, // Added in V3
+    #[serde(default)]
+    pub server_timestamps: TimestampSource, // Added in V3
     pub start: Duration,
     pub duration: Duration,
     pub stream_groups: Vec<RawStreamGroup>,
     pub pings: Vec<RawPing>,
+    #[serde(default)]
+    pub metadata: RawMetadata, // Added in V5
+    #[serde(default)]
+    pub inferred: RawInferred, // Added in V5
 }
 
 impl RawResult {
@@ -205,43 +402,154 @@ impl RawResult {
         self.stream_groups.iter().any(|group| group.both)
     }
 
-    pub fn load_from_reader(reader: impl Read) -> Option<Self> {
+    pub fn load_from_reader(reader: impl Read) -> Result<Self, LoadError> {
         let mut file = BufReader::new(reader);
-        let header: RawHeader = bincode::deserialize_from(&mut file).ok()?;
+        let header: RawHeader = bincode::deserialize_from(&mut file).map_err(LoadError::Header)?;
+        if header.magic == journal::MAGIC {
+            if header.version > RawHeader::default().version {
+                return Err(LoadError::UnsupportedVersion(header.version));
+            }
+            return journal::load(file, header.version);
+        }
         if header.magic != RawHeader::default().magic {
-            return None;
+            return Err(LoadError::Magic(header.magic));
         }
+        let decode_error = |error: &dyn Error| LoadError::Decode {
+            version: header.version,
+            error: error.to_string(),
+        };
         match header.version {
             0 => {
-                let result: RawResultV0 = bincode::deserialize_from(file).ok()?;
-                Some(result.to_v1())
+                let result: RawResultV0 =
+                    bincode::deserialize_from(file).map_err(|error| decode_error(&error))?;
+                Ok(result.to_v1())
             }
-            1 | 2 => {
-                let data = snap::read::FrameDecoder::new(file);
-                Some(rmp_serde::decode::from_read(data).ok()?)
+            1..=5 => {
+                let mut data = Vec::new();
+                snap::read::FrameDecoder::new(file)
+                    .read_to_end(&mut data)
+                    .map_err(LoadError::Decompression)?;
+                let mut result: RawResult =
+                    rmp_serde::from_slice(&data).map_err(|error| decode_error(&error))?;
+                if result.version < 5 {
+                    result.inferred = RawInferred::missing(result.version);
+                }
+                Ok(result)
             }
-            _ => None,
+            version => Err(LoadError::UnsupportedVersion(version)),
         }
     }
 
-    pub fn load(path: &Path) -> Option<Self> {
-        Self::load_from_reader(File::open(path).ok()?)
+    pub fn load(path: &Path) -> Result<Self, LoadError> {
+        Self::load_from_reader(File::open(path).map_err(LoadError::Io)?)
     }
 
-    pub fn save_to_writer(&self, writer: impl Write) {
+    /// Describes inconsistencies in the result which suggest it's damaged.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self
+            .stream_groups
+            .iter()
+            .any(|group| group.streams.len() as u64 != self.streams())
+        {
+            problems.push("Stream groups have different numbers of streams".to_owned());
+        }
+
+        for group in &self.stream_groups {
+            let name = group.name();
+            if group.streams.is_empty() {
+                problems.push(format!("{} group has no streams", name));
+            }
+            for (i, stream) in group.streams.iter().enumerate() {
+                if stream.data.is_empty() {
+                    problems.push(format!("{} stream {} is empty", name, i));
+                }
+                if stream.data.windows(2).any(|w| w[1].time < w[0].time) {
+                    problems.push(format!(
+                        "{} stream {} has non-monotonic timestamps",
+                        name, i
+                    ));
+                }
+                if stream.data.windows(2).any(|w| w[1].bytes < w[0].bytes) {
+                    problems.push(format!("{} stream {} has decreasing byte counts", name, i));
+                }
+                if stream
+                    .data
+                    .iter()
+                    .any(|point| point.time > self.start + self.duration)
+                {
+                    problems.push(format!(
+                        "{} stream {} has samples after the end of the test",
+                        name, i
+                    ));
+                }
+            }
+        }
+
+        if self.pings.is_empty() {
+            problems.push("There are no pings".to_owned());
+        }
+        if self.pings.windows(2).any(|w| w[1].index <= w[0].index) {
+            problems.push("Ping indices are not increasing".to_owned());
+        }
+        if self.pings.windows(2).any(|w| w[1].sent < w[0].sent) {
+            problems.push("Pings have non-monotonic send times".to_owned());
+        }
+
+        problems
+    }
+
+    pub fn save_to_writer(&self, writer: impl Write) -> Result<(), Box<dyn Error>> {
         let mut file = BufWriter::new(writer);
 
-        bincode::serialize_into(&mut file, &RawHeader::default()).unwrap();
+        bincode::serialize_into(&mut file, &RawHeader::default())?;
 
         let mut compressor = snap::write::FrameEncoder::new(file);
 
-        self.serialize(&mut rmp_serde::Serializer::new(&mut compressor).with_struct_map())
-            .unwrap();
+        self.serialize(&mut rmp_serde::Serializer::new(&mut compressor).with_struct_map())?;
 
-        compressor.flush().unwrap();
+        compressor.flush()?;
+        Ok(())
     }
 
     pub fn save(&self, name: &Path) {
-        self.save_to_writer(File::create(name).unwrap())
+        self.save_to_writer(File::create(name).unwrap()).unwrap()
+    }
+
+    /// Rewrites the result file at `path` in the latest format, keeping the original
+    /// with `.bak` appended to its name. Results written incrementally are rewritten as
+    /// regular result files. Returns the version it was upgraded from, or `None` if it
+    /// already was a regular result file in the latest format.
+    pub fn upgrade_file(path: &Path) -> Result<Option<u64>, Box<dyn Error>> {
+        let mut result = Self::load(path)?;
+        let version = result.version;
+        let header: RawHeader = bincode::deserialize_from(BufReader::new(File::open(path)?))?;
+        if version >= RawHeader::default().version && header.magic != journal::MAGIC {
+            return Ok(None);
+        }
+
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(".bak");
+        let backup = Path::new(&backup);
+        if backup.exists() {
+            return Err(format!("Backup {} already exists", backup.display()).into());
+        }
+        fs::copy(path, backup)?;
+
+        // Write to a temporary file first so `path` is never left partially written.
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = Path::new(&temp);
+        result.version = RawHeader::default().version;
+        let saved = File::create(temp)
+            .map_err(|error| error.into())
+            .and_then(|file| result.save_to_writer(file))
+            .and_then(|()| Ok(fs::rename(temp, path)?));
+        if saved.is_err() {
+            fs::remove_file(temp).ok();
+        }
+        saved?;
+        Ok(Some(version))
     }
 }
@@ -0,0 +1,547 @@
+//! Conversion of flent data files.
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::file_format::{
+    RawConfig, RawHeader, RawInferred, RawLatency, RawMetadata, RawPing, RawPoint, RawResult,
+    RawStream, RawStreamGroup,
+};
+use crate::test::unique;
+use crate::timestamp::TimestampSource;
+
+/// The version of flent's data format which is written.
+const FORMAT_VERSION: u64 = 4;
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "SCREAMING_SNAKE_CASE")]
+struct FlentMetadata {
+    name: Option<String>,
+    title: Option<String>,
+    note: Option<String>,
+    host: Option<String>,
+    local_host: Option<String>,
+    time: Option<String>,
+    length: Option<f64>,
+    total_length: Option<f64>,
+    step_size: Option<f64>,
+    flent_version: Option<String>,
+    ip_version: Option<u64>,
+    kernel_name: Option<String>,
+    kernel_release: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FlentData {
+    #[serde(default)]
+    metadata: FlentMetadata,
+    x_values: Vec<f64>,
+    /// Values of each series at `x_values`, with `None` where there's no data.
+    results: BTreeMap<String, Vec<Option<f64>>>,
+}
+
+/// Aggregates of the other series, which aren't streams of their own.
+fn aggregate(name: &str) -> bool {
+    name.ends_with(" sum") || name.ends_with(" avg") || name.contains("totals")
+}
+
+fn duration(seconds: f64) -> Duration {
+    Duration::from_secs_f64(seconds.max(0.0))
+}
+
+/// Parses flent's ISO 8601 time, which is in UTC when it has no offset.
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|time| time.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%.f")
+                .ok()
+                .map(|time| Utc.from_utc_datetime(&time))
+        })
+}
+
+/// Cumulative bytes of a throughput series in Mbps, taking each rate as the
+/// average since the previous sample time.
+fn stream(x_values: &[f64], rates: &[Option<f64>]) -> RawStream {
+    let mut bytes = 0.0;
+    let data = x_values
+        .iter()
+        .zip(rates)
+        .enumerate()
+        .filter_map(|(i, (&time, &rate))| {
+            let rate = rate?;
+            if let Some(last) = i.checked_sub(1).map(|i| x_values[i]) {
+                bytes += rate * 1000.0 * 1000.0 / 8.0 * (time - last);
+            }
+            Some(RawPoint {
+                time: duration(time),
+                bytes: bytes as u64,
+            })
+        })
+        .collect();
+    RawStream { data }
+}
+
+/// Pings from a latency series in milliseconds. Missing values between the first and
+/// last value are lost pings.
+fn pings(x_values: &[f64], latencies: &[Option<f64>]) -> Vec<RawPing> {
+    let first = latencies.iter().position(|latency| latency.is_some());
+    let last = latencies.iter().rposition(|latency| latency.is_some());
+    let (first, last) = match (first, last) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Vec::new(),
+    };
+    (first..=last)
+        .map(|i| RawPing {
+            index: (i - first) as u64,
+            sent: duration(x_values[i]),
+            latency: latencies[i].map(|latency| RawLatency {
+                total: Some(duration(latency / 1000.0)),
+                up: Duration::ZERO,
+            }),
+        })
+        .collect()
+}
+
+/// Converts flent data in JSON, like the uncompressed contents of a `.flent.gz` file.
+///
+/// TCP throughput series become streams, in the both phase if there are series in
+/// both directions. Pings come from the ICMP latency series if there is one, otherwise
+/// from the first latency series. Flent only measures round trips, so up and down
+/// latency and the direction of packet loss are recorded as inferred.
+pub fn import_from_reader(reader: impl Read) -> Result<RawResult, Box<dyn Error>> {
+    let data: FlentData = serde_json::from_reader(reader)?;
+    let metadata = &data.metadata;
+
+    let len = data.x_values.len();
+    if data.results.values().any(|series| series.len() != len) {
+        return Err("Series don't have a value for each sample time".into());
+    }
+
+    // The step becomes the ping and bandwidth intervals, which plotting steps by.
+    let step_size = metadata.step_size.unwrap_or(0.2);
+    let step = match Duration::try_from_secs_f64(step_size) {
+        Ok(step) if step >= Duration::from_millis(1) => step,
+        _ => return Err(format!("Invalid step size {}", step_size).into()),
+    };
+
+    let series = |direction: &str| -> Vec<RawStream> {
+        data.results
+            .iter()
+            .filter(|(name, _)| name.starts_with("TCP ") && name.contains(direction))
+            .filter(|(name, _)| !aggregate(name))
+            .map(|(_, rates)| stream(&data.x_values, rates))
+            .collect()
+    };
+    let (download, upload) = (series("download"), series("upload"));
+    let both = !download.is_empty() && !upload.is_empty();
+    let stream_groups: Vec<_> = [(true, download), (false, upload)]
+        .into_iter()
+        .filter(|(_, streams)| !streams.is_empty())
+        .map(|(download, streams)| RawStreamGroup {
+            download,
+            both,
+            streams,
+        })
+        .collect();
+
+    let latencies = data
+        .results
+        .iter()
+        .filter(|(name, _)| name.starts_with("Ping") && !aggregate(name))
+        .max_by_key(|(name, _)| name.contains("ICMP"))
+        .map(|(_, latencies)| latencies)
+        .ok_or("No latency series found")?;
+    let pings = pings(&data.x_values, latencies);
+
+    // Use the median latency before any load as the latency to the server.
+    let load_start = stream_groups
+        .iter()
+        .flat_map(|group| &group.streams)
+        .filter_map(|stream| stream.data.first())
+        .map(|point| point.time)
+        .min()
+        .unwrap_or_default();
+    let mut idle: Vec<_> = pings
+        .iter()
+        .filter(|ping| ping.sent < load_start)
+        .filter_map(|ping| ping.latency.and_then(|latency| latency.total))
+        .collect();
+    idle.sort();
+
+    let length = metadata.length.unwrap_or(0.0);
+
+    Ok(RawResult {
+        version: RawHeader::default().version,
+        generated_by: format!(
+            "Crusader {} flent importer, from flent {}",
+            env!("CARGO_PKG_VERSION"),
+            metadata.flent_version.as_deref().unwrap_or("unknown")
+        ),
+        config: RawConfig {
+            stagger: Duration::ZERO,
+            load_duration: duration(length),
+            grace_duration: duration((metadata.total_length.unwrap_or(length) - length) / 2.0),
+            ping_interval: step,
+            bandwidth_interval: step,
+            tls: None,
+        },
+        ipv6: metadata.ip_version == Some(6),
+        load_termination_timeout: false,
+        server_overload: false,
+        server_latency: idle.get(idle.len() / 2).copied().unwrap_or_default(),
+        client_timestamps: TimestampSource::Software,
+        server_timestamps: TimestampSource::Software,
+        start: Duration::ZERO,
+        duration: duration(data.x_values.last().copied().unwrap_or_default()),
+        stream_groups,
+        pings,
+        metadata: RawMetadata {
+            start_time: metadata.time.as_deref().and_then(parse_time),
+            server: metadata.host.clone(),
+            client_hostname: metadata.local_host.clone(),
+            client_os: metadata
+                .kernel_name
+                .as_ref()
+                .map(|name| match &metadata.kernel_release {
+                    Some(release) => format!("{} {}", name, release),
+                    None => name.clone(),
+                }),
+            label: metadata
+                .title
+                .clone()
+                .filter(|title| !title.is_empty())
+                .or_else(|| metadata.name.clone()),
+            notes: metadata.note.clone().filter(|note| !note.is_empty()),
+            ..Default::default()
+        },
+        inferred: RawInferred {
+            connection: true,
+            up_latency: true,
+            loss_direction: true,
+            warnings: true,
+            timestamps: true,
+            ..Default::default()
+        },
+    })
+}
+
+/// Imports a flent data file, which may be gzip compressed.
+pub fn import(path: &Path) -> Result<RawResult, Box<dyn Error>> {
+    let file = BufReader::new(File::open(path)?);
+    if path.extension().is_some_and(|extension| extension == "gz") {
+        import_from_reader(GzDecoder::new(file))
+    } else {
+        import_from_reader(file)
+    }
+}
+
+#[derive(Serialize)]
+struct RawValue {
+    t: f64,
+    val: f64,
+    seq: u64,
+}
+
+#[derive(Serialize)]
+struct FlentExport {
+    metadata: serde_json::Value,
+    version: u64,
+    x_values: Vec<f64>,
+    results: BTreeMap<String, Vec<Option<f64>>>,
+    raw_values: BTreeMap<String, Vec<RawValue>>,
+}
+
+fn add(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Interpolates the bytes transferred by `stream` at `time`, if it has samples around it.
+fn bytes_at(stream: &RawStream, time: Duration) -> Option<f64> {
+    let (first, last) = (stream.data.first()?, stream.data.last()?);
+    if time < first.time || time > last.time {
+        return None;
+    }
+    let i = stream.data.partition_point(|point| point.time < time);
+    let after = &stream.data[i];
+    let before = &stream.data[i.saturating_sub(1)];
+    if after.time == before.time {
+        return Some(after.bytes as f64);
+    }
+    let fraction = (time - before.time).as_secs_f64() / (after.time - before.time).as_secs_f64();
+    Some(before.bytes as f64 + (after.bytes as f64 - before.bytes as f64) * fraction)
+}
+
+/// Converts a result to flent data in JSON, like the uncompressed contents of a
+/// `.flent.gz` file.
+///
+/// Series are sampled at the bandwidth interval. Each stream becomes a
+/// `TCP download::N` or `TCP upload::N` series, with the stream of the same index
+/// in the both phase continuing it, and pings are averaged into `Ping (ms) ICMP`
+/// with the individual pings kept as its raw values. The test is named `rrul_var`,
+/// `tcp_ndown` or `tcp_nup` depending on the directions loaded, and the phases
+/// are described by `CRUSADER_PHASES` in the metadata.
+pub fn export_to_writer(result: &RawResult, writer: impl Write) -> Result<(), Box<dyn Error>> {
+    let step = result.config.bandwidth_interval;
+    if step.is_zero() {
+        return Err("Result has no bandwidth interval".into());
+    }
+    let steps = (result.duration.as_secs_f64() / step.as_secs_f64()) as u32;
+    let x_values: Vec<_> = (0..=steps).map(|i| (step * i).as_secs_f64()).collect();
+    // The window before each sample time, relative to the timestamps of the result.
+    let windows: Vec<_> = (0..=steps)
+        .map(|i| {
+            let end = result.start + step * i;
+            (end.saturating_sub(step), end)
+        })
+        .collect();
+
+    let mut results = BTreeMap::new();
+    let mut totals: Option<Vec<Option<f64>>> = None;
+    let mut streams = BTreeMap::new();
+    for (download, direction) in [(true, "download"), (false, "upload")] {
+        let groups: Vec<_> = result
+            .stream_groups
+            .iter()
+            .filter(|group| group.download == download)
+            .collect();
+        let count = groups
+            .iter()
+            .map(|group| group.streams.len())
+            .max()
+            .unwrap_or(0);
+        if count == 0 {
+            continue;
+        }
+        streams.insert(direction, count);
+
+        let rates: Vec<Vec<_>> = (0..count)
+            .map(|i| {
+                windows
+                    .iter()
+                    .enumerate()
+                    .map(|(k, &(from, to))| {
+                        groups
+                            .iter()
+                            .filter_map(|group| group.streams.get(i))
+                            .map(|stream| {
+                                if k == 0 {
+                                    return None;
+                                }
+                                let bytes = bytes_at(stream, to)? - bytes_at(stream, from)?;
+                                Some(bytes * 8.0 / (1000.0 * 1000.0) / step.as_secs_f64())
+                            })
+                            .fold(None, add)
+                    })
+                    .collect()
+            })
+            .collect();
+        let sum: Vec<_> = (0..windows.len())
+            .map(|k| rates.iter().map(|rates| rates[k]).fold(None, add))
+            .collect();
+        let avg = (0..windows.len())
+            .map(|k| {
+                let values = rates.iter().filter(|rates| rates[k].is_some()).count();
+                sum[k].map(|sum| sum / values as f64)
+            })
+            .collect();
+
+        totals = Some(match totals {
+            Some(totals) => totals.iter().zip(&sum).map(|(&a, &b)| add(a, b)).collect(),
+            None => sum.clone(),
+        });
+        for (i, rates) in rates.into_iter().enumerate() {
+            results.insert(format!("TCP {}::{}", direction, i + 1), rates);
+        }
+        results.insert(format!("TCP {} sum", direction), sum);
+        results.insert(format!("TCP {} avg", direction), avg);
+    }
+    if streams.len() == 2 {
+        results.insert("TCP totals".to_owned(), totals.unwrap_or_default());
+    }
+
+    let latency = |ping: &RawPing| {
+        ping.latency
+            .and_then(|latency| latency.total)
+            .map(|latency| latency.as_secs_f64() * 1000.0)
+    };
+    let pings: Vec<_> = windows
+        .iter()
+        .map(|&(from, to)| {
+            let latencies: Vec<_> = result
+                .pings
+                .iter()
+                .filter(|ping| ping.sent > from && ping.sent <= to)
+                .filter_map(latency)
+                .collect();
+            (!latencies.is_empty()).then(|| latencies.iter().sum::<f64>() / latencies.len() as f64)
+        })
+        .collect();
+    results.insert("Ping (ms) avg".to_owned(), pings.clone());
+    results.insert("Ping (ms) ICMP".to_owned(), pings);
+
+    let metadata = &result.metadata;
+    // Flent uses Unix timestamps for raw values.
+    let epoch = metadata
+        .start_time
+        .map(|time| time.timestamp_micros() as f64 / 1000.0 / 1000.0)
+        .unwrap_or(0.0);
+    let raw_pings = result
+        .pings
+        .iter()
+        .filter(|ping| ping.sent >= result.start)
+        .filter_map(|ping| {
+            Some(RawValue {
+                t: epoch + ping.sent.as_secs_f64() - result.start.as_secs_f64(),
+                val: latency(ping)?,
+                seq: ping.index,
+            })
+        })
+        .collect();
+    let raw_values = BTreeMap::from([("Ping (ms) ICMP".to_owned(), raw_pings)]);
+
+    let phases: Vec<_> = result
+        .to_test_result()
+        .phases()
+        .into_iter()
+        .map(|(phase, start, end)| {
+            json!({
+                "NAME": phase.name(),
+                "START": Duration::from_micros(start).saturating_sub(result.start).as_secs_f64(),
+                "END": Duration::from_micros(end).saturating_sub(result.start).as_secs_f64(),
+            })
+        })
+        .collect();
+
+    let name = match (streams.get("download"), streams.get("upload")) {
+        (Some(_), None) => "tcp_ndown",
+        (None, Some(_)) => "tcp_nup",
+        _ => "rrul_var",
+    };
+    let server = metadata
+        .server
+        .clone()
+        .or_else(|| metadata.server_address.clone());
+
+    let data = FlentExport {
+        metadata: json!({
+            "NAME": name,
+            "TITLE": metadata.label.clone().unwrap_or_default(),
+            "NOTE": metadata.notes.clone().unwrap_or_default(),
+            // Flent requires a time, so results without one start at the Unix epoch.
+            "TIME": metadata
+                .start_time
+                .unwrap_or_else(|| Utc.timestamp_opt(0, 0).unwrap())
+                .format("%Y-%m-%dT%H:%M:%S%.6f")
+                .to_string(),
+            "LENGTH": result.config.load_duration.as_secs_f64(),
+            "TOTAL_LENGTH": result.duration.as_secs_f64(),
+            "STEP_SIZE": step.as_secs_f64(),
+            "HOST": server,
+            "HOSTS": server.iter().collect::<Vec<_>>(),
+            "LOCAL_HOST": metadata.client_hostname,
+            "KERNEL_NAME": metadata.client_os,
+            "IP_VERSION": if result.ipv6 { 6 } else { 4 },
+            "TEST_PARAMETERS": {
+                "download_streams": streams.get("download").unwrap_or(&0).to_string(),
+                "upload_streams": streams.get("upload").unwrap_or(&0).to_string(),
+            },
+            "CRUSADER_GENERATED_BY": result.generated_by,
+            "CRUSADER_EXPORTED_BY": format!("Crusader {}", env!("CARGO_PKG_VERSION")),
+            "CRUSADER_PHASES": phases,
+            "CRUSADER_GRACE_DURATION": result.config.grace_duration.as_secs_f64(),
+            "CRUSADER_SERVER_LATENCY": result.server_latency.as_secs_f64() * 1000.0,
+        }),
+        version: FORMAT_VERSION,
+        x_values,
+        results,
+        raw_values,
+    };
+    serde_json::to_writer(writer, &data)?;
+    Ok(())
+}
+
+/// Exports a result as a flent data file, which is gzip compressed if the name ends in `.gz`.
+pub fn export(result: &RawResult, path: &Path) -> Result<(), Box<dyn Error>> {
+    let file = BufWriter::new(File::create(path)?);
+    if path.extension().is_some_and(|extension| extension == "gz") {
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        export_to_writer(result, &mut encoder)?;
+        encoder.finish()?.flush()?;
+    } else {
+        let mut file = file;
+        export_to_writer(result, &mut file)?;
+        file.flush()?;
+    }
+    Ok(())
+}
+
+/// Exports a result as a new `.flent.gz` file.
+pub fn save(result: &RawResult, name: &str) -> Result<String, Box<dyn Error>> {
+    let file = unique(name, "flent.gz");
+    export(result, Path::new(&file))?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(step_size: &str) -> String {
+        format!(
+            r#"{{
+                "metadata": {{ "STEP_SIZE": {}, "LENGTH": 0.4, "TOTAL_LENGTH": 0.6 }},
+                "x_values": [0.0, 0.2, 0.4, 0.6],
+                "results": {{
+                    "TCP download::1": [null, 10.0, 20.0, null],
+                    "TCP download sum": [null, 10.0, 20.0, null],
+                    "Ping (ms) ICMP": [1.0, 2.0, null, 1.5]
+                }}
+            }}"#,
+            step_size
+        )
+    }
+
+    #[test]
+    fn import_reads_streams_and_pings() {
+        let result = import_from_reader(fixture("0.2").as_bytes()).unwrap();
+        assert_eq!(result.config.bandwidth_interval, Duration::from_millis(200));
+        assert_eq!(result.stream_groups.len(), 1);
+        assert!(result.stream_groups[0].download);
+        assert_eq!(result.stream_groups[0].streams.len(), 1);
+        assert_eq!(result.pings.len(), 4);
+        assert_eq!(
+            result
+                .pings
+                .iter()
+                .filter(|ping| ping.latency.is_none())
+                .count(),
+            1
+        );
+        result.to_test_result();
+    }
+
+    #[test]
+    fn import_rejects_invalid_step_sizes() {
+        for step_size in ["0", "-0.2", "1e300"] {
+            let error = import_from_reader(fixture(step_size).as_bytes())
+                .err()
+                .unwrap();
+            assert!(error.to_string().starts_with("Invalid step size"));
+        }
+    }
+}
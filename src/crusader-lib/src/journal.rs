@@ -0,0 +1,401 @@
+//! Result files written during a test, so they survive it being interrupted.
+//!
+//! After a header like the one of regular result files, but with its own magic number,
+//! a journal is a sequence of chunks. Each is the length of its data as a little endian
+//! `u32` followed by a Snappy compressed MessagePack list of records. A truncated
+//! journal loads up to its last complete chunk.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time;
+
+use crate::edit::drop_unpaired;
+use crate::file_format::{LoadError, RawHeader, RawLatency, RawPing, RawPoint, RawResult};
+
+pub(crate) const MAGIC: u64 = 0x5372ab82ae7c59cc;
+
+/// How often records are written to the file.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The largest chunk to read, compressed or not, so a corrupt length can't exhaust memory.
+/// Chunks hold the records of a flush interval, which are far smaller.
+const MAX_CHUNK: usize = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) enum RawRecord {
+    /// Everything known before the test starts, with empty streams and no pings.
+    Begin(Box<RawResult>),
+    /// The start of the test, which times in the result are relative to.
+    Start(Duration),
+    PingSent {
+        index: u64,
+        sent: Duration,
+    },
+    /// The server received a ping.
+    PingReceived {
+        index: u64,
+        time: Duration,
+    },
+    /// The reply to a ping was received.
+    PingReturned {
+        index: u64,
+        time: Duration,
+    },
+    Sample {
+        download: bool,
+        both: bool,
+        stream: u32,
+        point: RawPoint,
+    },
+    End {
+        duration: Duration,
+        load_termination_timeout: bool,
+        server_overload: bool,
+    },
+}
+
+/// A handle to record measurements to a journal, which does nothing without one.
+#[derive(Clone, Default)]
+pub(crate) struct Recorder(Option<UnboundedSender<RawRecord>>);
+
+impl Recorder {
+    /// Whether records go to a journal, so measurements don't have to be kept in memory.
+    pub(crate) fn journaling(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub(crate) fn record(&self, record: RawRecord) {
+        if let Some(records) = &self.0 {
+            records.send(record).ok();
+        }
+    }
+}
+
+pub(crate) struct Journal {
+    path: PathBuf,
+    recorder: Recorder,
+    /// Returns the records which couldn't be written and the error which stopped writing.
+    writer: JoinHandle<(Vec<RawRecord>, Option<io::Error>)>,
+}
+
+impl Journal {
+    /// Creates a journal at `path` starting with `begin` and writes what's recorded to it
+    /// periodically until the end of the test is recorded. If writing fails, the rest of
+    /// the records are kept in memory instead.
+    pub(crate) fn create(path: &Path, begin: RawResult) -> Result<Self, Box<dyn Error>> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let header = RawHeader {
+            magic: MAGIC,
+            ..RawHeader::default()
+        };
+        bincode::serialize_into(&mut file, &header)?;
+        write_chunk(&mut file, &[RawRecord::Begin(Box::new(begin))])?;
+
+        let (tx, mut rx) = unbounded_channel();
+        let writer = tokio::spawn(async move {
+            let mut interval = time::interval(FLUSH_INTERVAL);
+            let mut file = Some(file);
+            let mut records = Vec::new();
+            let mut error = None;
+            loop {
+                interval.tick().await;
+                let mut done = false;
+                while let Ok(record) = rx.try_recv() {
+                    done |= matches!(record, RawRecord::End { .. });
+                    records.push(record);
+                }
+                if let Some(writer) = file.as_mut().filter(|_| !records.is_empty()) {
+                    match write_chunk(writer, &records) {
+                        Ok(()) => records.clear(),
+                        Err(write_error) => {
+                            // Drop the buffer without writing it, so the file ends with
+                            // an incomplete chunk at worst.
+                            file.take().map(BufWriter::into_parts);
+                            error = Some(write_error);
+                        }
+                    }
+                }
+                if done {
+                    return (records, error);
+                }
+            }
+        });
+
+        Ok(Journal {
+            path: path.to_owned(),
+            recorder: Recorder(Some(tx)),
+            writer,
+        })
+    }
+
+    pub(crate) fn recorder(&self) -> Recorder {
+        self.recorder.clone()
+    }
+
+    /// Records the end of the test and waits for everything to be written.
+    pub(crate) async fn finish(self, end: RawRecord) -> Result<(), Box<dyn Error>> {
+        match self.end(end).await?.1 {
+            Some(error) => Err(error.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Records the end of the test and reads the result back from the journal. If writing
+    /// failed, the records which weren't written are included and the error is returned too.
+    pub(crate) async fn finish_result(
+        self,
+        end: RawRecord,
+    ) -> Result<(RawResult, Option<io::Error>), Box<dyn Error>> {
+        let path = self.path.clone();
+        let (unwritten, error) = self.end(end).await?;
+        let mut file = BufReader::new(File::open(path)?);
+        let header: RawHeader = bincode::deserialize_from(&mut file)?;
+        let result = build(read_records(file).chain(unwritten), header.version)?;
+        Ok((result, error))
+    }
+
+    async fn end(self, end: RawRecord) -> Result<(Vec<RawRecord>, Option<io::Error>), JoinError> {
+        self.recorder.record(end);
+        self.writer.await
+    }
+}
+
+fn write_chunk(file: &mut impl Write, records: &[RawRecord]) -> io::Result<()> {
+    let mut data = Vec::new();
+    records
+        .serialize(&mut rmp_serde::Serializer::new(&mut data).with_struct_map())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    let data = snap::raw::Encoder::new().compress_vec(&data)?;
+    file.write_all(&(data.len() as u32).to_le_bytes())?;
+    file.write_all(&data)?;
+    file.flush()
+}
+
+/// Reads the next chunk, or `None` at the end of the file or at a damaged or incomplete chunk.
+fn read_chunk(reader: &mut impl Read) -> Option<Vec<RawRecord>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len).ok()?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_CHUNK {
+        return None;
+    }
+    let mut data = Vec::new();
+    reader
+        .by_ref()
+        .take(len as u64)
+        .read_to_end(&mut data)
+        .ok()?;
+    if data.len() != len || snap::raw::decompress_len(&data).ok()? > MAX_CHUNK {
+        return None;
+    }
+    let data = snap::raw::Decoder::new().decompress_vec(&data).ok()?;
+    rmp_serde::from_slice(&data).ok()
+}
+
+/// The records of a journal after its header, up to its last complete chunk.
+fn read_records(mut reader: impl Read) -> impl Iterator<Item = RawRecord> {
+    iter::from_fn(move || read_chunk(&mut reader)).flatten()
+}
+
+/// Loads the data of a journal after its header.
+pub(crate) fn load(reader: impl Read, version: u64) -> Result<RawResult, LoadError> {
+    build(read_records(reader), version)
+}
+
+/// Builds a result from the records of a journal.
+fn build(
+    mut records: impl Iterator<Item = RawRecord>,
+    version: u64,
+) -> Result<RawResult, LoadError> {
+    let mut result = match records.next() {
+        Some(RawRecord::Begin(result)) => *result,
+        _ => {
+            return Err(LoadError::Decode {
+                version,
+                error: "Journal doesn't begin with the test configuration".to_owned(),
+            })
+        }
+    };
+
+    let mut sent = BTreeMap::new();
+    let mut received = HashMap::new();
+    let mut returned = HashMap::new();
+    let mut ended = false;
+    let mut last = Duration::ZERO;
+
+    for record in records {
+        match record {
+            RawRecord::Begin(_) => (),
+            RawRecord::Start(start) => result.start = start,
+            RawRecord::PingSent { index, sent: time } => {
+                sent.insert(index, time);
+                last = last.max(time);
+            }
+            RawRecord::PingReceived { index, time } => {
+                received.insert(index, time);
+            }
+            RawRecord::PingReturned { index, time } => {
+                returned.insert(index, time);
+                last = last.max(time);
+            }
+            RawRecord::Sample {
+                download,
+                both,
+                stream,
+                point,
+            } => {
+                last = last.max(point.time);
+                result
+                    .stream_groups
+                    .iter_mut()
+                    .find(|group| group.download == download && group.both == both)
+                    .and_then(|group| group.streams.get_mut(stream as usize))
+                    .map(|stream| stream.data.push(point));
+            }
+            RawRecord::End {
+                duration,
+                load_termination_timeout,
+                server_overload,
+            } => {
+                result.duration = duration;
+                result.load_termination_timeout = load_termination_timeout;
+                result.server_overload = server_overload;
+                ended = true;
+            }
+        }
+    }
+
+    result.pings = sent
+        .iter()
+        .map(|(&index, &sent)| RawPing {
+            index,
+            sent,
+            latency: received.get(&index).map(|time| RawLatency {
+                total: returned.get(&index).map(|time| time.saturating_sub(sent)),
+                up: time.saturating_sub(sent),
+            }),
+        })
+        .collect();
+
+    if !ended {
+        // Pings sent after the last one which returned may still have been in flight, and
+        // the server reports the pings it received in batches.
+        let answered = result
+            .pings
+            .iter()
+            .filter(|ping| returned.contains_key(&ping.index))
+            .map(|ping| ping.sent)
+            .max();
+        let reported = result
+            .pings
+            .iter()
+            .filter(|ping| received.contains_key(&ping.index))
+            .map(|ping| ping.sent)
+            .max();
+        let answered = answered.min(reported);
+        if let Some(answered) = answered {
+            result.pings.retain(|ping| ping.sent <= answered);
+        }
+        // Remove the loads which didn't start.
+        result
+            .stream_groups
+            .retain(|group| group.streams.iter().any(|stream| !stream.data.is_empty()));
+        drop_unpaired(&mut result.stream_groups);
+        result.duration = last.saturating_sub(result.start);
+        result.inferred.interrupted = true;
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ping(index: u64) -> [RawRecord; 3] {
+        let sent = Duration::from_secs(index + 1);
+        [
+            RawRecord::PingSent { index, sent },
+            RawRecord::PingReceived {
+                index,
+                time: sent + Duration::from_millis(10),
+            },
+            RawRecord::PingReturned {
+                index,
+                time: sent + Duration::from_millis(20),
+            },
+        ]
+    }
+
+    /// A journal with one ping in each of its two chunks, and the offset of the second chunk.
+    fn journal() -> (Vec<u8>, usize) {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../data/v2.crr");
+        let mut begin = RawResult::load(Path::new(path)).unwrap();
+        begin.pings.clear();
+        for group in &mut begin.stream_groups {
+            for stream in &mut group.streams {
+                stream.data.clear();
+            }
+        }
+
+        let header = RawHeader {
+            magic: MAGIC,
+            ..Default::default()
+        };
+        let mut data = bincode::serialize(&header).unwrap();
+        let mut first = vec![
+            RawRecord::Begin(Box::new(begin)),
+            RawRecord::Start(Duration::ZERO),
+        ];
+        first.extend(ping(0));
+        write_chunk(&mut data, &first).unwrap();
+        let second = data.len();
+        let mut records: Vec<_> = ping(1).into();
+        records.push(RawRecord::End {
+            duration: Duration::from_secs(3),
+            load_termination_timeout: false,
+            server_overload: false,
+        });
+        write_chunk(&mut data, &records).unwrap();
+        (data, second)
+    }
+
+    #[test]
+    fn load_reads_all_chunks() {
+        let result = RawResult::load_from_reader(&journal().0[..]).unwrap();
+        assert_eq!(result.pings.len(), 2);
+        assert!(!result.inferred.interrupted);
+    }
+
+    #[test]
+    fn load_stops_at_truncated_chunk() {
+        let (data, _) = journal();
+        let result = RawResult::load_from_reader(&data[..data.len() - 2]).unwrap();
+        assert_eq!(result.pings.len(), 1);
+        assert!(result.inferred.interrupted);
+    }
+
+    #[test]
+    fn load_stops_at_corrupt_length() {
+        let (mut data, second) = journal();
+        data[second..(second + 4)].copy_from_slice(&u32::MAX.to_le_bytes());
+        let result = RawResult::load_from_reader(&data[..]).unwrap();
+        assert_eq!(result.pings.len(), 1);
+
+        // A chunk claiming to decompress to 4 GiB.
+        let (mut data, second) = journal();
+        data.truncate(second);
+        data.extend(5u32.to_le_bytes());
+        data.extend([0xff, 0xff, 0xff, 0xff, 0x0f]);
+        let result = RawResult::load_from_reader(&data[..]).unwrap();
+        assert_eq!(result.pings.len(), 1);
+    }
+}
@@ -2,6 +2,7 @@ use futures::future::FutureExt;
 use futures::{pin_mut, select};
 use parking_lot::Mutex;
 use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::{
     error::Error,
@@ -10,7 +11,7 @@ use std::{
     sync::Arc,
     time::Duration,
 };
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::UdpSocket;
 use tokio::sync::mpsc::{channel, Sender};
 use tokio::sync::oneshot;
@@ -22,8 +23,14 @@ use tokio::{
 };
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 
-use crate::protocol::{codec, receive, send, ClientMessage, Ping, ServerMessage};
-use crate::test::hello;
+use crate::file_format::{RawConfig, RawHeader, RawInferred, RawMetadata, RawResult};
+use crate::journal::{Journal, RawRecord};
+use crate::protocol::{
+    codec, receive, send, ClientMessage, Ping, PreSharedKey, ServerMessage, Session, SessionToken,
+};
+use crate::test::{hello, new_client, unique};
+use crate::timestamp::{self, TimestampSource};
+use crate::tls::{ClientTls, Connection, Connector};
 
 type UpdateFn = Arc<dyn Fn() + Send + Sync>;
 
@@ -31,6 +38,8 @@ type UpdateFn = Arc<dyn Fn() + Send + Sync>;
 pub struct Config {
     pub port: u16,
     pub ping_interval: Duration,
+    pub psk: Option<PreSharedKey>,
+    pub tls: Option<ClientTls>,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -84,36 +93,43 @@ impl Data {
     }
 }
 
+/// Monitors latency until `stop`, recording the whole session to `journal` if given.
 async fn test_async(
     config: Config,
     server: &str,
     data: Arc<Data>,
+    mut metadata: RawMetadata,
+    journal: Option<&Path>,
     stop: oneshot::Receiver<()>,
 ) -> Result<(), Box<dyn Error>> {
-    let control = net::TcpStream::connect((server, config.port)).await?;
-    control.set_nodelay(true)?;
+    let connector = Connector::new(config.tls, server)?;
+    let control = connector.connect((server, config.port)).await?;
+
+    metadata.server = Some(server.to_owned());
 
     let server = control.peer_addr()?;
 
+    metadata.server_address = Some(server.ip().to_string());
+
+    let tls = control.tls();
+
     *data.state.lock() = State::Syncing;
     (data.update_fn)();
 
-    let (rx, tx) = control.into_split();
+    let (rx, tx) = tokio::io::split(control);
     let mut control_rx = FramedRead::new(rx, codec());
     let mut control_tx = FramedWrite::new(tx, codec());
 
     hello(&mut control_tx, &mut control_rx).await?;
 
-    send(&mut control_tx, &ClientMessage::NewClient).await?;
-
     let setup_start = data.start;
 
-    let reply: ServerMessage = receive(&mut control_rx).await?;
-    let id = match reply {
-        ServerMessage::NewClient(Some(id)) => id,
-        ServerMessage::NewClient(None) => return Err("Server was unable to create client".into()),
-        _ => return Err(format!("Unexpected message {:?}", reply).into()),
-    };
+    metadata.start_time = Some(chrono::Utc::now());
+
+    let (session, server_timestamps, server_version) =
+        new_client(&mut control_tx, &mut control_rx, config.psk).await?;
+
+    metadata.server_version = Some(server_version);
 
     let local_udp = if server.is_ipv6() {
         SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
@@ -123,8 +139,8 @@ async fn test_async(
 
     let mut ping_index = 0;
 
-    let (_latency, server_time_offset, mut control_rx) = measure_latency(
-        id,
+    let (latency, server_time_offset, mut control_rx) = measure_latency(
+        session,
         &mut ping_index,
         &mut control_tx,
         control_rx,
@@ -136,10 +152,47 @@ async fn test_async(
 
     let udp_socket = Arc::new(net::UdpSocket::bind(local_udp).await?);
     udp_socket.connect(server).await?;
+    let timestamps = timestamp::enable(&udp_socket);
     let udp_socket2 = udp_socket.clone();
 
     let ping_interval = config.ping_interval;
 
+    // A session is stored as a test without loads.
+    let journal = match journal {
+        Some(path) => Some(Journal::create(
+            path,
+            RawResult {
+                version: RawHeader::default().version,
+                generated_by: format!("Crusader {} latency monitor", env!("CARGO_PKG_VERSION")),
+                config: RawConfig {
+                    stagger: Duration::ZERO,
+                    load_duration: Duration::ZERO,
+                    grace_duration: Duration::ZERO,
+                    ping_interval,
+                    bandwidth_interval: ping_interval,
+                    tls,
+                },
+                ipv6: server.is_ipv6(),
+                load_termination_timeout: false,
+                server_overload: false,
+                server_latency: latency,
+                client_timestamps: timestamps,
+                server_timestamps,
+                start: Duration::ZERO,
+                duration: Duration::ZERO,
+                stream_groups: Vec::new(),
+                pings: Vec::new(),
+                metadata,
+                inferred: RawInferred::default(),
+            },
+        )?),
+        None => None,
+    };
+    let recorder = journal
+        .as_ref()
+        .map(|journal| journal.recorder())
+        .unwrap_or_default();
+
     let (event_tx, mut event_rx) = channel(1000);
 
     send(&mut control_tx, &ClientMessage::GetMeasurements).await?;
@@ -176,10 +229,12 @@ async fn test_async(
         overload_
     });
 
-    tokio::spawn(ping_recv(
+    let ping_recv = tokio::spawn(ping_recv(
         event_tx.clone(),
         setup_start,
         udp_socket2.clone(),
+        session.token,
+        timestamps,
     ));
 
     time::sleep(Duration::from_millis(50)).await;
@@ -187,17 +242,35 @@ async fn test_async(
     *data.state.lock() = State::Monitoring;
     (data.update_fn)();
 
-    tokio::spawn(ping_send(
+    let start = setup_start.elapsed();
+    recorder.record(RawRecord::Start(start));
+
+    let ping_send = tokio::spawn(ping_send(
         event_tx.clone(),
         ping_index,
-        id,
+        session,
         setup_start,
         udp_socket2.clone(),
         ping_interval,
     ));
 
-    tokio::spawn(async move {
+    let events = tokio::spawn(async move {
         while let Some(event) = event_rx.recv().await {
+            match event.kind {
+                EventKind::Sent => recorder.record(RawRecord::PingSent {
+                    index: event.ping_index,
+                    sent: event.time,
+                }),
+                EventKind::AtServer => recorder.record(RawRecord::PingReceived {
+                    index: event.ping_index,
+                    time: event.time,
+                }),
+                EventKind::Pong => recorder.record(RawRecord::PingReturned {
+                    index: event.ping_index,
+                    time: event.time,
+                }),
+                EventKind::Timeout => (),
+            }
             {
                 let mut points = data.points.lock().await;
                 let i = points
@@ -242,19 +315,39 @@ async fn test_async(
 
     stop.await?;
 
+    let duration = setup_start.elapsed().saturating_sub(start);
+
+    // Let the last pings return before the server stops measuring them.
+    ping_send.abort();
+    time::sleep(Duration::from_secs(1)).await;
+
     send(&mut control_tx, &ClientMessage::StopMeasurements).await?;
     send(&mut control_tx, &ClientMessage::Done).await?;
 
-    let _server_overload = measures.await?;
+    let server_overload = measures.await?;
+
+    ping_recv.abort();
+    drop(event_tx);
+    events.await?;
+
+    if let Some(journal) = journal {
+        journal
+            .finish(RawRecord::End {
+                duration,
+                load_termination_timeout: false,
+                server_overload,
+            })
+            .await?;
+    }
 
     Ok(())
 }
 
 async fn measure_latency(
-    id: u64,
+    session: Session,
     ping_index: &mut u64,
-    mut control_tx: &mut FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>,
-    mut control_rx: FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
+    mut control_tx: &mut FramedWrite<WriteHalf<Connection>, LengthDelimitedCodec>,
+    mut control_rx: FramedRead<ReadHalf<Connection>, LengthDelimitedCodec>,
     server: SocketAddr,
     local_udp: SocketAddr,
     setup_start: Instant,
@@ -262,7 +355,7 @@ async fn measure_latency(
     (
         Duration,
         u64,
-        FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
+        FramedRead<ReadHalf<Connection>, LengthDelimitedCodec>,
     ),
     Box<dyn Error>,
 > {
@@ -287,6 +380,7 @@ async fn measure_latency(
 
     let udp_socket = Arc::new(net::UdpSocket::bind(local_udp).await?);
     udp_socket.connect(server).await?;
+    let timestamps = timestamp::enable(&udp_socket);
     let udp_socket2 = udp_socket.clone();
 
     let samples = 50;
@@ -294,13 +388,19 @@ async fn measure_latency(
     let ping_start_index = *ping_index;
     let ping_send = tokio::spawn(ping_measure_send(
         ping_start_index,
-        id,
+        session,
         setup_start,
         udp_socket,
         samples,
     ));
 
-    let ping_recv = tokio::spawn(ping_measure_recv(setup_start, udp_socket2, samples));
+    let ping_recv = tokio::spawn(ping_measure_recv(
+        setup_start,
+        udp_socket2,
+        session.token,
+        timestamps,
+        samples,
+    ));
 
     let (sent, recv) = join!(ping_send, ping_recv);
 
@@ -348,7 +448,7 @@ async fn measure_latency(
 
 async fn ping_measure_send(
     mut index: u64,
-    id: u64,
+    session: Session,
     setup_start: Instant,
     socket: Arc<UdpSocket>,
     samples: u32,
@@ -363,7 +463,7 @@ async fn ping_measure_send(
 
         let current = setup_start.elapsed();
 
-        let ping = Ping { id, index };
+        let ping = Ping::new(session.id, index, &session.token);
 
         index += 1;
 
@@ -382,6 +482,8 @@ async fn ping_measure_send(
 async fn ping_measure_recv(
     setup_start: Instant,
     socket: Arc<UdpSocket>,
+    token: SessionToken,
+    timestamps: TimestampSource,
     samples: u32,
 ) -> Vec<(Ping, Duration)> {
     let mut storage = Vec::with_capacity(samples as usize);
@@ -392,7 +494,7 @@ async fn ping_measure_recv(
 
     loop {
         let result = {
-            let packet = socket.recv(&mut buf).fuse();
+            let packet = timestamp::recv(&socket, &mut buf, timestamps).fuse();
             pin_mut!(packet);
 
             select! {
@@ -401,10 +503,13 @@ async fn ping_measure_recv(
             }
         };
 
-        let current = setup_start.elapsed();
-        let len = result.unwrap();
+        let (len, received) = result.unwrap();
+        let current = received.saturating_duration_since(setup_start);
         let buf = &mut buf[..len];
-        let ping: Ping = bincode::deserialize(buf).unwrap();
+        let ping = match bincode::deserialize::<Ping>(buf) {
+            Ok(ping) if ping.is_valid(&token) => ping,
+            _ => continue,
+        };
 
         storage.push((ping, current));
     }
@@ -415,7 +520,7 @@ async fn ping_measure_recv(
 async fn ping_send(
     event_tx: Sender<Event>,
     mut ping_index: u64,
-    id: u64,
+    session: Session,
     setup_start: Instant,
     socket: Arc<UdpSocket>,
     interval: Duration,
@@ -429,10 +534,7 @@ async fn ping_send(
 
         let current = setup_start.elapsed();
 
-        let ping = Ping {
-            id,
-            index: ping_index,
-        };
+        let ping = Ping::new(session.id, ping_index, &session.token);
 
         let mut cursor = Cursor::new(&mut buf[..]);
         bincode::serialize_into(&mut cursor, &ping).unwrap();
@@ -470,16 +572,21 @@ async fn ping_recv(
     event_tx: Sender<Event>,
     setup_start: Instant,
     socket: Arc<UdpSocket>,
+    token: SessionToken,
+    timestamps: TimestampSource,
 ) -> Vec<(Ping, Duration)> {
     let mut buf = [0; 64];
 
     loop {
-        let result = socket.recv(&mut buf).await;
+        let result = timestamp::recv(&socket, &mut buf, timestamps).await;
 
-        let current = setup_start.elapsed();
-        let len = result.unwrap();
+        let (len, received) = result.unwrap();
+        let current = received.saturating_duration_since(setup_start);
         let buf = &mut buf[..len];
-        let ping: Ping = bincode::deserialize(buf).unwrap();
+        let ping = match bincode::deserialize::<Ping>(buf) {
+            Ok(ping) if ping.is_valid(&token) => ping,
+            _ => continue,
+        };
 
         event_tx
             .send(Event {
@@ -492,10 +599,14 @@ async fn ping_recv(
     }
 }
 
+/// Monitors latency until stopped, recording the session to `journal` if given. The
+/// measured fields of `metadata` are filled in for the recording.
 pub fn test_callback(
     config: Config,
     host: &str,
     data: Arc<Data>,
+    metadata: RawMetadata,
+    journal: Option<PathBuf>,
     done: Box<dyn FnOnce(Option<Result<(), String>>) + Send>,
 ) -> oneshot::Sender<()> {
     let (stop_tx, stop_rx) = oneshot::channel();
@@ -514,7 +625,7 @@ pub fn test_callback(
             });
 
             let mut result = task::spawn(async move {
-                test_async(config, &host, data, rx)
+                test_async(config, &host, data, metadata, journal.as_deref(), rx)
                     .await
                     .map_err(|error| error.to_string())
             })
@@ -533,3 +644,51 @@ pub fn test_callback(
     });
     stop_tx
 }
+
+/// A file name for a new recording of a session.
+pub fn recording_name() -> String {
+    unique("monitor", "crr")
+}
+
+/// Records a session to a file until Ctrl-C is pressed or for `duration` if given.
+pub fn monitor(config: Config, host: &str, metadata: RawMetadata, duration: Option<Duration>) {
+    let name = recording_name();
+    let (done_tx, done_rx) = oneshot::channel();
+    let stop = test_callback(
+        config,
+        host,
+        Arc::new(Data::new(0, Arc::new(|| {}))),
+        metadata,
+        Some(PathBuf::from(&name)),
+        Box::new(move |result| {
+            done_tx.send(result).ok();
+        }),
+    );
+
+    println!("Recording latency to {}. Press Ctrl-C to stop", name);
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let result = rt.block_on(async move {
+        let mut done = done_rx.fuse();
+        let end = async {
+            match duration {
+                Some(duration) => time::sleep(duration).await,
+                None => futures::future::pending().await,
+            }
+        };
+        select! {
+            result = done => return result,
+            _ = tokio::signal::ctrl_c().fuse() => (),
+            _ = end.fuse() => (),
+        }
+        println!("Stopping..");
+        stop.send(()).ok();
+        done.await
+    });
+
+    match result {
+        Ok(Some(Ok(()))) => println!("Saved session to {}", name),
+        Ok(Some(Err(error))) => panic!("Monitoring failed: {}", error),
+        Ok(None) | Err(_) => panic!("Monitoring didn't stop. Partial data is in {}", name),
+    }
+}
@@ -7,9 +7,17 @@
     clippy::option_map_unit_fn
 )]
 
+pub mod discovery;
+pub mod edit;
 pub mod file_format;
+pub mod flent;
+mod journal;
 pub mod latency;
 pub mod plot;
 pub mod protocol;
+pub mod report;
+pub mod schedule;
 pub mod serve;
 pub mod test;
+pub mod timestamp;
+pub mod tls;
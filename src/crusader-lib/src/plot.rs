@@ -1,23 +1,244 @@
+use image::ImageEncoder;
+use plotters::coord::ranged1d::{AsRangedCoord, ValueFormatter};
 use plotters::coord::types::RangedCoordf64;
-use plotters::coord::Shift;
+use plotters::coord::{CoordTranslate, Shift};
 use plotters::prelude::*;
 use plotters::style::text_anchor::{HPos, Pos, VPos};
 use plotters::style::{register_font, RGBColor};
 
+use std::fs;
 use std::mem;
+use std::ops::Range;
 use std::path::Path;
+use std::str::FromStr;
 use std::time::Duration;
 
-use crate::file_format::{RawLatency, RawPing, RawResult};
+use crate::file_format::{RawLatency, RawMetadata, RawPing, RawResult};
 use crate::test::{unique, PlotConfig};
 
-const UP_COLOR: RGBColor = RGBColor(37, 83, 169);
-const DOWN_COLOR: RGBColor = RGBColor(95, 145, 62);
+pub(crate) const UP_COLOR: RGBColor = RGBColor(37, 83, 169);
+pub(crate) const DOWN_COLOR: RGBColor = RGBColor(95, 145, 62);
+pub(crate) const BOTH_COLOR: RGBColor = RGBColor(149, 96, 153);
+const IDLE_COLOR: RGBColor = RGBColor(120, 120, 120);
+
+const FONT: &[u8] = include_bytes!("../Ubuntu-Light.ttf");
 
 pub fn register_fonts() {
-    register_font("sans-serif", include_bytes!("../Ubuntu-Light.ttf"))
-        .map_err(|_| ())
-        .unwrap();
+    register_font("sans-serif", FONT).map_err(|_| ()).unwrap();
+}
+
+/// The image format plots are saved in.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PlotFormat {
+    #[default]
+    Png,
+    Svg,
+    Pdf,
+}
+
+impl PlotFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PlotFormat::Png => "png",
+            PlotFormat::Svg => "svg",
+            PlotFormat::Pdf => "pdf",
+        }
+    }
+
+    /// The format matching the extension of `path`, if any.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+}
+
+impl FromStr for PlotFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "png" => Ok(PlotFormat::Png),
+            "svg" => Ok(PlotFormat::Svg),
+            "pdf" => Ok(PlotFormat::Pdf),
+            _ => Err(format!(
+                "Unknown plot format `{}`, expected png, svg or pdf",
+                s
+            )),
+        }
+    }
+}
+
+/// Smoothing applied to bandwidth rates.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Smoothing {
+    #[default]
+    None,
+    /// The mean rate within a window centered on each sample.
+    MovingAverage(Duration),
+    /// An exponentially weighted moving average with this time constant.
+    Ewma(Duration),
+}
+
+impl Smoothing {
+    pub fn apply(&self, rates: &[(u64, f64)]) -> Vec<(u64, f64)> {
+        match *self {
+            Smoothing::None => rates.to_vec(),
+            Smoothing::MovingAverage(window) => {
+                let half = window.as_micros() as u64 / 2;
+                let (mut start, mut end, mut sum) = (0, 0, 0.0);
+                rates
+                    .iter()
+                    .map(|&(time, _)| {
+                        while end < rates.len() && rates[end].0 <= time + half {
+                            sum += rates[end].1;
+                            end += 1;
+                        }
+                        while rates[start].0 + half < time {
+                            sum -= rates[start].1;
+                            start += 1;
+                        }
+                        (time, sum / (end - start) as f64)
+                    })
+                    .collect()
+            }
+            Smoothing::Ewma(time_constant) => {
+                let time_constant = time_constant.as_secs_f64();
+                let mut last: Option<(u64, f64)> = None;
+                rates
+                    .iter()
+                    .map(|&(time, rate)| {
+                        let value = match last {
+                            Some((last_time, value)) if time_constant > 0.0 => {
+                                let elapsed = Duration::from_micros(time - last_time).as_secs_f64();
+                                let weight = 1.0 - (-elapsed / time_constant).exp();
+                                value + (rate - value) * weight
+                            }
+                            _ => rate,
+                        };
+                        last = Some((time, value));
+                        (time, value)
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// How bandwidth rates are computed from the bytes transferred.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RateFilter {
+    /// Resample the bytes transferred to this interval instead of the measured one.
+    pub interval: Option<Duration>,
+    pub smoothing: Smoothing,
+}
+
+impl RateFilter {
+    /// Rates in Mbps of cumulative `bytes`, like `to_rates`.
+    pub fn rates(&self, bytes: &[(u64, f64)]) -> Vec<(u64, f64)> {
+        let rates = match self.interval {
+            Some(interval) if !interval.is_zero() => {
+                to_rates(&interpolate(bytes, interval.as_micros() as u64))
+            }
+            _ => to_rates(bytes),
+        };
+        self.smoothing.apply(&rates)
+    }
+
+    /// A description for legends, if the rates aren't the measured ones.
+    pub fn describe(&self) -> Option<String> {
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let smoothing = match self.smoothing {
+            Smoothing::None => None,
+            Smoothing::MovingAverage(window) => {
+                Some(format!("{} ms moving average", millis(window)))
+            }
+            Smoothing::Ewma(time_constant) => Some(format!("{} ms EWMA", millis(time_constant))),
+        };
+        let interval = self
+            .interval
+            .map(|interval| format!("{} ms samples", millis(interval)));
+        match (interval, smoothing) {
+            (Some(interval), Some(smoothing)) => Some(format!("{}, {}", interval, smoothing)),
+            (interval, smoothing) => interval.or(smoothing),
+        }
+    }
+}
+
+/// Adds a legend entry describing `filter`, if it changes the rates.
+fn filter_legend<'a, DB: DrawingBackend + 'a, CT: CoordTranslate>(
+    chart: &mut ChartContext<'a, DB, CT>,
+    filter: &RateFilter,
+) where
+    CT::From: Clone + 'static,
+{
+    if let Some(description) = filter.describe() {
+        chart
+            .draw_series(std::iter::empty::<Circle<CT::From, i32>>())
+            .unwrap()
+            .label(description);
+    }
+}
+
+/// A plot which can be drawn with any backend.
+trait Figure {
+    fn draw<DB: DrawingBackend>(&self, root: &DrawingArea<DB, Shift>);
+}
+
+fn save_figure(path: &Path, format: PlotFormat, size: (u32, u32), figure: &impl Figure) {
+    fs::write(path, render_figure(format, size, figure)).expect("Unable to write plot to file");
+}
+
+fn render_figure(format: PlotFormat, size: (u32, u32), figure: &impl Figure) -> Vec<u8> {
+    let svg = |size| {
+        let mut svg = String::new();
+        {
+            let root = SVGBackend::with_string(&mut svg, size).into_drawing_area();
+            figure.draw(&root);
+            root.present().unwrap();
+        }
+        svg
+    };
+
+    match format {
+        PlotFormat::Png => {
+            let mut pixels = vec![0; size.0 as usize * size.1 as usize * 3];
+            {
+                let root = BitMapBackend::with_buffer(&mut pixels, size).into_drawing_area();
+                figure.draw(&root);
+                root.present().unwrap();
+            }
+            let mut png = Vec::new();
+            image::codecs::png::PngEncoder::new(&mut png)
+                .write_image(&pixels, size.0, size.1, image::ColorType::Rgb8)
+                .unwrap();
+            png
+        }
+        PlotFormat::Svg => svg(size).into_bytes(),
+        PlotFormat::Pdf => svg_to_pdf(&svg(size)),
+    }
+}
+
+fn svg_to_pdf(svg: &str) -> Vec<u8> {
+    use usvg::{TreeParsing, TreePostProc};
+
+    // Text is converted to paths, so use the same font as the other formats.
+    let mut fonts = usvg::fontdb::Database::new();
+    fonts.load_font_data(FONT.to_vec());
+    let family = fonts.faces().next().map(|face| face.families[0].0.clone());
+    if let Some(family) = family {
+        fonts.set_sans_serif_family(family);
+    }
+
+    let mut tree = usvg::Tree::from_str(svg, &usvg::Options::default()).unwrap();
+    tree.postprocess(usvg::PostProcessingSteps::default(), &fonts);
+    svg2pdf::convert_tree(&tree, svg2pdf::Options::default())
+}
+
+/// Splits a known image extension from `name`, which overrides the configured format.
+fn output_name(name: &str, format: PlotFormat) -> (&str, PlotFormat) {
+    match PlotFormat::from_path(Path::new(name)) {
+        Some(format) => (&name[..(name.len() - format.extension().len() - 1)], format),
+        None => (name, format),
+    }
 }
 
 impl RawResult {
@@ -27,35 +248,34 @@ impl RawResult {
         let stream_groups: Vec<_> = self
             .stream_groups
             .iter()
-            .map(|group| TestStreamGroup {
-                download: group.download,
-                both: group.both,
-                streams: (0..(group.streams.len()))
-                    .map(|i| {
-                        let bytes: Vec<_> = (0..=i)
-                            .map(|i| to_float(&group.streams[i].to_vec()))
-                            .collect();
-                        let bytes: Vec<_> = bytes.iter().map(|stream| stream.as_slice()).collect();
-                        TestStream {
-                            data: sum_bytes(&bytes, bandwidth_interval),
-                        }
-                    })
-                    .collect(),
+            .map(|group| {
+                let bytes: Vec<_> = group
+                    .streams
+                    .iter()
+                    .map(|stream| to_float(&stream.to_vec()))
+                    .collect();
+                let bytes: Vec<_> = bytes.iter().map(|stream| stream.as_slice()).collect();
+                TestStreamGroup {
+                    download: group.download,
+                    both: group.both,
+                    streams: stack_bytes(&bytes, bandwidth_interval)
+                        .into_iter()
+                        .map(|data| TestStream { data })
+                        .collect(),
+                }
             })
             .collect();
 
-        let process_bytes = |bytes: Vec<Vec<(u64, u64)>>| -> Vec<(u64, f64)> {
-            let bytes: Vec<_> = bytes.iter().map(|stream| to_float(stream)).collect();
-            let bytes: Vec<_> = bytes.iter().map(|stream| stream.as_slice()).collect();
-            sum_bytes(&bytes, bandwidth_interval)
-        };
-
+        // The last stacked stream of a group is the sum of all its streams.
         let groups: Vec<_> = self
             .stream_groups
             .iter()
-            .map(|group| {
-                let streams: Vec<_> = group.streams.iter().map(|stream| stream.to_vec()).collect();
-                let single = process_bytes(streams);
+            .zip(&stream_groups)
+            .map(|(group, stacked)| {
+                let single = match stacked.streams.last() {
+                    Some(stream) => stream.data.clone(),
+                    None => sum_bytes(&[], bandwidth_interval),
+                };
                 (group, single)
             })
             .collect();
@@ -131,6 +351,69 @@ pub struct TestStreamGroup {
     pub streams: Vec<TestStream>,
 }
 
+impl TestStreamGroup {
+    pub fn phase(&self) -> Phase {
+        match (self.both, self.download) {
+            (true, _) => Phase::Both,
+            (false, true) => Phase::Download,
+            (false, false) => Phase::Upload,
+        }
+    }
+
+    /// The bytes transferred by each individual stream, sampled at the same times.
+    fn stream_bytes(&self) -> Vec<Vec<(u64, f64)>> {
+        let times: Vec<_> = self
+            .streams
+            .last()
+            .map(|stream| stream.data.iter().map(|e| e.0).collect())
+            .unwrap_or_default();
+
+        // Each `TestStream` includes the bytes of the streams before it.
+        (0..self.streams.len())
+            .map(|i| {
+                times
+                    .iter()
+                    .map(|&time| {
+                        let before = i
+                            .checked_sub(1)
+                            .map(|i| bytes_at(&self.streams[i].data, time))
+                            .unwrap_or(0.0);
+                        (time, bytes_at(&self.streams[i].data, time) - before)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The rate in Mbps of each individual stream, sampled at the same times.
+    pub fn stream_rates(&self) -> Vec<Vec<(u64, f64)>> {
+        self.stream_bytes()
+            .iter()
+            .map(|bytes| {
+                bytes
+                    .windows(2)
+                    .map(|pair| (pair[1].0, rate(pair[0], pair[1])))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Throughput of a single stream in Mbps.
+pub struct StreamStats {
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+}
+
+/// Jain's fairness index, which is 1 when all `values` are equal and `1 / n` when one has it all.
+pub fn jain_index(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (n, sum, squares) = values.fold((0.0, 0.0, 0.0), |(n, sum, squares), value| {
+        (n + 1.0, sum + value, squares + value * value)
+    });
+    (squares > 0.0).then(|| sum * sum / (n * squares))
+}
+
 pub struct TestResult {
     pub raw_result: RawResult,
     pub start: Duration,
@@ -146,143 +429,1333 @@ pub struct TestResult {
     pub stream_groups: Vec<TestStreamGroup>,
 }
 
-pub fn save_graph(config: &PlotConfig, result: &TestResult, name: &str) -> String {
-    let file = unique(name, "png");
-    save_graph_to_path(file.as_ref(), config, result);
-    file
+/// A load applied during a test.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Download,
+    Upload,
+    Both,
 }
 
-pub fn save_graph_to_path(path: &Path, config: &PlotConfig, result: &TestResult) {
-    let mut bandwidth = Vec::new();
-
-    result.both_bytes.as_ref().map(|both_bytes| {
-        bandwidth.push((
-            "Both",
-            RGBColor(149, 96, 153),
-            to_rates(both_bytes),
-            vec![both_bytes.as_slice()],
-        ));
-    });
+impl Phase {
+    pub const ALL: [Phase; 3] = [Phase::Download, Phase::Upload, Phase::Both];
 
-    if result.upload_bytes.is_some() || result.both_upload_bytes.is_some() {
-        bandwidth.push((
-            "Upload",
-            UP_COLOR,
-            to_rates(&result.combined_upload_bytes),
-            [
-                result.upload_bytes.as_deref(),
-                result.both_upload_bytes.as_deref(),
-            ]
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>(),
-        ));
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Download => "Download",
+            Phase::Upload => "Upload",
+            Phase::Both => "Both",
+        }
     }
 
-    if result.download_bytes.is_some() || result.both_download_bytes.is_some() {
-        bandwidth.push((
-            "Download",
-            DOWN_COLOR,
-            to_rates(&result.combined_download_bytes),
-            [
-                result.download_bytes.as_deref(),
-                result.both_download_bytes.as_deref(),
-            ]
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>(),
-        ));
+    pub(crate) fn color(&self) -> RGBColor {
+        match self {
+            Phase::Download => DOWN_COLOR,
+            Phase::Upload => UP_COLOR,
+            Phase::Both => BOTH_COLOR,
+        }
     }
+}
 
-    graph(
-        path,
-        config,
-        result,
-        &result.pings,
-        &bandwidth,
-        result.start.as_secs_f64(),
-        result.duration.as_secs_f64(),
-    );
+impl FromStr for Phase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "download" => Ok(Phase::Download),
+            "upload" => Ok(Phase::Upload),
+            "both" => Ok(Phase::Both),
+            _ => Err(format!(
+                "Unknown load `{}`, expected download, upload or both",
+                s
+            )),
+        }
+    }
 }
 
-pub fn float_max(iter: impl Iterator<Item = f64>) -> f64 {
-    let mut max = iter.fold(f64::NAN, f64::max);
+/// The share of pings lost in a window of time, in percent.
+pub struct LossRate {
+    /// The middle of the window in microseconds.
+    pub time: u64,
+    pub total: f64,
+    /// Pings lost on the way to the server. Only known from version 2.
+    pub up: Option<f64>,
+    /// Replies lost on the way back. Only known from version 2.
+    pub down: Option<f64>,
+}
 
-    if max.is_nan() {
-        max = 100.0;
-    }
+/// Runs of consecutive lost pings.
+#[derive(Copy, Clone, Default)]
+pub struct LossBursts {
+    pub count: u64,
+    /// Mean number of pings in a burst.
+    pub mean: f64,
+    /// Pings in the longest burst.
+    pub max: u64,
+}
 
-    max
+/// Headline numbers for a test.
+pub struct Summary {
+    /// Mean throughput in Mbps of each load.
+    pub download: Option<f64>,
+    pub upload: Option<f64>,
+    pub both: Option<f64>,
+    pub idle_latency: Duration,
+    /// Median latency while loaded.
+    pub loaded_latency: Option<Duration>,
+    /// Fraction of pings lost.
+    pub packet_loss: f64,
+    pub loss_bursts: LossBursts,
+    /// Jain's fairness index of the mean stream throughput of each load.
+    pub download_fairness: Option<f64>,
+    pub upload_fairness: Option<f64>,
+    pub both_fairness: Option<f64>,
 }
 
-fn to_float(stream: &[(u64, u64)]) -> Vec<(u64, f64)> {
-    stream.iter().map(|(t, v)| (*t, *v as f64)).collect()
+/// The loads of a test in seconds relative to `start`.
+fn phase_spans(result: &TestResult, start: f64) -> Vec<(Phase, f64, f64)> {
+    result
+        .phases()
+        .into_iter()
+        .map(|(phase, phase_start, phase_end)| {
+            (
+                phase,
+                Duration::from_micros(phase_start).as_secs_f64() - start,
+                Duration::from_micros(phase_end).as_secs_f64() - start,
+            )
+        })
+        .collect()
 }
 
-pub fn to_rates(stream: &[(u64, f64)]) -> Vec<(u64, f64)> {
-    let mut result: Vec<(u64, f64)> = (0..stream.len())
-        .map(|i| {
-            let rate = if i > 0 {
-                let bytes = stream[i].1 - stream[i - 1].1;
-                let duration = Duration::from_micros(stream[i].0 - stream[i - 1].0);
-                let mbits = (bytes as f64 * 8.0) / (1000.0 * 1000.0);
-                mbits / duration.as_secs_f64()
-            } else {
-                0.0
-            };
-            (stream[i].0, rate)
+fn mean_rate(bytes: &[(u64, f64)]) -> Option<f64> {
+    let (first, last) = (bytes.first()?, bytes.last()?);
+    let duration = Duration::from_micros(last.0.checked_sub(first.0)?).as_secs_f64();
+    (duration > 0.0).then(|| (last.1 - first.1) * 8.0 / (1000.0 * 1000.0) / duration)
+}
+
+/// The window used for loss rates, which fits a reasonable number of pings.
+fn loss_window(result: &TestResult) -> Duration {
+    (result.raw_result.config.ping_interval * 20).max(Duration::from_millis(200))
+}
+
+/// Whether packet loss is plotted as a rate rather than as ticks for each lost ping.
+/// Results before V2 don't know which way pings were lost, and results which are
+/// short compared to the window can't show a rate.
+fn plot_loss_rate(config: &PlotConfig, result: &TestResult) -> bool {
+    !config.loss_ticks
+        && !result.raw_result.inferred.loss_direction
+        && result.raw_result.duration >= loss_window(result) * 4
+}
+
+/// The rate in Mbps between two samples of bytes transferred.
+fn rate(from: (u64, f64), to: (u64, f64)) -> f64 {
+    let duration = Duration::from_micros(to.0.saturating_sub(from.0)).as_secs_f64();
+    if duration > 0.0 {
+        (to.1 - from.1) * 8.0 / (1000.0 * 1000.0) / duration
+    } else {
+        0.0
+    }
+}
+
+/// The bytes transferred at `time` in a stream sampled at regular intervals.
+fn bytes_at(stream: &[(u64, f64)], time: u64) -> f64 {
+    match stream.binary_search_by_key(&time, |e| e.0) {
+        Ok(i) => stream[i].1,
+        Err(0) => 0.0,
+        Err(i) => stream[i - 1].1,
+    }
+}
+
+impl TestResult {
+    /// The loads in the order they ran, with their start and end in microseconds.
+    pub fn phases(&self) -> Vec<(Phase, u64, u64)> {
+        let mut phases: Vec<_> = [
+            (Phase::Download, self.download_bytes.as_deref()),
+            (Phase::Upload, self.upload_bytes.as_deref()),
+            (Phase::Both, self.both_bytes.as_deref()),
+        ]
+        .into_iter()
+        .filter_map(|(phase, bytes)| {
+            let bytes = bytes?;
+            let (first, last) = (bytes.first()?.1, bytes.last()?.1);
+            // Use the span where data was transferred as samples may also be taken while idle.
+            let start = bytes.iter().rposition(|e| e.1 <= first)?;
+            let end = bytes.iter().position(|e| e.1 >= last)?;
+            Some((phase, bytes[start].0, bytes[end.max(start)].0))
         })
         .collect();
+        phases.sort_by_key(|phase| phase.1);
+        phases
+    }
 
-    // Insert dummy zero points for nicer graphs
-    if !result.is_empty() {
-        result.first().unwrap().0.checked_sub(1).map(|first| {
-            result.insert(0, (first, 0.0));
-        });
-        result.push((result.last().unwrap().0 + 1, 0.0));
+    /// The span of the load of `group` in microseconds.
+    fn group_span(&self, group: &TestStreamGroup) -> Option<(u64, u64)> {
+        self.phases()
+            .into_iter()
+            .find(|phase| phase.0 == group.phase())
+            .map(|phase| (phase.1, phase.2))
     }
 
-    result
+    /// Throughput statistics of each stream in `group` during its load.
+    pub fn stream_stats(&self, group: &TestStreamGroup) -> Vec<StreamStats> {
+        let (start, end) = match self.group_span(group) {
+            Some(span) => span,
+            None => return Vec::new(),
+        };
+
+        group
+            .stream_bytes()
+            .iter()
+            .map(|bytes| {
+                let bytes: Vec<_> = bytes
+                    .iter()
+                    .copied()
+                    .filter(|e| e.0 >= start && e.0 <= end)
+                    .collect();
+                // Unstacking the streams can leave tiny negative rates from rounding.
+                let rates: Vec<_> = bytes
+                    .windows(2)
+                    .map(|pair| rate(pair[0], pair[1]).max(0.0))
+                    .collect();
+                StreamStats {
+                    min: rates.iter().copied().fold(f64::NAN, f64::min),
+                    mean: mean_rate(&bytes).unwrap_or(f64::NAN),
+                    max: rates.iter().copied().fold(f64::NAN, f64::max),
+                }
+            })
+            .collect()
+    }
+
+    /// Jain's fairness index of the stream rates in `group` over the duration of its load.
+    pub fn fairness(&self, group: &TestStreamGroup) -> Vec<(u64, f64)> {
+        let (start, end) = match self.group_span(group) {
+            Some(span) => span,
+            None => return Vec::new(),
+        };
+
+        let rates = group.stream_rates();
+        let samples = rates.first().map(|rates| rates.len()).unwrap_or(0);
+
+        (0..samples)
+            .filter_map(|i| {
+                let time = rates[0][i].0;
+                if time <= start || time > end {
+                    return None;
+                }
+                Some((time, jain_index(rates.iter().map(|rates| rates[i].1))?))
+            })
+            .collect()
+    }
+
+    /// Jain's fairness index of the mean stream throughput during `phase`.
+    /// For the bidirectional load this is the lower of the two directions.
+    fn phase_fairness(&self, phase: Phase) -> Option<f64> {
+        self.stream_groups
+            .iter()
+            .filter(|group| group.phase() == phase)
+            .filter_map(|group| {
+                jain_index(
+                    self.stream_stats(group)
+                        .iter()
+                        .map(|stats| stats.mean)
+                        .filter(|mean| !mean.is_nan()),
+                )
+            })
+            .reduce(f64::min)
+    }
+
+    /// Pings sent after the test started, in the order they were sent.
+    fn test_pings(&self) -> Vec<&RawPing> {
+        let mut pings: Vec<_> = self
+            .pings
+            .iter()
+            .filter(|ping| ping.sent >= self.start)
+            .collect();
+        pings.sort_by_key(|ping| ping.sent);
+        pings
+    }
+
+    /// The share of pings lost in consecutive windows of `window` over the test.
+    pub fn loss_rates(&self, window: Duration) -> Vec<LossRate> {
+        let window = window.as_micros().max(1) as u64;
+        let start = self.start.as_micros() as u64;
+        let windows = (self.duration.as_micros() as u64).div_ceil(window);
+        let split = !self.raw_result.inferred.loss_direction;
+
+        // Counts of sent, up lost and down lost pings in each window.
+        let mut counts = vec![(0u64, 0u64, 0u64); windows as usize];
+        for ping in self.test_pings() {
+            let i = ((ping.sent.as_micros() as u64 - start) / window) as usize;
+            if let Some(count) = counts.get_mut(i) {
+                count.0 += 1;
+                match ping.latency {
+                    None => count.1 += 1,
+                    Some(latency) if latency.total.is_none() => count.2 += 1,
+                    Some(_) => {}
+                }
+            }
+        }
+
+        counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| count.0 > 0)
+            .map(|(i, &(sent, up, down))| {
+                let percent = |lost| lost as f64 * 100.0 / sent as f64;
+                LossRate {
+                    time: start + i as u64 * window + window / 2,
+                    total: percent(up + down),
+                    up: split.then(|| percent(up)),
+                    down: split.then(|| percent(down)),
+                }
+            })
+            .collect()
+    }
+
+    pub fn loss_bursts(&self) -> LossBursts {
+        let mut bursts = Vec::new();
+        let mut current = 0;
+        for ping in self.test_pings() {
+            if ping.latency.and_then(|latency| latency.total).is_none() {
+                current += 1;
+            } else if current > 0 {
+                bursts.push(mem::take(&mut current));
+            }
+        }
+        if current > 0 {
+            bursts.push(current);
+        }
+
+        LossBursts {
+            count: bursts.len() as u64,
+            mean: if bursts.is_empty() {
+                0.0
+            } else {
+                bursts.iter().sum::<u64>() as f64 / bursts.len() as f64
+            },
+            max: bursts.iter().copied().max().unwrap_or(0),
+        }
+    }
+
+    pub fn summary(&self) -> Summary {
+        let loads = self.phases();
+
+        let mut loaded: Vec<_> = self
+            .pings
+            .iter()
+            .filter(|ping| {
+                let sent = ping.sent.as_micros() as u64;
+                loads
+                    .iter()
+                    .any(|&(_, start, end)| sent >= start && sent <= end)
+            })
+            .filter_map(|ping| ping.latency.and_then(|latency| latency.total))
+            .collect();
+        loaded.sort_unstable();
+
+        let pings: Vec<_> = self
+            .pings
+            .iter()
+            .filter(|ping| ping.sent >= self.start)
+            .collect();
+        let lost = pings
+            .iter()
+            .filter(|ping| ping.latency.and_then(|latency| latency.total).is_none())
+            .count();
+
+        Summary {
+            download: self.download_bytes.as_deref().and_then(mean_rate),
+            upload: self.upload_bytes.as_deref().and_then(mean_rate),
+            both: self.both_bytes.as_deref().and_then(mean_rate),
+            idle_latency: self.raw_result.server_latency,
+            loaded_latency: loaded.get(loaded.len() / 2).copied(),
+            packet_loss: if pings.is_empty() {
+                0.0
+            } else {
+                lost as f64 / pings.len() as f64
+            },
+            loss_bursts: self.loss_bursts(),
+            download_fairness: self.phase_fairness(Phase::Download),
+            upload_fairness: self.phase_fairness(Phase::Upload),
+            both_fairness: self.phase_fairness(Phase::Both),
+        }
+    }
 }
 
-fn sum_bytes(input: &[&[(u64, f64)]], interval: Duration) -> Vec<(u64, f64)> {
-    let interval = interval.as_micros() as u64;
+pub fn save_graph(config: &PlotConfig, result: &TestResult, name: &str) -> String {
+    let (name, format) = output_name(name, config.format);
+    let file = unique(name, format.extension());
+    save_graph_to_path(file.as_ref(), config, result);
+    file
+}
 
-    let bandwidth: Vec<_> = input
-        .iter()
-        .map(|stream| interpolate(stream, interval))
-        .collect();
+/// Saves a plot of `result`, in the format matching the extension of `path`
+/// or the configured format.
+pub fn save_graph_to_path(path: &Path, config: &PlotConfig, result: &TestResult) {
+    let graph = Graph::new(config, result);
+    save_figure(
+        path,
+        PlotFormat::from_path(path).unwrap_or(config.format),
+        graph.size(),
+        &graph,
+    );
+}
 
-    let min = bandwidth
-        .iter()
-        .map(|stream| stream.first().map(|e| e.0).unwrap_or(0))
-        .min()
-        .unwrap_or(0);
+/// Renders a plot of `result` into an image file in memory.
+pub fn render(config: &PlotConfig, result: &TestResult, format: PlotFormat) -> Vec<u8> {
+    let graph = Graph::new(config, result);
+    render_figure(format, graph.size(), &graph)
+}
 
-    let max = bandwidth
-        .iter()
-        .map(|stream| stream.last().map(|e| e.0).unwrap_or(0))
-        .max()
-        .unwrap_or(0);
+/// Draws the plot of a test result, for use with any plotters backend.
+pub struct Graph<'a> {
+    config: &'a PlotConfig,
+    result: &'a TestResult,
+}
 
-    let mut data = Vec::new();
+impl<'a> Graph<'a> {
+    pub fn new(config: &'a PlotConfig, result: &'a TestResult) -> Self {
+        Graph { config, result }
+    }
+
+    /// The image size in pixels used when saving the plot.
+    pub fn size(&self) -> (u32, u32) {
+        // Latency distributions get rows below the time series.
+        let distributions = Distribution::enabled(self.config).len() as u64;
+
+        (
+            self.config.width.unwrap_or(1280) as u32,
+            self.config.height.unwrap_or(720 + 250 * distributions) as u32,
+        )
+    }
+
+    /// Draws the plot onto `root`. Text and margins are not scaled,
+    /// so this looks best at about `size`.
+    pub fn draw<DB: DrawingBackend>(&self, root: &DrawingArea<DB, Shift>) {
+        let result = self.result;
+
+        let mut bandwidth = Vec::new();
+
+        result.both_bytes.as_ref().map(|both_bytes| {
+            bandwidth.push((
+                "Both",
+                BOTH_COLOR,
+                self.config.rates.rates(both_bytes),
+                vec![both_bytes.as_slice()],
+            ));
+        });
+
+        if result.upload_bytes.is_some() || result.both_upload_bytes.is_some() {
+            bandwidth.push((
+                "Upload",
+                UP_COLOR,
+                self.config.rates.rates(&result.combined_upload_bytes),
+                [
+                    result.upload_bytes.as_deref(),
+                    result.both_upload_bytes.as_deref(),
+                ]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>(),
+            ));
+        }
+
+        if result.download_bytes.is_some() || result.both_download_bytes.is_some() {
+            bandwidth.push((
+                "Download",
+                DOWN_COLOR,
+                self.config.rates.rates(&result.combined_download_bytes),
+                [
+                    result.download_bytes.as_deref(),
+                    result.both_download_bytes.as_deref(),
+                ]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>(),
+            ));
+        }
+
+        graph(
+            root,
+            self.config,
+            result,
+            &result.pings,
+            &bandwidth,
+            result.start.as_secs_f64(),
+            result.duration.as_secs_f64(),
+        );
+    }
+}
+
+impl Figure for Graph<'_> {
+    fn draw<DB: DrawingBackend>(&self, root: &DrawingArea<DB, Shift>) {
+        Graph::draw(self, root);
+    }
+}
+
+/// Plots multiple results on shared axes with the start of each load aligned.
+/// Each result is paired with the name used in the legend.
+pub fn save_comparison(config: &PlotConfig, results: &[(&str, &TestResult)], name: &str) -> String {
+    let (name, format) = output_name(name, config.format);
+    let file = unique(name, format.extension());
+    save_comparison_to_path(file.as_ref(), config, results);
+    file
+}
+
+pub fn save_comparison_to_path(path: &Path, config: &PlotConfig, results: &[(&str, &TestResult)]) {
+    save_figure(
+        path,
+        PlotFormat::from_path(path).unwrap_or(config.format),
+        (
+            config.width.unwrap_or(1280) as u32,
+            config.height.unwrap_or(720) as u32,
+        ),
+        &Comparison { config, results },
+    );
+}
+
+struct Comparison<'a> {
+    config: &'a PlotConfig,
+    results: &'a [(&'a str, &'a TestResult)],
+}
+
+impl Figure for Comparison<'_> {
+    fn draw<DB: DrawingBackend>(&self, root: &DrawingArea<DB, Shift>) {
+        comparison(root, self.config, self.results);
+    }
+}
+
+fn comparison<DB: DrawingBackend>(
+    root: &DrawingArea<DB, Shift>,
+    config: &PlotConfig,
+    results: &[(&str, &TestResult)],
+) {
+    let alignments = align(results);
+
+    let axes = Axes::new(
+        config,
+        float_max(
+            results
+                .iter()
+                .zip(&alignments)
+                .map(|((_, result), alignment)| alignment.map(result.duration.as_secs_f64())),
+        ),
+    );
+
+    let (width, _) = root.dim_in_pixel();
+
+    root.fill(&WHITE).unwrap();
+
+    let style: TextStyle = (FontFamily::SansSerif, 26).into();
+
+    root.draw_text(
+        "Latency under load comparison",
+        &style.pos(Pos::new(HPos::Center, VPos::Center)),
+        (width as i32 / 2, 25),
+    )
+    .unwrap();
+
+    let root = root.split_vertically(50).1;
+
+    let (root, loss) = root.split_vertically(root.relative_to_height(1.0) - 70.0);
+
+    let download = results
+        .iter()
+        .any(|(_, result)| result.raw_result.download() || result.raw_result.both());
+    let upload = results
+        .iter()
+        .any(|(_, result)| result.raw_result.upload() || result.raw_result.both());
+
+    let areas = root.split_evenly((1 + download as usize + upload as usize, 1));
+    let mut areas = areas.iter();
+
+    let phases = aligned_phases(results, &alignments);
+
+    if download {
+        compare_bandwidth(
+            true,
+            results,
+            &config.rates,
+            &alignments,
+            &axes,
+            &phases,
+            areas.next().unwrap(),
+        );
+    }
+    if upload {
+        compare_bandwidth(
+            false,
+            results,
+            &config.rates,
+            &alignments,
+            &axes,
+            &phases,
+            areas.next().unwrap(),
+        );
+    }
+
+    compare_latency(results, &alignments, &axes, &phases, areas.next().unwrap());
+    compare_packet_loss(results, &alignments, &axes, &phases, &loss);
+}
+
+fn compare_color(i: usize) -> RGBColor {
+    const COLORS: [RGBColor; 8] = [
+        RGBColor(37, 83, 169),
+        RGBColor(193, 85, 85),
+        RGBColor(95, 145, 62),
+        RGBColor(149, 96, 153),
+        RGBColor(219, 142, 38),
+        RGBColor(45, 150, 160),
+        RGBColor(120, 120, 120),
+        RGBColor(170, 140, 40),
+    ];
+    COLORS[i % COLORS.len()]
+}
+
+/// Maps the time of a result in seconds onto the shared time axis of a comparison.
+struct Alignment {
+    // Pairs of result time and shared time where loads start.
+    anchors: Vec<(f64, f64)>,
+}
+
+impl Alignment {
+    fn map(&self, time: f64) -> f64 {
+        let (local, shared) = self
+            .anchors
+            .iter()
+            .rev()
+            .find(|anchor| anchor.0 <= time)
+            .copied()
+            .unwrap_or((0.0, 0.0));
+        shared + time - local
+    }
+}
+
+fn align(results: &[(&str, &TestResult)]) -> Vec<Alignment> {
+    let phases: Vec<_> = results.iter().map(|(_, result)| result.phases()).collect();
+
+    let mut alignments: Vec<_> = results
+        .iter()
+        .map(|_| Alignment {
+            anchors: vec![(0.0, 0.0)],
+        })
+        .collect();
+
+    // Each load starts at the latest time any result would place it at,
+    // so the time before every load is at least as long as in each result.
+    for phase in Phase::ALL {
+        let starts: Vec<_> = results
+            .iter()
+            .zip(&phases)
+            .map(|((_, result), phases)| {
+                phases
+                    .iter()
+                    .find(|p| p.0 == phase)
+                    .map(|p| Duration::from_micros(p.1).as_secs_f64() - result.start.as_secs_f64())
+            })
+            .collect();
+
+        let shared = alignments
+            .iter()
+            .zip(&starts)
+            .filter_map(|(alignment, start)| Some(alignment.map((*start)?)))
+            .fold(f64::NAN, f64::max);
+
+        if shared.is_nan() {
+            continue;
+        }
+
+        for (alignment, start) in alignments.iter_mut().zip(starts) {
+            if let Some(start) = start {
+                alignment.anchors.push((start, shared));
+            }
+        }
+    }
+
+    alignments
+}
+
+/// The span of each load on the shared time axis, covering it in every result.
+fn aligned_phases(
+    results: &[(&str, &TestResult)],
+    alignments: &[Alignment],
+) -> Vec<(Phase, f64, f64)> {
+    let spans: Vec<_> = results
+        .iter()
+        .zip(alignments)
+        .flat_map(|((_, result), alignment)| {
+            phase_spans(result, result.start.as_secs_f64())
+                .into_iter()
+                .map(|(phase, start, end)| (phase, alignment.map(start), alignment.map(end)))
+        })
+        .collect();
+
+    Phase::ALL
+        .into_iter()
+        .filter_map(|phase| {
+            spans
+                .iter()
+                .filter(|span| span.0 == phase)
+                .map(|span| (span.0, span.1, span.2))
+                .reduce(|a, b| (phase, a.1.min(b.1), a.2.max(b.2)))
+        })
+        .collect()
+}
+
+fn compare_bandwidth<DB: DrawingBackend>(
+    download: bool,
+    results: &[(&str, &TestResult)],
+    filter: &RateFilter,
+    alignments: &[Alignment],
+    axes: &Axes,
+    phases: &[(Phase, f64, f64)],
+    area: &DrawingArea<DB, Shift>,
+) {
+    let rates: Vec<_> = results
+        .iter()
+        .map(|(_, result)| {
+            filter.rates(if download {
+                &result.combined_download_bytes
+            } else {
+                &result.combined_upload_bytes
+            })
+        })
+        .collect();
+
+    let max_bandwidth = axes.bandwidth_max(results.iter().zip(alignments).zip(&rates).flat_map(
+        |(((_, result), alignment), rates)| {
+            let start = result.start.as_secs_f64();
+            rates.iter().map(move |(time, rate)| {
+                (
+                    alignment.map(Duration::from_micros(*time).as_secs_f64() - start),
+                    *rate,
+                )
+            })
+        },
+    ));
+
+    let mut chart = new_chart(
+        axes,
+        None,
+        0.0..max_bandwidth,
+        if download {
+            "Download (Mbps)"
+        } else {
+            "Upload (Mbps)"
+        },
+        None,
+        phases,
+        area,
+    );
+
+    for (i, (((name, result), alignment), rates)) in
+        results.iter().zip(alignments).zip(rates).enumerate()
+    {
+        let color = compare_color(i);
+        let start = result.start.as_secs_f64();
+        chart
+            .draw_series(LineSeries::new(
+                rates.iter().map(|(time, rate)| {
+                    (
+                        alignment.map(Duration::from_micros(*time).as_secs_f64() - start),
+                        *rate,
+                    )
+                }),
+                color,
+            ))
+            .unwrap()
+            .label(*name)
+            .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 18, y + 3)], color.filled()));
+    }
+
+    filter_legend(&mut chart, filter);
+
+    legends(&mut chart);
+}
+
+fn compare_latency<DB: DrawingBackend>(
+    results: &[(&str, &TestResult)],
+    alignments: &[Alignment],
+    axes: &Axes,
+    phases: &[(Phase, f64, f64)],
+    area: &DrawingArea<DB, Shift>,
+) {
+    let latency = axes.latency_range(results.iter().zip(alignments).flat_map(
+        |((_, result), alignment)| {
+            let start = result.start.as_secs_f64();
+            result.pings.iter().filter_map(move |ping| {
+                Some((
+                    alignment.map(ping.sent.as_secs_f64() - start),
+                    ping.latency?.total?.as_secs_f64() * 1000.0,
+                ))
+            })
+        },
+    ));
+
+    if axes.log_latency {
+        let chart = new_chart(
+            axes,
+            None,
+            latency.log_scale(),
+            "Latency (ms)",
+            None,
+            phases,
+            area,
+        );
+        compare_latency_series(chart, results, alignments);
+    } else {
+        let chart = new_chart(axes, None, latency, "Latency (ms)", None, phases, area);
+        compare_latency_series(chart, results, alignments);
+    }
+}
+
+fn compare_latency_series<'a, DB: DrawingBackend + 'a, Y: Ranged<ValueType = f64>>(
+    mut chart: ChartContext<'a, DB, Cartesian2d<RangedCoordf64, Y>>,
+    results: &[(&str, &TestResult)],
+    alignments: &[Alignment],
+) {
+    // Keep latencies of 0 on a logarithmic axis
+    let min = chart.y_range().start;
+
+    for (i, ((name, result), alignment)) in results.iter().zip(alignments).enumerate() {
+        let color = compare_color(i);
+        let start = result.start.as_secs_f64();
+
+        let mut data = Vec::new();
+
+        let flush = |data: &mut Vec<_>| {
+            let data = mem::take(data);
+
+            if data.len() == 1 {
+                chart
+                    .plotting_area()
+                    .draw(&Circle::new(data[0], 1, color.filled()))
+                    .unwrap();
+            } else {
+                chart
+                    .plotting_area()
+                    .draw(&PathElement::new(data, color))
+                    .unwrap();
+            }
+        };
+
+        for ping in &result.pings {
+            match ping.latency.and_then(|latency| latency.total) {
+                Some(latency) => {
+                    let x = alignment.map(ping.sent.as_secs_f64() - start);
+                    data.push((x, (latency.as_secs_f64() * 1000.0).max(min)));
+                }
+                None => flush(&mut data),
+            }
+        }
+
+        flush(&mut data);
+
+        chart
+            .draw_series(LineSeries::new(std::iter::empty(), color))
+            .unwrap()
+            .label(*name)
+            .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 18, y + 3)], color.filled()));
+    }
+
+    legends(&mut chart);
+}
+
+fn compare_packet_loss<DB: DrawingBackend>(
+    results: &[(&str, &TestResult)],
+    alignments: &[Alignment],
+    axes: &Axes,
+    phases: &[(Phase, f64, f64)],
+    area: &DrawingArea<DB, Shift>,
+) {
+    let rows = results.len() as f64;
+
+    let chart = new_chart(
+        axes,
+        Some(30),
+        0.0..rows,
+        "Packet loss",
+        Some("Elapsed time (seconds)"),
+        phases,
+        area,
+    );
+
+    // Each result gets its own row, with the first one at the top.
+    for (i, ((_, result), alignment)) in results.iter().zip(alignments).enumerate() {
+        let color = compare_color(i);
+        let start = result.start.as_secs_f64();
+        let bottom = rows - i as f64 - 1.0;
+
+        for ping in &result.pings {
+            if ping.latency.and_then(|latency| latency.total).is_none() {
+                let x = alignment.map(ping.sent.as_secs_f64() - start);
+                chart
+                    .plotting_area()
+                    .draw(&PathElement::new(
+                        vec![(x, bottom + 0.1), (x, bottom + 0.9)],
+                        color,
+                    ))
+                    .unwrap();
+            }
+        }
+    }
+
+    chart
+        .plotting_area()
+        .draw(&PathElement::new(
+            vec![(axes.time.start, rows), (axes.time.end, rows)],
+            BLACK,
+        ))
+        .unwrap();
+}
+
+/// Plots latency distributions of multiple results with a row for each phase.
+/// This saves an image for each distribution enabled in `config`.
+pub fn save_comparison_distributions(
+    config: &PlotConfig,
+    results: &[(&str, &TestResult)],
+    name: &str,
+) -> Vec<String> {
+    let (name, format) = output_name(name, config.format);
+    Distribution::enabled(config)
+        .into_iter()
+        .map(|distribution| {
+            let file = unique(
+                &format!("{} {}", name, distribution.name()),
+                format.extension(),
+            );
+            comparison_distribution(file.as_ref(), config, format, distribution, results);
+            file
+        })
+        .collect()
+}
+
+fn comparison_distribution(
+    path: &Path,
+    config: &PlotConfig,
+    format: PlotFormat,
+    distribution: Distribution,
+    results: &[(&str, &TestResult)],
+) {
+    let kinds = latency_kinds(
+        results
+            .iter()
+            .all(|(_, result)| !result.raw_result.inferred.up_latency),
+    );
+
+    // Samples of each result, by latency kind and then by phase.
+    let samples: Vec<Vec<_>> = results
+        .iter()
+        .map(|(_, result)| {
+            kinds
+                .iter()
+                .map(|(_, get_latency)| phase_latencies(result, *get_latency))
+                .collect()
+        })
+        .collect();
+
+    let phases: Vec<_> = ["Idle", "Download", "Upload", "Both"]
+        .into_iter()
+        .filter(|phase| {
+            samples
+                .iter()
+                .flatten()
+                .flatten()
+                .any(|series| series.0 == *phase)
+        })
+        .collect();
+
+    let size = (
+        config.width.unwrap_or(1280) as u32,
+        config
+            .height
+            .unwrap_or(50 + 250 * phases.len().max(1) as u64) as u32,
+    );
+
+    save_figure(
+        path,
+        format,
+        size,
+        &DistributionComparison {
+            distribution,
+            results,
+            kinds,
+            samples,
+            phases,
+            max_latency: config
+                .max_latency
+                .map(|latency| latency.as_secs_f64() * 1000.0),
+        },
+    );
+}
+
+struct DistributionComparison<'a> {
+    distribution: Distribution,
+    results: &'a [(&'a str, &'a TestResult)],
+    kinds: Vec<LatencyKind>,
+    samples: Vec<Vec<Vec<(&'static str, RGBColor, Vec<f64>)>>>,
+    phases: Vec<&'static str>,
+    max_latency: Option<f64>,
+}
+
+impl Figure for DistributionComparison<'_> {
+    fn draw<DB: DrawingBackend>(&self, root: &DrawingArea<DB, Shift>) {
+        let DistributionComparison {
+            distribution,
+            results,
+            kinds,
+            samples,
+            phases,
+            max_latency,
+        } = self;
+
+        let (width, _) = root.dim_in_pixel();
+
+        root.fill(&WHITE).unwrap();
+
+        let style: TextStyle = (FontFamily::SansSerif, 26).into();
+
+        root.draw_text(
+            &format!("Latency {} comparison", distribution.name()),
+            &style.pos(Pos::new(HPos::Center, VPos::Center)),
+            (width as i32 / 2, 25),
+        )
+        .unwrap();
+
+        let areas = root
+            .split_vertically(50)
+            .1
+            .split_evenly((phases.len().max(1), kinds.len()));
+
+        for (row, phase) in phases.iter().enumerate() {
+            for (column, (kind, _)) in kinds.iter().enumerate() {
+                let series: Vec<_> = results
+                    .iter()
+                    .zip(samples.iter())
+                    .enumerate()
+                    .filter_map(|(i, ((name, _), samples))| {
+                        samples[column]
+                            .iter()
+                            .find(|series| series.0 == *phase)
+                            .map(|series| (*name, compare_color(i), series.2.clone()))
+                    })
+                    .collect();
+
+                distribution_chart(
+                    *distribution,
+                    &format!("{} phase, {} latency", phase, kind.to_lowercase()),
+                    &series,
+                    *max_latency,
+                    &areas[row * kinds.len() + column],
+                );
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Distribution {
+    Cdf,
+    Histogram,
+}
+
+impl Distribution {
+    fn enabled(config: &PlotConfig) -> Vec<Self> {
+        [
+            (config.cdf, Distribution::Cdf),
+            (config.histogram, Distribution::Histogram),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, distribution)| enabled.then_some(distribution))
+        .collect()
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Distribution::Cdf => "CDF",
+            Distribution::Histogram => "histogram",
+        }
+    }
+}
+
+pub(crate) type LatencyKind = (&'static str, fn(&RawLatency) -> Option<Duration>);
+
+/// The kinds of latency to plot. Files before version 1 only have total latency.
+pub(crate) fn latency_kinds(split: bool) -> Vec<LatencyKind> {
+    let mut kinds: Vec<LatencyKind> = vec![("Total", |latency| latency.total)];
+    if split {
+        kinds.push(("Up", |latency| Some(latency.up)));
+        kinds.push(("Down", |latency| latency.down()));
+    }
+    kinds
+}
+
+/// Sorted latency samples in milliseconds for the idle periods and each load of a test.
+fn phase_latencies(
+    result: &TestResult,
+    get_latency: fn(&RawLatency) -> Option<Duration>,
+) -> Vec<(&'static str, RGBColor, Vec<f64>)> {
+    let start = result.start.as_micros() as u64;
+    let end = (result.start + result.duration).as_micros() as u64;
+    let phases = result.phases();
+
+    let mut series = vec![("Idle", IDLE_COLOR, Vec::new())];
+    series.extend(
+        phases
+            .iter()
+            .map(|(phase, _, _)| (phase.name(), phase.color(), Vec::new())),
+    );
+
+    for ping in &result.pings {
+        let latency = match ping.latency.and_then(|latency| get_latency(&latency)) {
+            Some(latency) => latency.as_secs_f64() * 1000.0,
+            None => continue,
+        };
+        let sent = ping.sent.as_micros() as u64;
+        match phases
+            .iter()
+            .position(|&(_, start, end)| sent >= start && sent <= end)
+        {
+            Some(i) => series[i + 1].2.push(latency),
+            None if sent >= start && sent <= end => series[0].2.push(latency),
+            None => (),
+        }
+    }
+
+    series.retain(|series| !series.2.is_empty());
+    for series in &mut series {
+        series.2.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    }
+    series
+}
+
+fn latency_distributions<DB: DrawingBackend>(
+    config: &PlotConfig,
+    result: &TestResult,
+    area: &DrawingArea<DB, Shift>,
+) {
+    let distributions = Distribution::enabled(config);
+    let kinds = latency_kinds(!result.raw_result.inferred.up_latency);
+
+    let areas = area.split_evenly((distributions.len(), kinds.len()));
+
+    for (row, distribution) in distributions.iter().enumerate() {
+        for (column, (kind, get_latency)) in kinds.iter().enumerate() {
+            distribution_chart(
+                *distribution,
+                &format!("{} latency {}", kind, distribution.name()),
+                &phase_latencies(result, *get_latency),
+                config
+                    .max_latency
+                    .map(|latency| latency.as_secs_f64() * 1000.0),
+                &areas[row * kinds.len() + column],
+            );
+        }
+    }
+}
+
+fn distribution_chart<DB: DrawingBackend>(
+    distribution: Distribution,
+    caption: &str,
+    series: &[(&str, RGBColor, Vec<f64>)],
+    max_latency: Option<f64>,
+    area: &DrawingArea<DB, Shift>,
+) {
+    const BINS: usize = 50;
+
+    let font = (FontFamily::SansSerif, 16);
+
+    let max_latency = max_latency.unwrap_or_else(|| {
+        float_max(series.iter().filter_map(|series| series.2.last().copied())) * 1.05
+    });
+
+    let bin_width = max_latency / BINS as f64;
+
+    let points: Vec<Vec<(f64, f64)>> = series
+        .iter()
+        .map(|(_, _, samples)| {
+            let count = samples.len() as f64;
+            match distribution {
+                Distribution::Cdf => samples
+                    .first()
+                    .map(|first| (*first, 0.0))
+                    .into_iter()
+                    .chain(
+                        samples
+                            .iter()
+                            .enumerate()
+                            .map(|(i, latency)| (*latency, (i + 1) as f64 * 100.0 / count)),
+                    )
+                    .collect(),
+                Distribution::Histogram => {
+                    let mut bins = [0usize; BINS];
+                    for latency in samples {
+                        bins[((latency / bin_width) as usize).min(BINS - 1)] += 1;
+                    }
+                    bins.iter()
+                        .enumerate()
+                        .flat_map(|(i, samples)| {
+                            let share = *samples as f64 * 100.0 / count;
+                            [
+                                (i as f64 * bin_width, share),
+                                ((i + 1) as f64 * bin_width, share),
+                            ]
+                        })
+                        .collect()
+                }
+            }
+        })
+        .collect();
+
+    let max_share = match distribution {
+        Distribution::Cdf => 100.0,
+        Distribution::Histogram => {
+            float_max(points.iter().flat_map(|points| points.iter()).map(|e| e.1)) * 1.05
+        }
+    };
+
+    let mut chart = ChartBuilder::on(area)
+        .caption(caption, font)
+        .margin(6)
+        .set_label_area_size(LabelAreaPosition::Left, 60)
+        .set_label_area_size(LabelAreaPosition::Bottom, 40)
+        .build_cartesian_2d(0.0..max_latency, 0.0..max_share)
+        .unwrap();
+
+    chart
+        .plotting_area()
+        .fill(&RGBColor(248, 248, 248))
+        .unwrap();
+
+    chart
+        .configure_mesh()
+        .disable_x_mesh()
+        .disable_y_mesh()
+        .x_labels(8)
+        .y_labels(5)
+        .x_label_style(font)
+        .y_label_style(font)
+        .x_desc("Latency (ms)")
+        .y_desc(match distribution {
+            Distribution::Cdf => "Samples below (%)",
+            Distribution::Histogram => "Samples (%)",
+        })
+        .draw()
+        .unwrap();
+
+    for ((name, color, _), points) in series.iter().zip(points) {
+        let color = *color;
+        chart
+            .draw_series(LineSeries::new(points, color))
+            .unwrap()
+            .label(*name)
+            .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 18, y + 3)], color.filled()));
+    }
+
+    legends(&mut chart);
+}
+
+pub fn float_max(iter: impl Iterator<Item = f64>) -> f64 {
+    let mut max = iter.fold(f64::NAN, f64::max);
+
+    if max.is_nan() {
+        max = 100.0;
+    }
+
+    max
+}
+
+fn to_float(stream: &[(u64, u64)]) -> Vec<(u64, f64)> {
+    stream.iter().map(|(t, v)| (*t, *v as f64)).collect()
+}
+
+pub fn to_rates(stream: &[(u64, f64)]) -> Vec<(u64, f64)> {
+    let mut result: Vec<(u64, f64)> = (0..stream.len())
+        .map(|i| {
+            let rate = if i > 0 {
+                let bytes = stream[i].1 - stream[i - 1].1;
+                let duration = Duration::from_micros(stream[i].0 - stream[i - 1].0);
+                let mbits = (bytes as f64 * 8.0) / (1000.0 * 1000.0);
+                mbits / duration.as_secs_f64()
+            } else {
+                0.0
+            };
+            (stream[i].0, rate)
+        })
+        .collect();
+
+    // Insert dummy zero points for nicer graphs
+    if !result.is_empty() {
+        result.first().unwrap().0.checked_sub(1).map(|first| {
+            result.insert(0, (first, 0.0));
+        });
+        result.push((result.last().unwrap().0 + 1, 0.0));
+    }
+
+    result
+}
+
+fn sum_bytes(input: &[&[(u64, f64)]], interval: Duration) -> Vec<(u64, f64)> {
+    match stack_bytes(input, interval).pop() {
+        Some(data) => data,
+        None => vec![(0, 0.0)],
+    }
+}
+
+/// Returns the sum of the first `n` streams of `input` for every `n`, each spanning
+/// the time covered by the streams in it.
+///
+/// Each stream is resampled once onto a time grid shared by all of them, which the
+/// sums are then accumulated on. Streams count as having transferred nothing before
+/// they start and keep their last value after they end.
+fn stack_bytes(input: &[&[(u64, f64)]], interval: Duration) -> Vec<Vec<(u64, f64)>> {
+    let interval = interval.as_micros() as u64;
+
+    let bandwidth: Vec<_> = input
+        .iter()
+        .map(|stream| interpolate(stream, interval))
+        .collect();
+
+    // The span of each stream, with empty streams at 0.
+    let spans: Vec<_> = bandwidth
+        .iter()
+        .map(|stream| {
+            (
+                stream.first().map(|e| e.0).unwrap_or(0),
+                stream.last().map(|e| e.0).unwrap_or(0),
+            )
+        })
+        .collect();
+
+    let min = spans.iter().map(|span| span.0).min().unwrap_or(0);
+    let max = spans.iter().map(|span| span.1).max().unwrap_or(0);
+
+    let index = |point: u64| ((point - min) / interval) as usize;
+
+    let mut sum = vec![0.0; index(max) + 1];
+    let (mut start, mut end) = (u64::MAX, 0);
+
+    bandwidth
+        .iter()
+        .zip(spans)
+        .map(|(stream, span)| {
+            if let Some(last) = stream.last() {
+                let values = stream.iter().map(|e| e.1).chain(std::iter::repeat(last.1));
+                for (sum, value) in sum[index(span.0)..].iter_mut().zip(values) {
+                    *sum += value;
+                }
+            }
 
-    for point in (min..=max).step_by(interval as usize) {
-        let value = bandwidth
-            .iter()
-            .map(
-                |stream| match stream.binary_search_by_key(&point, |e| e.0) {
-                    Ok(i) => stream[i].1,
-                    Err(0) => 0.0,
-                    Err(i) if i == stream.len() => stream.last().unwrap().1,
-                    _ => panic!("unexpected index"),
-                },
-            )
-            .sum();
-        data.push((point, value));
-    }
+            start = start.min(span.0);
+            end = end.max(span.1);
 
-    data
+            (start..=end)
+                .step_by(interval as usize)
+                .zip(&sum[index(start)..])
+                .map(|(point, value)| (point, *value))
+                .collect()
+        })
+        .collect()
 }
 
 fn interpolate(input: &[(u64, f64)], interval: u64) -> Vec<(u64, f64)> {
@@ -317,14 +1790,114 @@ fn interpolate(input: &[(u64, f64)], interval: u64) -> Vec<(u64, f64)> {
     data
 }
 
-fn new_chart<'a, 'b, 'c>(
-    duration: f64,
+/// The label and notes of a result on a single line, shortened to fit in a plot header.
+fn description(metadata: &RawMetadata) -> Option<String> {
+    let notes = metadata
+        .notes
+        .as_ref()
+        .map(|notes| notes.lines().collect::<Vec<_>>().join("; "));
+    let description = match (&metadata.label, notes) {
+        (Some(label), Some(notes)) => format!("{}: {}", label, notes),
+        (label, notes) => label.clone().or(notes)?,
+    };
+    let max = 70;
+    Some(if description.chars().count() > max {
+        format!(
+            "{}...",
+            description.chars().take(max - 3).collect::<String>()
+        )
+    } else {
+        description
+    })
+}
+
+/// The time and value axes shared by the time series charts of a plot.
+struct Axes {
+    /// Elapsed seconds to show, with room for the legend.
+    time: Range<f64>,
+    log_latency: bool,
+    /// Fixed maximum of latency charts in milliseconds.
+    max_latency: Option<f64>,
+    /// Fixed maximum of bandwidth charts in Mbps.
+    max_bandwidth: Option<f64>,
+}
+
+impl Axes {
+    fn new(config: &PlotConfig, duration: f64) -> Self {
+        let start = config
+            .crop_start
+            .map(|start| start.as_secs_f64())
+            .unwrap_or(0.0);
+        let end = config
+            .crop_end
+            .map(|end| end.as_secs_f64())
+            .unwrap_or(duration)
+            .max(start + 0.1);
+
+        Axes {
+            // Scale to fit the legend
+            time: start..(end + (end - start) * 0.08),
+            log_latency: config.log_latency,
+            max_latency: config
+                .max_latency
+                .map(|latency| latency.as_secs_f64() * 1000.0),
+            max_bandwidth: config.max_bandwidth,
+        }
+    }
+
+    /// The maximum of a bandwidth chart showing `rates` at their elapsed time.
+    fn bandwidth_max(&self, rates: impl Iterator<Item = (f64, f64)>) -> f64 {
+        self.max_bandwidth.unwrap_or_else(|| {
+            float_max(
+                rates
+                    .filter(|(time, _)| self.time.contains(time))
+                    .map(|(_, rate)| rate),
+            ) * 1.05
+        })
+    }
+
+    /// The range of a latency chart showing `latencies` in milliseconds at their elapsed time.
+    fn latency_range(&self, latencies: impl Iterator<Item = (f64, f64)>) -> Range<f64> {
+        let latencies: Vec<_> = latencies
+            .filter(|(time, _)| self.time.contains(time))
+            .map(|(_, latency)| latency)
+            .collect();
+
+        let max = self
+            .max_latency
+            .unwrap_or_else(|| latencies.iter().copied().fold(1.0, f64::max) * 1.05);
+
+        if self.log_latency {
+            // Start at the power of ten below the lowest latency
+            let min = latencies
+                .iter()
+                .copied()
+                .filter(|latency| *latency > 0.0)
+                .fold(f64::NAN, f64::min);
+            let min = if min.is_nan() {
+                1.0
+            } else {
+                10f64.powf(min.log10().floor())
+            };
+            min.min(max / 10.0)..max
+        } else {
+            0.0..max
+        }
+    }
+}
+
+fn new_chart<'a, DB: DrawingBackend, Y: AsRangedCoord<Value = f64>>(
+    axes: &Axes,
     padding_bottom: Option<i32>,
-    max: f64,
-    label: &'b str,
-    x_label: Option<&'b str>,
-    area: &'a DrawingArea<BitMapBackend<'c>, Shift>,
-) -> ChartContext<'a, BitMapBackend<'c>, Cartesian2d<RangedCoordf64, RangedCoordf64>> {
+    y: Y,
+    label: &str,
+    x_label: Option<&str>,
+    phases: &[(Phase, f64, f64)],
+    area: &'a DrawingArea<DB, Shift>,
+) -> ChartContext<'a, DB, Cartesian2d<RangedCoordf64, Y::CoordDescType>>
+where
+    Y::CoordDescType: ValueFormatter<f64>,
+{
     let font = (FontFamily::SansSerif, 16);
 
     let mut chart = ChartBuilder::on(area)
@@ -332,19 +1905,47 @@ fn new_chart<'a, 'b, 'c>(
         .set_label_area_size(LabelAreaPosition::Left, 100)
         .set_label_area_size(LabelAreaPosition::Right, 100)
         .set_label_area_size(LabelAreaPosition::Bottom, padding_bottom.unwrap_or(20))
-        .build_cartesian_2d(0.0..duration, 0.0..max)
+        .build_cartesian_2d(axes.time.clone(), y)
         .unwrap();
 
+    let y = chart.y_range();
+
+    // The packet loss strip is too short for labels.
+    let compact = area.dim_in_pixel().1 < 120;
+
     chart
         .plotting_area()
         .fill(&RGBColor(248, 248, 248))
         .unwrap();
 
+    for &(phase, start, end) in phases {
+        chart
+            .plotting_area()
+            .draw(&Rectangle::new(
+                [(start, y.start), (end, y.end)],
+                phase.color().mix(0.1).filled(),
+            ))
+            .unwrap();
+
+        if !compact {
+            chart
+                .plotting_area()
+                .draw(&Text::new(
+                    phase.name(),
+                    (start, y.end),
+                    TextStyle::from((FontFamily::SansSerif, 14))
+                        .color(&phase.color())
+                        .pos(Pos::new(HPos::Left, VPos::Top)),
+                ))
+                .unwrap();
+        }
+    }
+
     let mut mesh = chart.configure_mesh();
 
     mesh.disable_x_mesh().disable_y_mesh();
 
-    if x_label.is_none() {
+    if !compact {
         mesh.x_labels(20).y_labels(10);
     } else {
         mesh.x_labels(0).y_labels(0);
@@ -361,9 +1962,7 @@ fn new_chart<'a, 'b, 'c>(
     chart
 }
 
-fn legends<'a, 'b: 'a>(
-    chart: &mut ChartContext<'a, BitMapBackend<'b>, Cartesian2d<RangedCoordf64, RangedCoordf64>>,
-) {
+fn legends<'a, DB: DrawingBackend + 'a, CT: CoordTranslate>(chart: &mut ChartContext<'a, DB, CT>) {
     let font = (FontFamily::SansSerif, 16);
 
     chart
@@ -375,28 +1974,107 @@ fn legends<'a, 'b: 'a>(
         .unwrap();
 }
 
-fn latency(
+fn latency<DB: DrawingBackend>(
     result: &TestResult,
     pings: &[RawPing],
     start: f64,
-    duration: f64,
-    area: &DrawingArea<BitMapBackend, Shift>,
-    packet_loss_area: &DrawingArea<BitMapBackend, Shift>,
+    axes: &Axes,
+    percentiles: bool,
+    area: &DrawingArea<DB, Shift>,
 ) {
-    let max_latency = pings
-        .iter()
-        .filter_map(|d| d.latency)
-        .filter_map(|latency| latency.total)
-        .max()
-        .unwrap_or(Duration::from_millis(100))
-        .as_secs_f64() as f64
-        * 1000.0;
+    let phases = phase_spans(result, start);
 
-    let max_latency = max_latency * 1.05;
+    let latency = axes.latency_range(pings.iter().filter_map(|ping| {
+        Some((
+            ping.sent.as_secs_f64() - start,
+            ping.latency?.total?.as_secs_f64() * 1000.0,
+        ))
+    }));
 
     // Latency
 
-    let mut chart = new_chart(duration, None, max_latency, "Latency (ms)", None, area);
+    if axes.log_latency {
+        let chart = new_chart(
+            axes,
+            None,
+            latency.log_scale(),
+            "Latency (ms)",
+            None,
+            &phases,
+            area,
+        );
+        latency_series(chart, pings, start, &phases, percentiles);
+    } else {
+        let chart = new_chart(axes, None, latency, "Latency (ms)", None, &phases, area);
+        latency_series(chart, pings, start, &phases, percentiles);
+    }
+}
+
+fn packet_loss<DB: DrawingBackend>(
+    result: &TestResult,
+    pings: &[RawPing],
+    start: f64,
+    axes: &Axes,
+    phases: &[(Phase, f64, f64)],
+    area: &DrawingArea<DB, Shift>,
+) {
+    let chart = new_chart(
+        axes,
+        Some(30),
+        0.0..1.0,
+        "Packet loss",
+        Some("Elapsed time (seconds)"),
+        phases,
+        area,
+    );
+
+    for ping in pings {
+        let x = ping.sent.as_secs_f64() - start;
+        if ping.latency.and_then(|latency| latency.total).is_none() {
+            let bold_size = 0.1111;
+            let (color, s, e, bold) = if !result.raw_result.inferred.loss_direction {
+                if ping.latency.is_none() {
+                    (UP_COLOR, 0.0, 0.5, Some(0.0 + bold_size))
+                } else {
+                    (DOWN_COLOR, 1.0, 0.5, Some(1.0 - bold_size))
+                }
+            } else {
+                (RGBColor(193, 85, 85), 0.0, 1.0, None)
+            };
+            chart
+                .plotting_area()
+                .draw(&PathElement::new(vec![(x, s), (x, e)], color))
+                .unwrap();
+            bold.map(|bold| {
+                chart
+                    .plotting_area()
+                    .draw(&PathElement::new(
+                        vec![(x, s), (x, bold)],
+                        color.stroke_width(2),
+                    ))
+                    .unwrap();
+            });
+        }
+    }
+
+    chart
+        .plotting_area()
+        .draw(&PathElement::new(
+            vec![(axes.time.start, 1.0), (axes.time.end, 1.0)],
+            BLACK,
+        ))
+        .unwrap();
+}
+
+fn latency_series<'a, DB: DrawingBackend + 'a, Y: Ranged<ValueType = f64>>(
+    mut chart: ChartContext<'a, DB, Cartesian2d<RangedCoordf64, Y>>,
+    pings: &[RawPing],
+    start: f64,
+    phases: &[(Phase, f64, f64)],
+    percentiles: bool,
+) {
+    // Keep latencies of 0 on a logarithmic axis
+    let min = chart.y_range().start;
 
     let mut draw_latency =
         |color: RGBColor, name: &str, get_latency: fn(&RawLatency) -> Option<Duration>| {
@@ -423,7 +2101,7 @@ fn latency(
                     Some(latency) => match get_latency(latency) {
                         Some(latency) => {
                             let x = ping.sent.as_secs_f64() - start;
-                            let y = latency.as_secs_f64() * 1000.0;
+                            let y = (latency.as_secs_f64() * 1000.0).max(min);
 
                             data.push((x, y));
                         }
@@ -454,60 +2132,128 @@ fn latency(
 
     draw_latency(RGBColor(50, 50, 50), "Total", |latency| latency.total);
 
-    legends(&mut chart);
+    if percentiles {
+        let style = TextStyle::from((FontFamily::SansSerif, 14))
+            .color(&BLACK)
+            .pos(Pos::new(HPos::Left, VPos::Bottom));
 
-    // Packet loss
+        for &(phase, phase_start, phase_end) in phases {
+            let mut samples: Vec<_> = pings
+                .iter()
+                .filter(|ping| {
+                    let sent = ping.sent.as_secs_f64() - start;
+                    sent >= phase_start && sent <= phase_end
+                })
+                .filter_map(|ping| ping.latency.and_then(|latency| latency.total))
+                .collect();
 
-    let chart = new_chart(
-        duration,
-        Some(30),
-        1.0,
-        "Packet loss",
-        Some("Elapsed time (seconds)"),
-        packet_loss_area,
-    );
+            if samples.is_empty() {
+                continue;
+            }
+            samples.sort_unstable();
 
-    for ping in pings {
-        let x = ping.sent.as_secs_f64() - start;
-        if ping.latency.and_then(|latency| latency.total).is_none() {
-            let bold_size = 0.1111;
-            let (color, s, e, bold) = if result.raw_result.version >= 2 {
-                if ping.latency.is_none() {
-                    (UP_COLOR, 0.0, 0.5, Some(0.0 + bold_size))
-                } else {
-                    (DOWN_COLOR, 1.0, 0.5, Some(1.0 - bold_size))
-                }
-            } else {
-                (RGBColor(193, 85, 85), 0.0, 1.0, None)
-            };
-            chart
-                .plotting_area()
-                .draw(&PathElement::new(vec![(x, s), (x, e)], color))
-                .unwrap();
-            bold.map(|bold| {
+            let median = samples[samples.len() / 2];
+            let p99 = samples[(samples.len() * 99 / 100).min(samples.len() - 1)];
+
+            for (name, latency, width) in [("Median", median, 2), ("p99", p99, 1)] {
+                let y = (latency.as_secs_f64() * 1000.0).max(min);
                 chart
                     .plotting_area()
                     .draw(&PathElement::new(
-                        vec![(x, s), (x, bold)],
-                        color.stroke_width(2),
+                        vec![(phase_start, y), (phase_end, y)],
+                        phase.color().stroke_width(width),
                     ))
                     .unwrap();
-            });
+                chart
+                    .plotting_area()
+                    .draw(&Text::new(
+                        format!("{} {:.1} ms", name, y),
+                        (phase_start, y),
+                        style.clone(),
+                    ))
+                    .unwrap();
+            }
         }
     }
 
+    legends(&mut chart);
+}
+
+fn loss_rate<DB: DrawingBackend>(
+    result: &TestResult,
+    start: f64,
+    axes: &Axes,
+    phases: &[(Phase, f64, f64)],
+    area: &DrawingArea<DB, Shift>,
+) {
+    let rates = result.loss_rates(loss_window(result));
+
+    let max = float_max(
+        rates
+            .iter()
+            .filter(|rate| {
+                axes.time
+                    .contains(&(Duration::from_micros(rate.time).as_secs_f64() - start))
+            })
+            .map(|rate| rate.total),
+    )
+    .max(1.0)
+        * 1.05;
+
+    let mut chart = new_chart(
+        axes,
+        Some(50),
+        0.0..max,
+        "Packet loss (%)",
+        Some("Elapsed time (seconds)"),
+        phases,
+        area,
+    );
+
+    let mut draw_rate = |name: &str, color: RGBColor, get_rate: fn(&LossRate) -> Option<f64>| {
+        chart
+            .draw_series(LineSeries::new(
+                rates.iter().filter_map(|rate| {
+                    Some((
+                        Duration::from_micros(rate.time).as_secs_f64() - start,
+                        get_rate(rate)?,
+                    ))
+                }),
+                color,
+            ))
+            .unwrap()
+            .label(name)
+            .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 18, y + 3)], color.filled()));
+    };
+
+    if !result.raw_result.inferred.loss_direction {
+        draw_rate("Up", UP_COLOR, |rate| rate.up);
+        draw_rate("Down", DOWN_COLOR, |rate| rate.down);
+    } else {
+        draw_rate("Lost", RGBColor(193, 85, 85), |rate| Some(rate.total));
+    }
+
+    let bursts = result.loss_bursts();
     chart
-        .plotting_area()
-        .draw(&PathElement::new(vec![(0.0, 1.0), (duration, 1.0)], BLACK))
-        .unwrap();
+        .draw_series(LineSeries::new(std::iter::empty(), BLACK))
+        .unwrap()
+        .label(format!(
+            "{} bursts, longest {} {}",
+            bursts.count,
+            bursts.max,
+            if bursts.max == 1 { "ping" } else { "pings" }
+        ));
+
+    legends(&mut chart);
 }
 
-fn plot_split_bandwidth(
+fn plot_split_bandwidth<DB: DrawingBackend>(
     download: bool,
     result: &TestResult,
+    filter: &RateFilter,
     start: f64,
-    duration: f64,
-    area: &DrawingArea<BitMapBackend, Shift>,
+    axes: &Axes,
+    area: &DrawingArea<DB, Shift>,
 ) {
     let groups: Vec<_> = result
         .stream_groups
@@ -520,31 +2266,30 @@ fn plot_split_bandwidth(
                 .streams
                 .iter()
                 .map(|stream| TestStream {
-                    data: to_rates(&stream.data),
+                    data: filter.rates(&stream.data),
                 })
                 .collect(),
         })
         .collect();
 
-    let max_bandwidth = float_max(
+    let max_bandwidth = axes.bandwidth_max(
         groups
             .iter()
             .flat_map(|group| group.streams.last().unwrap().data.iter())
-            .map(|e| e.1),
+            .map(|(time, rate)| (Duration::from_micros(*time).as_secs_f64() - start, *rate)),
     );
 
-    let max_bandwidth = max_bandwidth * 1.05;
-
     let mut chart = new_chart(
-        duration,
+        axes,
         None,
-        max_bandwidth,
+        0.0..max_bandwidth,
         if download {
             "Download (Mbps)"
         } else {
             "Upload (Mbps)"
         },
         None,
+        &phase_spans(result, start),
         area,
     );
 
@@ -582,24 +2327,35 @@ fn plot_split_bandwidth(
                 .unwrap();
         }
     }
+
+    if filter.describe().is_some() {
+        filter_legend(&mut chart, filter);
+        legends(&mut chart);
+    }
 }
 
-fn plot_bandwidth(
+fn plot_bandwidth<DB: DrawingBackend>(
     bandwidth: &[(&str, RGBColor, Vec<(u64, f64)>, Vec<&[(u64, f64)]>)],
+    filter: &RateFilter,
     start: f64,
-    duration: f64,
-    area: &DrawingArea<BitMapBackend, Shift>,
+    axes: &Axes,
+    phases: &[(Phase, f64, f64)],
+    area: &DrawingArea<DB, Shift>,
 ) {
-    let max_bandwidth = float_max(bandwidth.iter().flat_map(|list| list.2.iter()).map(|e| e.1));
-
-    let max_bandwidth = max_bandwidth * 1.05;
+    let max_bandwidth = axes.bandwidth_max(
+        bandwidth
+            .iter()
+            .flat_map(|list| list.2.iter())
+            .map(|(time, rate)| (Duration::from_micros(*time).as_secs_f64() - start, *rate)),
+    );
 
     let mut chart = new_chart(
-        duration,
+        axes,
         None,
-        max_bandwidth,
+        0.0..max_bandwidth,
         "Bandwidth (Mbps)",
         None,
+        phases,
         area,
     );
 
@@ -616,20 +2372,72 @@ fn plot_bandwidth(
             .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 18, y + 3)], color.filled()));
     }
 
+    filter_legend(&mut chart, filter);
+
+    legends(&mut chart);
+}
+
+fn fairness<DB: DrawingBackend>(
+    result: &TestResult,
+    start: f64,
+    axes: &Axes,
+    phases: &[(Phase, f64, f64)],
+    area: &DrawingArea<DB, Shift>,
+) {
+    let mut chart = new_chart(
+        axes,
+        None,
+        0.0..1.05,
+        "Fairness (Jain's index)",
+        None,
+        phases,
+        area,
+    );
+
+    let mut labeled = [false; 2];
+
+    for group in &result.stream_groups {
+        let (name, color) = if group.download {
+            ("Download", DOWN_COLOR)
+        } else {
+            ("Upload", UP_COLOR)
+        };
+
+        let series = chart
+            .draw_series(LineSeries::new(
+                result.fairness(group).into_iter().map(|(time, index)| {
+                    (Duration::from_micros(time).as_secs_f64() - start, index)
+                }),
+                color,
+            ))
+            .unwrap();
+
+        if !mem::replace(&mut labeled[group.download as usize], true) {
+            series.label(name).legend(move |(x, y)| {
+                Rectangle::new([(x, y - 5), (x + 18, y + 3)], color.filled())
+            });
+        }
+    }
+
     legends(&mut chart);
 }
 
-pub(crate) fn bytes_transferred(
+fn bytes_transferred<DB: DrawingBackend>(
     bandwidth: &[(&str, RGBColor, Vec<(u64, f64)>, Vec<&[(u64, f64)]>)],
     start: f64,
-    duration: f64,
-    area: &DrawingArea<BitMapBackend, Shift>,
+    axes: &Axes,
+    phases: &[(Phase, f64, f64)],
+    area: &DrawingArea<DB, Shift>,
 ) {
     let max_bytes = float_max(
         bandwidth
             .iter()
             .flat_map(|list| list.3.iter())
             .flat_map(|list| list.iter())
+            .filter(|e| {
+                axes.time
+                    .contains(&(Duration::from_micros(e.0).as_secs_f64() - start))
+            })
             .map(|e| e.1),
     );
 
@@ -638,11 +2446,12 @@ pub(crate) fn bytes_transferred(
     let max_bytes = max_bytes * 1.05;
 
     let mut chart = new_chart(
-        duration,
+        axes,
         Some(50),
-        max_bytes,
+        0.0..max_bytes,
         "Data transferred (GiB)",
         None,
+        phases,
         area,
     );
 
@@ -671,8 +2480,8 @@ pub(crate) fn bytes_transferred(
     legends(&mut chart);
 }
 
-pub(crate) fn graph(
-    path: &Path,
+pub(crate) fn graph<DB: DrawingBackend>(
+    root: &DrawingArea<DB, Shift>,
     config: &PlotConfig,
     result: &TestResult,
     pings: &[RawPing],
@@ -680,10 +2489,9 @@ pub(crate) fn graph(
     start: f64,
     duration: f64,
 ) {
-    let width = config.width.unwrap_or(1280) as u32;
+    let (width, height) = root.dim_in_pixel();
 
-    let root =
-        BitMapBackend::new(path, (width, config.height.unwrap_or(720) as u32)).into_drawing_area();
+    let distributions = Distribution::enabled(config).len() as u64;
 
     root.fill(&WHITE).unwrap();
 
@@ -691,20 +2499,48 @@ pub(crate) fn graph(
 
     let small_style: TextStyle = (FontFamily::SansSerif, 14).into();
 
-    let lines = 2;
+    let metadata = &result.raw_result.metadata;
+
+    let description = description(metadata);
+
+    // The description gets its own line below the others.
+    let description_lines = description.is_some() as i32;
+
+    let lines = if result.raw_result.inferred.metadata {
+        2
+    } else {
+        3
+    } + description_lines;
 
     let text_height = (root.estimate_text_size("Wg", &small_style).unwrap().1 as i32 + 5) * lines;
 
-    let center = text_height / 2 + 10;
+    let center = text_height * (lines - description_lines) / lines / 2 + 10;
+
+    // Latency monitor sessions have no loads.
+    let title = if result.raw_result.stream_groups.is_empty() {
+        "Latency"
+    } else {
+        "Latency under load"
+    };
 
     root.draw_text(
-        "Latency under load",
+        title,
         &style.pos(Pos::new(HPos::Center, VPos::Center)),
         (width as i32 / 2, center),
     )
     .unwrap();
 
-    if result.raw_result.version >= 1 {
+    if let Some(description) = description {
+        let label_style: TextStyle = (FontFamily::SansSerif, 16).into();
+        root.draw_text(
+            &description,
+            &label_style.pos(Pos::new(HPos::Center, VPos::Top)),
+            (width as i32 / 2, 10 + text_height * (lines - 1) / lines),
+        )
+        .unwrap();
+    }
+
+    if !result.raw_result.inferred.connection {
         let top_margin = 10;
         root.draw_text(
             &format!(
@@ -747,17 +2583,93 @@ pub(crate) fn graph(
         )
         .unwrap();
 
-        root.draw_text(
-            &result.raw_result.generated_by,
-            &small_style.pos(Pos::new(HPos::Right, VPos::Center)),
-            (width as i32 - 100, center),
-        )
-        .unwrap();
+        if !result.raw_result.inferred.timestamps {
+            root.draw_text(
+                &result.raw_result.generated_by,
+                &small_style.pos(Pos::new(HPos::Right, VPos::Top)),
+                (width as i32 - 100, top_margin),
+            )
+            .unwrap();
+
+            root.draw_text(
+                &format!(
+                    "Timestamps: client {}, server {}{}",
+                    result.raw_result.client_timestamps,
+                    result.raw_result.server_timestamps,
+                    result
+                        .raw_result
+                        .config
+                        .tls
+                        .as_ref()
+                        .map(|tls| format!(", TLS overhead {:.2}%", tls.overhead() * 100.0))
+                        .unwrap_or_default(),
+                ),
+                &small_style.pos(Pos::new(HPos::Right, VPos::Top)),
+                (width as i32 - 100, top_margin + text_height / lines),
+            )
+            .unwrap();
+        } else {
+            root.draw_text(
+                &result.raw_result.generated_by,
+                &small_style.pos(Pos::new(HPos::Right, VPos::Center)),
+                (width as i32 - 100, center),
+            )
+            .unwrap();
+        }
+
+        if !result.raw_result.inferred.metadata {
+            let server = match (&metadata.server, &metadata.server_address) {
+                (Some(server), Some(address)) if server != address => {
+                    format!("{} ({})", server, address)
+                }
+                (server, address) => server.clone().or(address.clone()).unwrap_or_default(),
+            };
+            let server = [
+                Some(server).filter(|server| !server.is_empty()),
+                metadata
+                    .server_version
+                    .as_ref()
+                    .map(|version| format!("Crusader {}", version)),
+            ]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(", ");
+
+            root.draw_text(
+                &format!("Server: {}", server),
+                &small_style.pos(Pos::new(HPos::Left, VPos::Top)),
+                (100, top_margin + text_height * 2 / lines),
+            )
+            .unwrap();
+
+            let client = [
+                metadata.client_hostname.clone(),
+                metadata.client_os.clone(),
+                metadata
+                    .start_time
+                    .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string()),
+            ]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(", ");
+
+            root.draw_text(
+                &format!("Client: {}", client),
+                &small_style.pos(Pos::new(HPos::Right, VPos::Top)),
+                (width as i32 - 100, top_margin + text_height * 2 / lines),
+            )
+            .unwrap();
+        }
     }
 
     let root = root.split_vertically(text_height + 10).1;
 
-    let (root, loss) = root.split_vertically(root.relative_to_height(1.0) - 70.0);
+    let (root, distribution) = root.split_vertically(
+        root.relative_to_height(1.0)
+            - height as f64 * (250 * distributions) as f64 / (720 + 250 * distributions) as f64,
+    );
 
     let mut charts = 1;
 
@@ -772,44 +2684,99 @@ pub(crate) fn graph(
         } else {
             charts += 1
         }
+        if config.fairness {
+            charts += 1
+        }
         if config.transferred {
             charts += 1
         }
     }
 
+    let loss_rate_chart = plot_loss_rate(config, result);
+
+    let (root, loss) = if loss_rate_chart {
+        // The loss rate gets a full chart, with room for the time axis description.
+        let height = root.relative_to_height(1.0) - 30.0;
+        root.split_vertically(height * charts as f64 / (charts + 1) as f64)
+    } else {
+        root.split_vertically(root.relative_to_height(1.0) - 70.0)
+    };
+
     let areas = root.split_evenly((charts, 1));
 
-    // Scale to fit the legend
-    let duration = duration * 1.08;
+    let axes = Axes::new(config, duration);
+
+    let phases = phase_spans(result, start);
 
     let mut chart_index = 0;
 
     if result.raw_result.streams() > 0 {
         if config.split_bandwidth {
             if result.raw_result.download() || result.raw_result.both() {
-                plot_split_bandwidth(true, result, start, duration, &areas[chart_index]);
+                plot_split_bandwidth(
+                    true,
+                    result,
+                    &config.rates,
+                    start,
+                    &axes,
+                    &areas[chart_index],
+                );
                 chart_index += 1;
             }
             if result.raw_result.upload() || result.raw_result.both() {
-                plot_split_bandwidth(false, result, start, duration, &areas[chart_index]);
+                plot_split_bandwidth(
+                    false,
+                    result,
+                    &config.rates,
+                    start,
+                    &axes,
+                    &areas[chart_index],
+                );
                 chart_index += 1;
             }
         } else {
-            plot_bandwidth(bandwidth, start, duration, &areas[chart_index]);
+            plot_bandwidth(
+                bandwidth,
+                &config.rates,
+                start,
+                &axes,
+                &phases,
+                &areas[chart_index],
+            );
+            chart_index += 1;
+        }
+
+        if config.fairness {
+            fairness(result, start, &axes, &phases, &areas[chart_index]);
             chart_index += 1;
         }
     }
 
-    latency(result, pings, start, duration, &areas[chart_index], &loss);
+    latency(
+        result,
+        pings,
+        start,
+        &axes,
+        config.percentiles,
+        &areas[chart_index],
+    );
     chart_index += 1;
 
+    if loss_rate_chart {
+        loss_rate(result, start, &axes, &phases, &loss);
+    } else {
+        packet_loss(result, pings, start, &axes, &phases, &loss);
+    }
+
     if result.raw_result.streams() > 0 && config.transferred {
-        bytes_transferred(bandwidth, start, duration, &areas[chart_index]);
+        bytes_transferred(bandwidth, start, &axes, &phases, &areas[chart_index]);
         #[allow(unused_assignments)]
         {
             chart_index += 1;
         }
     }
 
-    root.present().expect("Unable to write plot to file");
+    if distributions > 0 {
+        latency_distributions(config, result, &distribution);
+    }
 }
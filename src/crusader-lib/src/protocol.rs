@@ -1,14 +1,24 @@
 use std::error::Error;
+use std::net::IpAddr;
 
 use bytes::{Bytes, BytesMut};
 use futures::{Sink, SinkExt, Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio_util::codec::{length_delimited, LengthDelimitedCodec};
 
+use crate::timestamp::TimestampSource;
+
 pub const PORT: u16 = 35481;
 
 pub const MAGIC: u64 = 0x5372ab82ae7c59cb;
-pub const VERSION: u64 = 3;
+pub const VERSION: u64 = 6;
+
+/// Starts UDP packets used for server discovery. It can't be mistaken for a
+/// `Ping` as those start with a small session id.
+pub const DISCOVER_MAGIC: u64 = 0xc5d1a39f0e7b4826;
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct Hello {
@@ -25,12 +35,94 @@ impl Hello {
     }
 }
 
+/// Broadcast by clients looking for servers on the local network.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Discover {
+    pub magic: u64,
+}
+
+impl Discover {
+    pub fn new() -> Self {
+        Discover {
+            magic: DISCOVER_MAGIC,
+        }
+    }
+}
+
+/// A server's reply to `Discover`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Announcement {
+    pub magic: u64,
+    pub hostname: String,
+    pub software_version: String,
+    pub protocol_version: u64,
+    pub addresses: Vec<IpAddr>,
+    pub tls: bool,
+    pub psk: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub struct TestStream {
     pub group: u32,
     pub id: u32,
 }
 
+/// A random secret handed to a client when its session is created. Loaders
+/// must present it to associate with the session and pings are authenticated
+/// with it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionToken(pub [u8; 32]);
+
+impl SessionToken {
+    pub fn new() -> Self {
+        SessionToken(rand::thread_rng().gen())
+    }
+
+    pub fn matches(&self, other: &SessionToken) -> bool {
+        // Compare in constant time.
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::new_from_slice(&self.0).unwrap()
+    }
+}
+
+/// A key shared between a server and the clients it is willing to serve.
+#[derive(Clone, Copy)]
+pub struct PreSharedKey([u8; 32]);
+
+impl PreSharedKey {
+    pub fn new(secret: &str) -> Self {
+        PreSharedKey(Sha256::digest(secret.as_bytes()).into())
+    }
+
+    fn mac(&self, challenge: &[u8; 32]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).unwrap();
+        mac.update(b"crusader-authenticate");
+        mac.update(challenge);
+        mac
+    }
+
+    pub fn respond(&self, challenge: &[u8; 32]) -> [u8; 32] {
+        self.mac(challenge).finalize().into_bytes().into()
+    }
+
+    pub fn verify(&self, challenge: &[u8; 32], response: &[u8; 32]) -> bool {
+        self.mac(challenge).verify_slice(response).is_ok()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Session {
+    pub id: u64,
+    pub token: SessionToken,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LatencyMeasure {
     pub time: u64,
@@ -39,7 +131,13 @@ pub struct LatencyMeasure {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ServerMessage {
-    NewClient(Option<u64>),
+    Challenge([u8; 32]),
+    Unauthorized,
+    NewClient {
+        session: Option<Session>,
+        timestamps: TimestampSource,
+        software_version: String,
+    },
     LatencyMeasures(Vec<LatencyMeasure>),
     Measure {
         stream: TestStream,
@@ -67,7 +165,8 @@ pub enum ServerMessage {
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ClientMessage {
     NewClient,
-    Associate(u64),
+    Authenticate([u8; 32]),
+    Associate(Session),
     Done,
     ScheduleLoads {
         groups: Vec<u32>,
@@ -96,6 +195,29 @@ pub enum ClientMessage {
 pub struct Ping {
     pub id: u64,
     pub index: u64,
+    /// Truncated HMAC of `id` and `index` keyed by the session token.
+    pub mac: [u8; 16],
+}
+
+impl Ping {
+    pub fn new(id: u64, index: u64, token: &SessionToken) -> Self {
+        let mut mac = [0; 16];
+        mac.copy_from_slice(&Self::mac(id, index, token).finalize().into_bytes()[..16]);
+        Ping { id, index, mac }
+    }
+
+    pub fn is_valid(&self, token: &SessionToken) -> bool {
+        Self::mac(self.id, self.index, token)
+            .verify_truncated_left(&self.mac)
+            .is_ok()
+    }
+
+    fn mac(id: u64, index: u64, token: &SessionToken) -> Hmac<Sha256> {
+        let mut mac = token.mac();
+        mac.update(&id.to_le_bytes());
+        mac.update(&index.to_le_bytes());
+        mac
+    }
 }
 
 pub fn codec() -> LengthDelimitedCodec {
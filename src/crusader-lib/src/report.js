@@ -0,0 +1,444 @@
+// Interactive charts for reports. This is embedded in the HTML so reports work offline.
+"use strict";
+
+(function () {
+  const results = JSON.parse(document.getElementById("data").textContent);
+
+  const MARGIN = { left: 56, right: 12, top: 8, bottom: 22 };
+  const FONT = "12px sans-serif";
+
+  // The visible time range, shared by every chart.
+  const full = [0, Math.max(...results.map((result) => result.duration)) * 1.02];
+  let view = full.slice();
+  let hover = null;
+  const hidden = new Set();
+  const charts = [];
+
+  function niceStep(range, count) {
+    const rough = range / count;
+    const power = Math.pow(10, Math.floor(Math.log10(rough)));
+    for (const factor of [1, 2, 2.5, 5, 10]) {
+      if (power * factor >= rough) {
+        return power * factor;
+      }
+    }
+    return power * 10;
+  }
+
+  function ticks(min, max, count) {
+    if (!(max > min)) {
+      return [];
+    }
+    const step = niceStep(max - min, count);
+    const result = [];
+    for (let value = Math.ceil(min / step) * step; value <= max + step * 1e-9; value += step) {
+      result.push(value);
+    }
+    return result;
+  }
+
+  function format(value, step) {
+    const digits = Math.max(0, Math.min(3, -Math.floor(Math.log10(step))));
+    return value.toFixed(digits);
+  }
+
+  // Index of the first point at or after `time`.
+  function search(points, time) {
+    let low = 0;
+    let high = points.length;
+    while (low < high) {
+      const middle = (low + high) >> 1;
+      if (points[middle][0] < time) {
+        low = middle + 1;
+      } else {
+        high = middle;
+      }
+    }
+    return low;
+  }
+
+  function visibleMax(series) {
+    let max = 0;
+    for (const line of series) {
+      if (hidden.has(line.name)) {
+        continue;
+      }
+      const end = Math.min(line.points.length, search(line.points, view[1]) + 1);
+      for (let i = Math.max(0, search(line.points, view[0]) - 1); i < end; i++) {
+        const value = line.points[i][1];
+        if (value !== null && value > max) {
+          max = value;
+        }
+      }
+    }
+    return max;
+  }
+
+  class Chart {
+    constructor(parent, result, kind) {
+      this.result = result;
+      this.kind = kind;
+      this.series = kind === "loss" ? [] : result[kind];
+      this.unit = kind === "bandwidth" ? "Mbps" : "ms";
+
+      this.element = document.createElement("div");
+      this.element.className = "chart";
+      const title = document.createElement("div");
+      title.className = "title";
+      title.textContent =
+        result.name +
+        " - " +
+        { bandwidth: "Bandwidth (Mbps)", latency: "Latency (ms)", loss: "Packet loss" }[kind];
+      this.element.appendChild(title);
+
+      this.canvas = document.createElement("canvas");
+      this.height = kind === "loss" ? 70 : 240;
+      this.canvas.style.height = this.height + "px";
+      this.element.appendChild(this.canvas);
+
+      if (this.series.length > 0) {
+        const legend = document.createElement("div");
+        legend.className = "legend";
+        for (const line of this.series) {
+          const entry = document.createElement("span");
+          entry.dataset.name = line.name;
+          entry.innerHTML = '<i style="background:' + line.color + '"></i>';
+          entry.appendChild(document.createTextNode(line.name));
+          entry.addEventListener("click", () => toggle(line.name));
+          legend.appendChild(entry);
+        }
+        this.element.appendChild(legend);
+      }
+
+      this.tooltip = document.createElement("div");
+      this.tooltip.className = "tooltip";
+      this.element.appendChild(this.tooltip);
+
+      parent.appendChild(this.element);
+      this.listen();
+    }
+
+    get plot() {
+      return {
+        left: MARGIN.left,
+        top: MARGIN.top,
+        width: this.width - MARGIN.left - MARGIN.right,
+        height: this.height - MARGIN.top - MARGIN.bottom,
+      };
+    }
+
+    x(time) {
+      const plot = this.plot;
+      return plot.left + ((time - view[0]) / (view[1] - view[0])) * plot.width;
+    }
+
+    time(x) {
+      const plot = this.plot;
+      return view[0] + ((x - plot.left) / plot.width) * (view[1] - view[0]);
+    }
+
+    y(value) {
+      const plot = this.plot;
+      return plot.top + plot.height - (value / this.max) * plot.height;
+    }
+
+    listen() {
+      let drag = null;
+
+      const position = (event) => event.clientX - this.canvas.getBoundingClientRect().left;
+
+      this.canvas.addEventListener("mousedown", (event) => {
+        drag = { start: position(event), view: view.slice(), pan: event.shiftKey };
+        event.preventDefault();
+      });
+
+      window.addEventListener("mousemove", (event) => {
+        if (!drag) {
+          return;
+        }
+        if (drag.pan) {
+          const shift =
+            ((position(event) - drag.start) / this.plot.width) * (drag.view[1] - drag.view[0]);
+          view = [drag.view[0] - shift, drag.view[1] - shift];
+          drawAll();
+        } else {
+          this.selection = [drag.start, position(event)];
+          this.draw();
+        }
+      });
+
+      window.addEventListener("mouseup", (event) => {
+        if (!drag) {
+          return;
+        }
+        const end = position(event);
+        if (!drag.pan && Math.abs(end - drag.start) > 4) {
+          const times = [this.time(drag.start), this.time(end)].sort((a, b) => a - b);
+          view = times;
+        }
+        drag = null;
+        this.selection = null;
+        drawAll();
+      });
+
+      this.canvas.addEventListener("mousemove", (event) => {
+        hover = this.time(position(event));
+        drawAll();
+        this.showTooltip(position(event), event.clientY - this.canvas.getBoundingClientRect().top);
+      });
+
+      this.canvas.addEventListener("mouseleave", () => {
+        hover = null;
+        this.tooltip.style.display = "none";
+        drawAll();
+      });
+
+      this.canvas.addEventListener("dblclick", () => {
+        view = full.slice();
+        drawAll();
+      });
+
+      this.canvas.addEventListener(
+        "wheel",
+        (event) => {
+          if (!event.ctrlKey) {
+            return;
+          }
+          event.preventDefault();
+          const center = this.time(position(event));
+          const factor = event.deltaY > 0 ? 1.25 : 0.8;
+          view = [center - (center - view[0]) * factor, center + (view[1] - center) * factor];
+          drawAll();
+        },
+        { passive: false }
+      );
+    }
+
+    showTooltip(x, y) {
+      if (this.series.length === 0 || hover === null) {
+        this.tooltip.style.display = "none";
+        return;
+      }
+      const lines = [hover.toFixed(2) + " s"];
+      for (const line of this.series) {
+        if (hidden.has(line.name) || line.points.length === 0) {
+          continue;
+        }
+        const i = Math.min(search(line.points, hover), line.points.length - 1);
+        const previous = Math.max(i - 1, 0);
+        const nearest =
+          Math.abs(line.points[previous][0] - hover) < Math.abs(line.points[i][0] - hover)
+            ? previous
+            : i;
+        const value = line.points[nearest][1];
+        lines.push(line.name + ": " + (value === null ? "lost" : value.toFixed(2) + " " + this.unit));
+      }
+      this.tooltip.textContent = "";
+      for (const line of lines) {
+        this.tooltip.appendChild(document.createTextNode(line));
+        this.tooltip.appendChild(document.createElement("br"));
+      }
+      this.tooltip.style.display = "block";
+      const flip = x > this.width / 2;
+      this.tooltip.style.left = flip ? "" : x + 12 + "px";
+      this.tooltip.style.right = flip ? this.width - x + 12 + "px" : "";
+      this.tooltip.style.top = y + 20 + "px";
+    }
+
+    draw() {
+      const ratio = window.devicePixelRatio || 1;
+      this.width = this.canvas.clientWidth;
+      this.canvas.width = this.width * ratio;
+      this.canvas.height = this.height * ratio;
+      const context = this.canvas.getContext("2d");
+      context.setTransform(ratio, 0, 0, ratio, 0, 0);
+      context.font = FONT;
+
+      const plot = this.plot;
+      context.fillStyle = "#f8f8f8";
+      context.fillRect(plot.left, plot.top, plot.width, plot.height);
+
+      if (this.kind !== "loss") {
+        this.drawAxis(context);
+      }
+
+      context.save();
+      context.beginPath();
+      context.rect(plot.left, plot.top, plot.width, plot.height);
+      context.clip();
+
+      for (const phase of this.result.phases) {
+        const start = this.x(phase.start);
+        context.globalAlpha = 0.12;
+        context.fillStyle = phase.color;
+        context.fillRect(start, plot.top, this.x(phase.end) - start, plot.height);
+        context.globalAlpha = 1;
+        if (this.kind !== "loss") {
+          context.fillStyle = phase.color;
+          context.textBaseline = "top";
+          context.fillText(phase.name, start + 3, plot.top + 2);
+        }
+      }
+
+      if (this.kind === "loss") {
+        this.drawLoss(context);
+      } else {
+        this.drawSeries(context);
+      }
+
+      if (hover !== null) {
+        context.strokeStyle = "rgba(0, 0, 0, 0.4)";
+        context.lineWidth = 1;
+        context.beginPath();
+        context.moveTo(Math.round(this.x(hover)) + 0.5, plot.top);
+        context.lineTo(Math.round(this.x(hover)) + 0.5, plot.top + plot.height);
+        context.stroke();
+      }
+
+      if (this.selection) {
+        context.fillStyle = "rgba(37, 83, 169, 0.15)";
+        const [a, b] = this.selection;
+        context.fillRect(Math.min(a, b), plot.top, Math.abs(b - a), plot.height);
+      }
+
+      context.restore();
+
+      context.strokeStyle = "#000";
+      context.lineWidth = 1;
+      context.strokeRect(plot.left + 0.5, plot.top + 0.5, plot.width, plot.height);
+
+      context.fillStyle = "#222";
+      context.textBaseline = "top";
+      context.textAlign = "center";
+      const step = niceStep(view[1] - view[0], Math.max(2, plot.width / 70));
+      for (const time of ticks(view[0], view[1], Math.max(2, plot.width / 70))) {
+        context.fillText(format(time, step), this.x(time), plot.top + plot.height + 5);
+      }
+      context.textAlign = "left";
+    }
+
+    drawAxis(context) {
+      const plot = this.plot;
+      this.max = rowMax(this.kind) || 1;
+
+      const count = Math.max(2, plot.height / 40);
+      const step = niceStep(this.max, count);
+      context.textAlign = "right";
+      context.textBaseline = "middle";
+      for (const value of ticks(0, this.max, count)) {
+        context.fillStyle = "#e4e4e4";
+        context.fillRect(plot.left, Math.round(this.y(value)), plot.width, 1);
+        context.fillStyle = "#222";
+        context.fillText(format(value, step), plot.left - 6, this.y(value));
+      }
+      context.textAlign = "left";
+    }
+
+    drawSeries(context) {
+      for (const line of this.series) {
+        if (hidden.has(line.name)) {
+          continue;
+        }
+        context.strokeStyle = line.color;
+        context.fillStyle = line.color;
+        context.lineWidth = 1;
+        context.beginPath();
+        const start = Math.max(0, search(line.points, view[0]) - 1);
+        const end = Math.min(line.points.length, search(line.points, view[1]) + 1);
+        let drawing = false;
+        for (let i = start; i < end; i++) {
+          const [time, value] = line.points[i];
+          if (value === null) {
+            drawing = false;
+            continue;
+          }
+          if (drawing) {
+            context.lineTo(this.x(time), this.y(value));
+          } else {
+            context.moveTo(this.x(time), this.y(value));
+            drawing = true;
+          }
+        }
+        context.stroke();
+      }
+    }
+
+    drawLoss(context) {
+      const plot = this.plot;
+      for (const loss of this.result.loss) {
+        const x = Math.round(this.x(loss.time)) + 0.5;
+        if (x < plot.left || x > plot.left + plot.width) {
+          continue;
+        }
+        // Lost pings are drawn from the bottom going up, lost replies from the top going down.
+        let from = plot.top;
+        let to = plot.top + plot.height;
+        if (loss.down === true) {
+          context.strokeStyle = this.result.latency.find((line) => line.name === "Down").color;
+          to = plot.top + plot.height / 2;
+        } else if (loss.down === false) {
+          context.strokeStyle = this.result.latency.find((line) => line.name === "Up").color;
+          from = plot.top + plot.height / 2;
+        } else {
+          context.strokeStyle = "#c15555";
+        }
+        context.beginPath();
+        context.moveTo(x, from);
+        context.lineTo(x, to);
+        context.stroke();
+      }
+    }
+  }
+
+  // Charts of the same kind share their scale so results can be compared.
+  function rowMax(kind) {
+    const max = Math.max(
+      ...charts.filter((chart) => chart.kind === kind).map((chart) => visibleMax(chart.series))
+    );
+    return max * 1.05;
+  }
+
+  function toggle(name) {
+    if (hidden.has(name)) {
+      hidden.delete(name);
+    } else {
+      hidden.add(name);
+    }
+    for (const entry of document.querySelectorAll(".legend span")) {
+      entry.classList.toggle("hidden", hidden.has(entry.dataset.name));
+    }
+    drawAll();
+  }
+
+  let pending = false;
+
+  function drawAll() {
+    if (pending) {
+      return;
+    }
+    pending = true;
+    window.requestAnimationFrame(() => {
+      pending = false;
+      for (const chart of charts) {
+        chart.draw();
+      }
+    });
+  }
+
+  const container = document.getElementById("charts");
+  const kinds = ["bandwidth", "latency", "loss"].filter(
+    (kind) => kind === "loss" || results.some((result) => result[kind].length > 0)
+  );
+  for (const kind of kinds) {
+    const row = document.createElement("div");
+    row.className = "row";
+    row.style.gridTemplateColumns = "repeat(" + results.length + ", 1fr)";
+    container.appendChild(row);
+    for (const result of results) {
+      charts.push(new Chart(row, result, kind));
+    }
+  }
+
+  window.addEventListener("resize", drawAll);
+  drawAll();
+})();
@@ -0,0 +1,346 @@
+//! Self-contained HTML reports with interactive charts.
+
+use plotters::style::RGBColor;
+use serde::Serialize;
+use std::fs;
+use std::time::Duration;
+
+use crate::plot::{latency_kinds, to_rates, Summary, TestResult, BOTH_COLOR, DOWN_COLOR, UP_COLOR};
+use crate::test::unique;
+
+const SCRIPT: &str = include_str!("report.js");
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 20px; color: #222; }
+h1 { font-weight: normal; text-align: center; margin: 0 0 4px 0; }
+.help { text-align: center; color: #777; font-size: 13px; margin: 0 0 16px 0; }
+.row { display: grid; gap: 16px; margin-bottom: 8px; }
+.chart { position: relative; min-width: 0; }
+.chart .title { font-size: 13px; color: #555; }
+.chart canvas { display: block; width: 100%; cursor: crosshair; }
+.legend { font-size: 13px; user-select: none; }
+.legend span { cursor: pointer; margin-right: 12px; white-space: nowrap; }
+.legend span.hidden { opacity: 0.35; }
+.legend i { display: inline-block; width: 14px; height: 8px; margin-right: 4px; }
+.tooltip { position: absolute; pointer-events: none; background: rgba(255, 255, 255, 0.9);
+  border: 1px solid #999; padding: 3px 6px; font-size: 12px; white-space: nowrap; display: none; }
+table { border-collapse: collapse; margin-bottom: 24px; font-size: 14px; }
+th, td { border: 1px solid #ccc; padding: 4px 10px; text-align: left; }
+th { background: #f0f0f0; }
+";
+
+#[derive(Serialize)]
+struct Series {
+    name: &'static str,
+    color: String,
+    /// Pairs of elapsed seconds and value, with `None` for gaps.
+    points: Vec<(f64, Option<f64>)>,
+}
+
+#[derive(Serialize)]
+struct Span {
+    name: &'static str,
+    color: String,
+    start: f64,
+    end: f64,
+}
+
+#[derive(Serialize)]
+struct Loss {
+    time: f64,
+    /// The direction the ping was lost in, if known.
+    down: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    name: &'a str,
+    duration: f64,
+    phases: Vec<Span>,
+    bandwidth: Vec<Series>,
+    latency: Vec<Series>,
+    loss: Vec<Loss>,
+}
+
+fn hex(color: RGBColor) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.0, color.1, color.2)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.2} ms", duration.as_secs_f64() * 1000.0)
+}
+
+fn optional(value: Option<f64>, unit: &str) -> String {
+    value
+        .map(|value| format!("{:.2}{}", value, unit))
+        .unwrap_or_else(|| "-".to_owned())
+}
+
+fn report_data<'a>(name: &'a str, result: &TestResult) -> Report<'a> {
+    let start = result.start.as_secs_f64();
+    let elapsed = |time: Duration| time.as_secs_f64() - start;
+
+    let phases = result
+        .phases()
+        .into_iter()
+        .map(|(phase, phase_start, phase_end)| Span {
+            name: phase.name(),
+            color: hex(phase.color()),
+            start: elapsed(Duration::from_micros(phase_start)),
+            end: elapsed(Duration::from_micros(phase_end)),
+        })
+        .collect();
+
+    let rates = |name, color, bytes: &[(u64, f64)]| Series {
+        name,
+        color: hex(color),
+        points: to_rates(bytes)
+            .into_iter()
+            .map(|(time, rate)| (elapsed(Duration::from_micros(time)), Some(rate)))
+            .collect(),
+    };
+
+    let mut bandwidth = Vec::new();
+    if result.download_bytes.is_some() || result.both_download_bytes.is_some() {
+        bandwidth.push(rates(
+            "Download",
+            DOWN_COLOR,
+            &result.combined_download_bytes,
+        ));
+    }
+    if result.upload_bytes.is_some() || result.both_upload_bytes.is_some() {
+        bandwidth.push(rates("Upload", UP_COLOR, &result.combined_upload_bytes));
+    }
+    if let Some(both_bytes) = &result.both_bytes {
+        bandwidth.push(rates("Both", BOTH_COLOR, both_bytes));
+    }
+
+    let latency = latency_kinds(!result.raw_result.inferred.up_latency)
+        .into_iter()
+        .map(|(name, get_latency)| Series {
+            name,
+            color: hex(match name {
+                "Up" => UP_COLOR,
+                "Down" => DOWN_COLOR,
+                _ => RGBColor(50, 50, 50),
+            }),
+            points: result
+                .pings
+                .iter()
+                .map(|ping| {
+                    (
+                        elapsed(ping.sent),
+                        ping.latency
+                            .and_then(|latency| get_latency(&latency))
+                            .map(|latency| latency.as_secs_f64() * 1000.0),
+                    )
+                })
+                .collect(),
+        })
+        .collect();
+
+    let loss = result
+        .pings
+        .iter()
+        .filter(|ping| ping.sent >= result.start)
+        .filter(|ping| ping.latency.and_then(|latency| latency.total).is_none())
+        .map(|ping| Loss {
+            time: elapsed(ping.sent),
+            down: (!result.raw_result.inferred.loss_direction).then(|| ping.latency.is_some()),
+        })
+        .collect();
+
+    Report {
+        name,
+        duration: result.duration.as_secs_f64(),
+        phases,
+        bandwidth,
+        latency,
+        loss,
+    }
+}
+
+/// Writes a table with a row for each of `rows` and a column for each result.
+fn table(html: &mut String, names: &[&str], rows: &[(&str, Vec<String>)]) {
+    html.push_str("<table>\n<tr><th></th>");
+    for name in names {
+        html.push_str(&format!("<th>{}</th>", escape(name)));
+    }
+    html.push_str("</tr>\n");
+    for (label, values) in rows {
+        html.push_str(&format!("<tr><th>{}</th>", escape(label)));
+        for value in values {
+            html.push_str(&format!("<td>{}</td>", escape(value)));
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n");
+}
+
+fn summary_rows(results: &[(&str, &TestResult)]) -> Vec<(&'static str, Vec<String>)> {
+    let summaries: Vec<_> = results.iter().map(|(_, result)| result.summary()).collect();
+    let row =
+        |label, value: &dyn Fn(&Summary) -> String| (label, summaries.iter().map(value).collect());
+
+    vec![
+        row("Download", &|summary| optional(summary.download, " Mbps")),
+        row("Upload", &|summary| optional(summary.upload, " Mbps")),
+        row("Both", &|summary| optional(summary.both, " Mbps")),
+        row("Idle latency", &|summary| millis(summary.idle_latency)),
+        row("Median loaded latency", &|summary| {
+            summary
+                .loaded_latency
+                .map(millis)
+                .unwrap_or_else(|| "-".to_owned())
+        }),
+        row("Packet loss", &|summary| {
+            format!("{:.2}%", summary.packet_loss * 100.0)
+        }),
+        row("Loss bursts", &|summary| {
+            format!(
+                "{}, mean {:.2} pings, longest {} pings",
+                summary.loss_bursts.count, summary.loss_bursts.mean, summary.loss_bursts.max
+            )
+        }),
+        row("Download fairness", &|summary| {
+            optional(summary.download_fairness, "")
+        }),
+        row("Upload fairness", &|summary| {
+            optional(summary.upload_fairness, "")
+        }),
+        row("Both fairness", &|summary| {
+            optional(summary.both_fairness, "")
+        }),
+    ]
+}
+
+fn config_rows(results: &[(&str, &TestResult)]) -> Vec<(&'static str, Vec<String>)> {
+    let row = |label, value: &dyn Fn(&TestResult) -> String| {
+        (
+            label,
+            results.iter().map(|(_, result)| value(result)).collect(),
+        )
+    };
+
+    vec![
+        row("Generated by", &|result| {
+            result.raw_result.generated_by.clone()
+        }),
+        row("File format version", &|result| {
+            let inferred = &result.raw_result.inferred;
+            match inferred.original_version {
+                Some(version) if version != result.raw_result.version => {
+                    format!("{}, upgraded from {}", result.raw_result.version, version)
+                }
+                _ => result.raw_result.version.to_string(),
+            }
+        }),
+        row("Inferred", &|result| {
+            let fields = result.raw_result.inferred.fields();
+            if fields.is_empty() {
+                "-".to_owned()
+            } else {
+                fields.join(", ")
+            }
+        }),
+        row("Connections", &|result| {
+            format!(
+                "{} over IPv{}",
+                result.raw_result.streams(),
+                if result.raw_result.ipv6 { 6 } else { 4 }
+            )
+        }),
+        row("Load duration", &|result| {
+            format!(
+                "{:.2} s",
+                result.raw_result.config.load_duration.as_secs_f64()
+            )
+        }),
+        row("Grace duration", &|result| {
+            format!(
+                "{:.2} s",
+                result.raw_result.config.grace_duration.as_secs_f64()
+            )
+        }),
+        row("Stream stagger", &|result| {
+            format!("{:.2} s", result.raw_result.config.stagger.as_secs_f64())
+        }),
+        row("Ping interval", &|result| {
+            millis(result.raw_result.config.ping_interval)
+        }),
+        row("Bandwidth interval", &|result| {
+            millis(result.raw_result.config.bandwidth_interval)
+        }),
+        row("Server latency", &|result| {
+            millis(result.raw_result.server_latency)
+        }),
+        row("Timestamps", &|result| {
+            format!(
+                "client {}, server {}",
+                result.raw_result.client_timestamps, result.raw_result.server_timestamps
+            )
+        }),
+        row("TLS", &|result| {
+            result
+                .raw_result
+                .config
+                .tls
+                .as_ref()
+                .map(|tls| format!("{}, {}", tls.protocol, tls.cipher_suite))
+                .unwrap_or_else(|| "None".to_owned())
+        }),
+    ]
+}
+
+/// Renders `results` as a single HTML page, with the results side by side.
+pub fn report(results: &[(&str, &TestResult)]) -> String {
+    let names: Vec<_> = results.iter().map(|(name, _)| *name).collect();
+
+    let data: Vec<_> = results
+        .iter()
+        .map(|(name, result)| report_data(name, result))
+        .collect();
+    // Keep the data from closing the script element.
+    let data = serde_json::to_string(&data).unwrap().replace("</", "<\\/");
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+        <title>Latency under load - {}</title>\n<style>{}</style>\n</head>\n<body>\n\
+        <h1>Latency under load</h1>\n\
+        <p class=\"help\">Drag to zoom in, shift and drag to pan, \
+        ctrl and scroll to zoom and double click to reset. \
+        Click legend entries to hide series.</p>\n\
+        <div id=\"charts\"></div>\n",
+        escape(&names.join(", ")),
+        STYLE,
+    );
+
+    html.push_str("<h2>Summary</h2>\n");
+    table(&mut html, &names, &summary_rows(results));
+
+    html.push_str("<h2>Configuration</h2>\n");
+    table(&mut html, &names, &config_rows(results));
+
+    html.push_str(&format!(
+        "<script id=\"data\" type=\"application/json\">{}</script>\n\
+        <script>{}</script>\n</body>\n</html>\n",
+        data, SCRIPT
+    ));
+
+    html
+}
+
+/// Saves a report of `results` as a new HTML file.
+pub fn save_report(results: &[(&str, &TestResult)], name: &str) -> String {
+    let name = name.strip_suffix(".html").unwrap_or(name);
+    let file = unique(name, "html");
+    fs::write(&file, report(results)).unwrap();
+    file
+}
@@ -0,0 +1,301 @@
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use crate::file_format::RawMetadata;
+use crate::plot::save_graph;
+use crate::test::{run_test, save_raw, timed, unique, Config, PlotConfig};
+
+/// A cron-like schedule with minute, hour, day of month, month and day of week
+/// fields. Fields can be `*`, numbers, ranges like `1-5` and lists of those,
+/// each optionally followed by a step like `*/15`.
+#[derive(Clone, Debug)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Like cron, a day matches either field if both are restricted.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<(u64, bool), Box<dyn Error>> {
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>()?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(format!("Invalid step in `{}`", field).into());
+        }
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (start.parse()?, end.parse()?)
+        } else {
+            let value = range.parse()?;
+            (value, if part.contains('/') { max } else { value })
+        };
+        if start < min || end > max || start > end {
+            return Err(format!("`{}` is outside of {}-{}", part, min, max).into());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok((mask, field == "*"))
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(|error| error.to_string())
+    }
+}
+
+impl Cron {
+    pub fn parse(s: &str) -> Result<Self, Box<dyn Error>> {
+        let fields: Vec<_> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err("Expected 5 fields: minute, hour, day of month, month, day of week".into());
+        }
+        let (minutes, _) = parse_field(fields[0], 0, 59)?;
+        let (hours, _) = parse_field(fields[1], 0, 23)?;
+        let (days, any_day) = parse_field(fields[2], 1, 31)?;
+        let (months, _) = parse_field(fields[3], 1, 12)?;
+        let (mut weekdays, any_weekday) = parse_field(fields[4], 0, 7)?;
+        // Both 0 and 7 are Sunday.
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Cron {
+            minutes,
+            hours,
+            days,
+            months,
+            weekdays,
+            any_day,
+            any_weekday,
+        })
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        day && self.months & (1 << date.month()) != 0
+    }
+
+    /// Finds the first matching minute after `time`.
+    pub fn next_after(&self, time: DateTime<Local>) -> Option<DateTime<Local>> {
+        let after = time.naive_local();
+        let mut date = after.date();
+
+        // Every schedule matches at least once in 8 years due to leap days.
+        for _ in 0..(366 * 8) {
+            if self.matches_day(date) {
+                for hour in (0..24).filter(|hour| self.hours & (1 << hour) != 0) {
+                    for minute in (0..60).filter(|minute| self.minutes & (1 << minute) != 0) {
+                        let candidate =
+                            NaiveDateTime::new(date, NaiveTime::from_hms_opt(hour, minute, 0)?);
+                        if candidate <= after {
+                            continue;
+                        }
+                        if let Some(time) = Local.from_local_datetime(&candidate).earliest() {
+                            return Some(time);
+                        }
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+
+        None
+    }
+}
+
+/// When the tests of a session run.
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    /// Stop after this many runs, or run until stopped.
+    pub runs: Option<u64>,
+    /// Minimum time between the start of runs.
+    pub interval: Duration,
+    /// Start runs on the minutes matching this.
+    pub cron: Option<Cron>,
+    /// Attempts at each run before it's recorded as failed.
+    pub attempts: u64,
+    pub retry_delay: Duration,
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn optional(value: Option<f64>) -> String {
+    value
+        .map(|value| format!("{:.2}", value))
+        .unwrap_or_default()
+}
+
+/// Runs tests according to `schedule`, saving results into a new directory
+/// along with an `index.csv` file listing each run.
+pub fn run(
+    config: Config,
+    plot: Option<PlotConfig>,
+    host: &str,
+    metadata: RawMetadata,
+    schedule: &Schedule,
+) -> Result<(), Box<dyn Error>> {
+    let dir = timed("session");
+    fs::create_dir(&dir)?;
+    let dir = Path::new(&dir);
+
+    let index = dir.join("index.csv");
+    let mut index_file = OpenOptions::new().create(true).append(true).open(&index)?;
+    writeln!(
+        index_file,
+        "time,status,attempts,download_mbps,upload_mbps,both_mbps,\
+        idle_latency_ms,loaded_latency_ms,packet_loss_percent,\
+        loss_bursts,mean_loss_burst,max_loss_burst,\
+        download_fairness,upload_fairness,both_fairness,data,plot,error"
+    )?;
+    index_file.flush()?;
+
+    println!("Saving session to {}", dir.display());
+
+    let mut next = Local::now();
+    let mut run = 0;
+
+    while schedule.runs.map(|runs| run < runs).unwrap_or(true) {
+        if let Some(cron) = &schedule.cron {
+            next = cron.next_after(next).ok_or("Schedule never matches")?;
+        }
+        let wait = (next - Local::now()).to_std().unwrap_or_default();
+        if !wait.is_zero() {
+            println!("Next run at {}", next.format("%Y-%m-%d %H:%M:%S"));
+            thread::sleep(wait);
+        }
+
+        run += 1;
+        let started = Local::now();
+        next = started + chrono::Duration::from_std(schedule.interval)?;
+
+        let journal = config
+            .incremental
+            .then(|| unique(dir.join("data").to_str().unwrap(), "crr"));
+
+        let mut attempt = 0;
+        let result = loop {
+            attempt += 1;
+            println!(
+                "Starting run {}{}",
+                run,
+                schedule
+                    .runs
+                    .map(|runs| format!(" of {}", runs))
+                    .unwrap_or_default()
+            );
+            match run_test(
+                config,
+                host,
+                metadata.clone(),
+                journal.as_deref().map(Path::new),
+            ) {
+                Ok(result) => break Ok(result),
+                Err(error) if attempt < schedule.attempts => {
+                    println!(
+                        "Run {} failed: {}. Retrying in {} s...",
+                        run,
+                        error,
+                        schedule.retry_delay.as_secs()
+                    );
+                    thread::sleep(schedule.retry_delay);
+                }
+                Err(error) => break Err(error),
+            }
+        };
+
+        let mut row = format!("{},", started.to_rfc3339());
+        match result {
+            Ok((result, journaled)) => {
+                let data = match journal.as_ref().filter(|_| journaled) {
+                    Some(journal) => journal.clone(),
+                    None => save_raw(&result, dir.join("data").to_str().ok_or("Invalid path")?),
+                };
+                println!("Saved raw data as {}", data);
+
+                let test_result = result.to_test_result();
+                let plot = plot.as_ref().map(|plot| {
+                    let file = save_graph(plot, &test_result, dir.join("plot").to_str().unwrap());
+                    println!("Saved plot as {}", file);
+                    file
+                });
+
+                let summary = test_result.summary();
+                write!(
+                    row,
+                    "ok,{},{},{},{},{:.2},{},{:.2},{},{:.2},{},{},{},{},{},{},",
+                    attempt,
+                    optional(summary.download),
+                    optional(summary.upload),
+                    optional(summary.both),
+                    summary.idle_latency.as_secs_f64() * 1000.0,
+                    optional(
+                        summary
+                            .loaded_latency
+                            .map(|latency| latency.as_secs_f64() * 1000.0)
+                    ),
+                    summary.packet_loss * 100.0,
+                    summary.loss_bursts.count,
+                    summary.loss_bursts.mean,
+                    summary.loss_bursts.max,
+                    optional(summary.download_fairness),
+                    optional(summary.upload_fairness),
+                    optional(summary.both_fairness),
+                    csv_field(&file_name(&data)),
+                    csv_field(&plot.as_deref().map(file_name).unwrap_or_default()),
+                )?;
+            }
+            Err(error) => {
+                println!("Run {} failed after {} attempts: {}", run, attempt, error);
+                write!(
+                    row,
+                    "failed,{},,,,,,,,,,,,,,,{}",
+                    attempt,
+                    csv_field(&error)
+                )?;
+            }
+        }
+        writeln!(index_file, "{}", row)?;
+        index_file.flush()?;
+    }
+
+    println!("Session complete, index saved as {}", index.display());
+
+    Ok(())
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
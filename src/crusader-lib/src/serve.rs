@@ -1,5 +1,6 @@
 use futures::{pin_mut, select, FutureExt};
 use parking_lot::Mutex;
+use rand::Rng;
 use socket2::{Domain, Protocol, Socket};
 use std::collections::HashMap;
 use std::error::Error;
@@ -17,10 +18,14 @@ use tokio::task::{self};
 use tokio::{signal, time, time::Instant};
 use tokio_util::codec::{FramedRead, FramedWrite};
 
+use crate::discovery;
 use crate::protocol::{
-    self, codec, receive, send, ClientMessage, LatencyMeasure, ServerMessage, TestStream,
+    self, codec, receive, send, ClientMessage, LatencyMeasure, PreSharedKey, ServerMessage,
+    Session, SessionToken, TestStream,
 };
 use crate::test;
+use crate::timestamp::{self, TimestampSource};
+use crate::tls::{Acceptor, ServerTls};
 
 use std::thread;
 
@@ -34,6 +39,7 @@ struct SlotUpdate {
 #[derive(Debug)]
 struct Client {
     ip: Ipv6Addr,
+    token: SessionToken,
     tx_message: UnboundedSender<ServerMessage>,
     tx_latency: Sender<LatencyMeasure>,
     rx_latency: Mutex<Receiver<LatencyMeasure>>,
@@ -92,6 +98,10 @@ struct State {
     clients: Mutex<Vec<Option<Arc<Client>>>>,
     pong_v6: UnboundedSender<SlotUpdate>,
     pong_v4: UnboundedSender<SlotUpdate>,
+    timestamps: TimestampSource,
+    psk: Option<PreSharedKey>,
+    tls: bool,
+    acceptor: Acceptor,
     msg: Box<dyn Fn(&str) + Send + Sync>,
 }
 
@@ -111,11 +121,11 @@ impl<F: Fn()> Drop for OnDrop<F> {
 }
 
 async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), Box<dyn Error>> {
-    stream.set_nodelay(true)?;
+    let stream = state.acceptor.accept(stream).await?;
 
     let addr = stream.peer_addr()?;
 
-    let (rx, tx) = stream.into_split();
+    let (rx, tx) = tokio::io::split(stream);
     let mut stream_rx = FramedRead::new(rx, codec());
     let mut stream_tx = FramedWrite::new(tx, codec());
 
@@ -144,6 +154,21 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), Box<dyn Erro
         let request: ClientMessage = receive(&mut stream_rx).await?;
         match request {
             ClientMessage::NewClient => {
+                if let Some(psk) = state.psk {
+                    let challenge = rand::thread_rng().gen();
+                    send(&mut stream_tx, &ServerMessage::Challenge(challenge)).await?;
+                    let reply: ClientMessage = receive(&mut stream_rx).await?;
+                    let authorized = match reply {
+                        ClientMessage::Authenticate(response) => psk.verify(&challenge, &response),
+                        _ => false,
+                    };
+                    if !authorized {
+                        (state.msg)(&format!("Client {} failed to authenticate", addr));
+                        send(&mut stream_tx, &ServerMessage::Unauthorized).await?;
+                        return Ok(());
+                    }
+                }
+
                 (state.msg)(&format!("Serving {}, version {}", addr, hello.version));
 
                 let client = {
@@ -159,6 +184,7 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), Box<dyn Erro
                             let slot = slot as u64;
                             let new_client = Arc::new(Client {
                                 ip: ip_to_ipv6_mapped(addr.ip()),
+                                token: SessionToken::new(),
                                 tx_message,
                                 tx_latency,
                                 rx_latency: Mutex::new(rx_latency),
@@ -216,24 +242,37 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), Box<dyn Erro
                                 .ok();
                         });
 
-                        Some(slot)
+                        Some(Session {
+                            id: slot,
+                            token: client.token,
+                        })
                     } else {
                         None
                     }
                 };
 
-                send(&mut stream_tx, &ServerMessage::NewClient(client)).await?;
+                send(
+                    &mut stream_tx,
+                    &ServerMessage::NewClient {
+                        session: client,
+                        timestamps: state.timestamps,
+                        software_version: env!("CARGO_PKG_VERSION").to_owned(),
+                    },
+                )
+                .await?;
             }
-            ClientMessage::Associate(id) => {
+            ClientMessage::Associate(session) => {
                 client = Some(
                     state
                         .clients
                         .lock()
-                        .get(id as usize)
+                        .get(session.id as usize)
                         .and_then(|client| client.as_ref())
                         .cloned()
                         .and_then(|client| {
-                            (client.ip == ip_to_ipv6_mapped(addr.ip())).then_some(client)
+                            (client.ip == ip_to_ipv6_mapped(addr.ip())
+                                && client.token.matches(&session.token))
+                            .then_some(client)
                         })
                         .ok_or("Unable to assoicate client")?,
                 );
@@ -323,25 +362,20 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), Box<dyn Erro
             } => {
                 let client = client.ok_or("No associated client")?;
 
-                let mut stream_rx = stream_rx.into_inner();
-
                 send(&mut stream_tx, &ServerMessage::WaitingForByte).await?;
 
-                // Wait for a pending read byte
-                loop {
-                    let _ = stream_rx.read(&mut []).await?;
-                    match time::timeout(Duration::from_millis(10), stream_rx.peek(&mut [0])).await {
-                        Ok(Ok(1)) => break,
-                        Err(_) | Ok(Ok(_)) => (),
-                        Ok(Err(err)) => return Err(err.into()),
-                    }
-                }
+                let mut stream = stream_rx.into_inner().unsplit(stream_tx.into_inner());
 
-                let mut waiter = client.load_waiter(test_stream.group);
+                // Wait for the byte from the client, which is encrypted with TLS
+                stream.read_u8().await?;
 
-                send(&mut stream_tx, &ServerMessage::WaitingForLoad).await?;
+                let mut waiter = client.load_waiter(test_stream.group);
 
-                let stream = stream_tx.into_inner().reunite(stream_rx).unwrap();
+                send(
+                    &mut FramedWrite::new(&mut stream, codec()),
+                    &ServerMessage::WaitingForLoad,
+                )
+                .await?;
 
                 waiter.changed().await?;
                 let start = waiter.borrow().ok_or("Expected time")? + Duration::from_micros(delay);
@@ -380,12 +414,10 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), Box<dyn Erro
                     _ => return Err(format!("Unexpected message {:?}", reply).into()),
                 };
 
-                let mut stream = stream_rx
-                    .into_inner()
-                    .reunite(stream_tx.into_inner())
-                    .unwrap();
+                let mut stream = stream_rx.into_inner().unsplit(stream_tx.into_inner());
 
                 stream.write_u8(1).await.unwrap();
+                stream.flush().await.unwrap();
 
                 let (reading_done_tx, reading_done_rx) = oneshot::channel();
 
@@ -454,6 +486,7 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), Box<dyn Erro
                 return Ok(());
             }
             msg @ (ClientMessage::StopMeasurements
+            | ClientMessage::Authenticate(..)
             | ClientMessage::ScheduleLoads { .. }
             | ClientMessage::LoadComplete { .. }
             | ClientMessage::SendByte) => {
@@ -486,6 +519,7 @@ async fn handle_ping(
     slots: &[Option<Arc<Client>>],
     packet: &[u8],
     src: SocketAddr,
+    received: Instant,
     socket: &UdpSocket,
 ) {
     let valid_ping = bincode::deserialize(packet)
@@ -495,12 +529,13 @@ async fn handle_ping(
                 .get(ping.id as usize)
                 .and_then(|client| client.as_ref())
                 .and_then(|client| {
-                    (ip_to_ipv6_mapped(src.ip()) == client.ip).then_some((client, ping))
+                    (ip_to_ipv6_mapped(src.ip()) == client.ip && ping.is_valid(&client.token))
+                        .then_some((client, ping))
                 })
         });
 
     if let Some((client, ping)) = valid_ping {
-        let time = Instant::now()
+        let time = received
             .saturating_duration_since(state.started)
             .as_micros() as u64;
 
@@ -523,13 +558,49 @@ async fn handle_ping(
     }
 }
 
-async fn pong(socket: UdpSocket, state: Arc<State>, mut rx: UnboundedReceiver<SlotUpdate>) {
+/// Replies to clients looking for servers on the local network. Requests from
+/// other networks are ignored.
+async fn handle_discover(
+    state: &State,
+    packet: &[u8],
+    src: SocketAddr,
+    socket: &UdpSocket,
+) -> bool {
+    match bincode::deserialize::<protocol::Discover>(packet) {
+        Ok(discover) if discover.magic == protocol::DISCOVER_MAGIC => (),
+        _ => return false,
+    }
+
+    if !discovery::local(src.ip()) {
+        return true;
+    }
+
+    let reply =
+        bincode::serialize(&discovery::announcement(state.tls, state.psk.is_some())).unwrap();
+
+    socket
+        .send_to(&reply, &src)
+        .await
+        .map_err(|error| {
+            (state.msg)(&format!("Unable to reply to discovery: {:?}", error));
+        })
+        .ok();
+
+    true
+}
+
+async fn pong(
+    socket: UdpSocket,
+    timestamps: TimestampSource,
+    state: Arc<State>,
+    mut rx: UnboundedReceiver<SlotUpdate>,
+) {
     let mut slots: Vec<_> = (0..SLOTS).map(|_| None).collect();
     let mut buf = [0; 128];
 
     loop {
         let packet = {
-            let socket_packet = socket.recv_from(&mut buf).fuse();
+            let socket_packet = timestamp::recv_from(&socket, &mut buf, timestamps).fuse();
             pin_mut!(socket_packet);
 
             let message = rx.recv().fuse();
@@ -538,8 +609,8 @@ async fn pong(socket: UdpSocket, state: Arc<State>, mut rx: UnboundedReceiver<Sl
             select! {
                 result = socket_packet => {
                     match result {
-                        Ok((len, src)) => {
-                            Some((len, src))
+                        Ok((len, src, received)) => {
+                            Some((len, src, received))
                         }
                         Err(error) => {
                             (state.msg)(&format!("Unable to get UDP ping: {:?}", error));
@@ -557,19 +628,32 @@ async fn pong(socket: UdpSocket, state: Arc<State>, mut rx: UnboundedReceiver<Sl
             }
         };
 
-        if let Some((len, src)) = packet {
+        if let Some((len, src, received)) = packet {
             let packet = &mut buf[..len];
-            handle_ping(&state, slots.as_slice(), packet, src, &socket).await;
+            if !handle_discover(&state, packet, src, &socket).await {
+                handle_ping(&state, slots.as_slice(), packet, src, received, &socket).await;
+            }
         }
     }
 }
 
 const SLOTS: usize = 1000;
 
+#[derive(Clone)]
+pub struct Config {
+    pub port: u16,
+    /// Only serve clients which know this key.
+    pub psk: Option<PreSharedKey>,
+    /// Require TLS for all TCP connections.
+    pub tls: Option<ServerTls>,
+}
+
 async fn serve_async(
-    port: u16,
+    config: Config,
     msg: Box<dyn Fn(&str) + Send + Sync>,
 ) -> Result<(), Box<dyn Error>> {
+    let port = config.port;
+
     let socket_v6 = Socket::new(Domain::IPV6, socket2::Type::DGRAM, Some(Protocol::UDP))?;
     socket_v6.set_only_v6(true)?;
     socket_v6.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).into())?;
@@ -580,6 +664,9 @@ async fn serve_async(
     let socket_v4 =
         UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)).await?;
 
+    let timestamps_v6 = timestamp::enable(&socket_v6);
+    let timestamps_v4 = timestamp::enable(&socket_v4);
+
     let (pong_ipv6_tx, pong_ipv6_rx) = unbounded_channel();
     let (pong_ipv4_tx, pong_ipv4_rx) = unbounded_channel();
 
@@ -589,6 +676,10 @@ async fn serve_async(
         clients: Mutex::new((0..SLOTS).map(|_| None).collect()),
         pong_v6: pong_ipv6_tx,
         pong_v4: pong_ipv4_tx,
+        timestamps: timestamps_v6.min(timestamps_v4),
+        psk: config.psk,
+        tls: config.tls.is_some(),
+        acceptor: Acceptor::new(config.tls.clone()),
         msg,
     });
 
@@ -602,19 +693,34 @@ async fn serve_async(
 
     let v4 = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
 
-    tokio::spawn(pong(socket_v6, state.clone(), pong_ipv6_rx));
-    tokio::spawn(pong(socket_v4, state.clone(), pong_ipv4_rx));
+    tokio::spawn(pong(socket_v6, timestamps_v6, state.clone(), pong_ipv6_rx));
+    tokio::spawn(pong(socket_v4, timestamps_v4, state.clone(), pong_ipv4_rx));
 
     task::spawn(listen(state.clone(), v6));
     task::spawn(listen(state.clone(), v4));
 
-    (state.msg)("Server running...");
+    (state.msg)(&format!(
+        "Server running with {} timestamps{}...",
+        state.timestamps,
+        if state.psk.is_some() {
+            ", requiring a pre-shared key"
+        } else {
+            ""
+        }
+    ));
+
+    if let Some(tls) = &config.tls {
+        (state.msg)(&format!(
+            "Using TLS with certificate fingerprint {}",
+            tls.fingerprint()
+        ));
+    }
 
     Ok(())
 }
 
 pub fn serve_until(
-    port: u16,
+    config: Config,
     msg: Box<dyn Fn(&str) + Send + Sync>,
     started: Box<dyn FnOnce(Result<(), String>) + Send>,
     done: Box<dyn FnOnce() + Send>,
@@ -624,7 +730,7 @@ pub fn serve_until(
     thread::spawn(move || {
         let rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async move {
-            match serve_async(port, msg).await {
+            match serve_async(config, msg).await {
                 Ok(()) => {
                     started(Ok(()));
                     rx.await.unwrap();
@@ -639,11 +745,11 @@ pub fn serve_until(
     tx
 }
 
-pub fn serve(port: u16) {
+pub fn serve(config: Config) {
     let rt = tokio::runtime::Runtime::new().unwrap();
     rt.block_on(async move {
         serve_async(
-            port,
+            config,
             Box::new(|msg: &str| {
                 let msg = msg.to_owned();
                 task::spawn_blocking(move || println!("{msg}"));
@@ -1,12 +1,12 @@
 use bytes::{Bytes, BytesMut};
 use futures::future::FutureExt;
 use futures::{pin_mut, select, Sink, Stream};
-use futures::{stream, StreamExt};
 use parking_lot::Mutex;
 use rand::prelude::StdRng;
 use rand::Rng;
 use rand::SeedableRng;
 use std::collections::HashMap;
+use std::future::Future;
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::thread;
@@ -17,11 +17,9 @@ use std::{
     sync::Arc,
     time::Duration,
 };
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::join;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::{TcpStream, UdpSocket};
-use tokio::sync::mpsc::{channel, Sender};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{channel, unbounded_channel, Sender, UnboundedSender};
 use tokio::sync::{oneshot, watch, Semaphore};
 use tokio::task::{self, yield_now, JoinHandle};
 use tokio::time::Instant;
@@ -32,16 +30,24 @@ use tokio::{
 use tokio_util::codec::{Framed, FramedRead, FramedWrite, LengthDelimitedCodec};
 
 use crate::file_format::{
-    RawConfig, RawHeader, RawLatency, RawPing, RawPoint, RawResult, RawStream, RawStreamGroup,
+    RawConfig, RawHeader, RawInferred, RawLatency, RawMetadata, RawPing, RawPoint, RawResult,
+    RawStream, RawStreamGroup,
 };
-use crate::plot::save_graph;
+use crate::journal::{Journal, RawRecord, Recorder};
+use crate::plot::{save_graph, PlotFormat, RateFilter};
 use crate::protocol::{
-    codec, receive, send, ClientMessage, Hello, Ping, ServerMessage, TestStream,
+    codec, receive, send, ClientMessage, Hello, Ping, PreSharedKey, ServerMessage, Session,
+    SessionToken, TestStream,
 };
 use crate::serve::OnDrop;
+use crate::timestamp::{self, TimestampSource};
+use crate::tls::{ClientTls, Connection, Connector};
 
 type Msg = Arc<dyn Fn(&str) + Send + Sync>;
 
+/// Receives the errors of the tasks of a test, which end the test.
+type Failed = UnboundedSender<String>;
+
 const MEASURE_DELAY: Duration = Duration::from_millis(50);
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, PartialOrd, Ord)]
@@ -92,10 +98,11 @@ where
     let server_hello: Hello = receive(stream).await?;
 
     if hello != server_hello {
-        panic!(
+        return Err(format!(
             "Mismatched server hello, got {:?}, expected {:?}",
             server_hello, hello
-        );
+        )
+        .into());
     }
 
     Ok(())
@@ -129,13 +136,53 @@ where
     Ok(())
 }
 
+/// Requests a new session from the server, answering its challenge if it
+/// requires a pre-shared key. Returns the session, the server's timestamp source
+/// and its software version.
+pub(crate) async fn new_client<
+    T: Sink<Bytes> + Unpin,
+    R: Stream<Item = Result<BytesMut, RE>> + Unpin,
+    RE,
+>(
+    tx: &mut T,
+    rx: &mut R,
+    psk: Option<PreSharedKey>,
+) -> Result<(Session, TimestampSource, String), Box<dyn Error>>
+where
+    T::Error: Error + 'static,
+    RE: Error + 'static,
+{
+    send(tx, &ClientMessage::NewClient).await?;
+
+    let mut reply: ServerMessage = receive(rx).await?;
+
+    if let ServerMessage::Challenge(challenge) = reply {
+        let psk = psk.ok_or("Server requires a pre-shared key")?;
+        send(tx, &ClientMessage::Authenticate(psk.respond(&challenge))).await?;
+        reply = receive(rx).await?;
+    }
+
+    match reply {
+        ServerMessage::NewClient {
+            session: Some(session),
+            timestamps,
+            software_version,
+        } => Ok((session, timestamps, software_version)),
+        ServerMessage::NewClient { session: None, .. } => {
+            Err("Server was unable to create client".into())
+        }
+        ServerMessage::Unauthorized => Err("Server rejected the pre-shared key".into()),
+        _ => Err(format!("Unexpected message {:?}", reply).into()),
+    }
+}
+
 pub(crate) async fn write_data(
-    stream: TcpStream,
+    stream: Connection,
     data: &[u8],
     until: Instant,
 ) -> Result<(), Box<dyn Error>> {
-    stream.set_nodelay(false).ok();
-    stream.set_linger(Some(Duration::from_secs(0))).ok();
+    stream.tcp().set_nodelay(false).ok();
+    stream.tcp().set_linger(Some(Duration::from_secs(0))).ok();
 
     let done = Arc::new(AtomicBool::new(false));
     let done_ = done.clone();
@@ -145,6 +192,32 @@ pub(crate) async fn write_data(
         done.store(true, Ordering::Release);
     });
 
+    let stream = match stream {
+        Connection::Plain(stream) => stream,
+        mut stream @ Connection::Tls(..) => {
+            // TLS records can't be written with `try_write`, so use regular writes
+            // with a timeout to check for the end of the load.
+            loop {
+                if done_.load(Ordering::Acquire) {
+                    break;
+                }
+                match time::timeout(Duration::from_millis(50), stream.write(data)).await {
+                    Ok(Ok(_)) | Err(_) => (),
+                    Ok(Err(err)) => {
+                        if err.kind() == std::io::ErrorKind::ConnectionReset
+                            || err.kind() == std::io::ErrorKind::ConnectionAborted
+                        {
+                            break;
+                        } else {
+                            return Err(err.into());
+                        }
+                    }
+                }
+            }
+            return Ok(());
+        }
+    };
+
     loop {
         if let Ok(Err(err)) = time::timeout(Duration::from_millis(50), stream.writable()).await {
             if err.kind() == std::io::ErrorKind::ConnectionReset
@@ -182,13 +255,13 @@ pub(crate) async fn write_data(
 }
 
 pub(crate) async fn read_data(
-    stream: TcpStream,
+    stream: Connection,
     buffer: &mut [u8],
     bytes: Arc<AtomicU64>,
     until: Instant,
     writer_done: oneshot::Receiver<()>,
 ) -> Result<bool, Box<dyn Error>> {
-    stream.set_linger(Some(Duration::from_secs(0))).ok();
+    stream.tcp().set_linger(Some(Duration::from_secs(0))).ok();
 
     let reading_done = Arc::new(AtomicBool::new(false));
 
@@ -236,6 +309,33 @@ pub(crate) async fn read_data(
         reading_done_.store(true, Ordering::Release);
     });
 
+    let stream = match stream {
+        Connection::Plain(stream) => stream,
+        mut stream @ Connection::Tls(..) => loop {
+            if reading_done.load(Ordering::Acquire) {
+                return Ok(true);
+            }
+            match time::timeout(Duration::from_millis(50), stream.read(buffer)).await {
+                Err(_) => (),
+                Ok(Ok(0)) => return Ok(false),
+                Ok(Ok(n)) => {
+                    bytes.fetch_add(n as u64, Ordering::Release);
+                }
+                Ok(Err(err)) => {
+                    // The peer closes loads without a TLS close notification.
+                    if err.kind() == std::io::ErrorKind::ConnectionReset
+                        || err.kind() == std::io::ErrorKind::ConnectionAborted
+                        || err.kind() == std::io::ErrorKind::UnexpectedEof
+                    {
+                        return Ok(false);
+                    } else {
+                        return Err(err.into());
+                    }
+                }
+            }
+        },
+    };
+
     loop {
         if let Ok(Err(err)) = time::timeout(Duration::from_millis(50), stream.readable()).await {
             if err.kind() == std::io::ErrorKind::ConnectionReset
@@ -278,8 +378,32 @@ pub(crate) async fn read_data(
 pub struct PlotConfig {
     pub split_bandwidth: bool,
     pub transferred: bool,
+    /// Add a chart of how fairly streams share bandwidth.
+    pub fairness: bool,
+    /// Plot each lost ping instead of the share of pings lost over time.
+    pub loss_ticks: bool,
+    /// Add latency CDF charts for each phase.
+    pub cdf: bool,
+    /// Add latency histograms for each phase.
+    pub histogram: bool,
+    /// Mark the median and 99th percentile latency of each phase.
+    pub percentiles: bool,
+    /// Use a logarithmic latency axis.
+    pub log_latency: bool,
+    /// Fixed maximum of latency axes, so plots can be compared.
+    pub max_latency: Option<Duration>,
+    /// Fixed maximum of bandwidth axes in Mbps.
+    pub max_bandwidth: Option<f64>,
+    /// Resampling and smoothing of bandwidth rates.
+    pub rates: RateFilter,
+    /// Only plot from this time after the start of the test.
+    pub crop_start: Option<Duration>,
+    /// Only plot until this time after the start of the test.
+    pub crop_end: Option<Duration>,
     pub width: Option<u64>,
     pub height: Option<u64>,
+    /// Used unless the output file name has a known image extension.
+    pub format: PlotFormat,
 }
 
 #[derive(Copy, Clone)]
@@ -294,32 +418,119 @@ pub struct Config {
     pub stream_stagger: Duration,
     pub ping_interval: Duration,
     pub bandwidth_interval: Duration,
+    pub psk: Option<PreSharedKey>,
+    pub tls: Option<ClientTls>,
+    /// Write the result file while the test runs, so it survives the test being interrupted.
+    pub incremental: bool,
+}
+
+/// The stream groups a test with `config` measures, without any data.
+fn empty_stream_groups(config: &Config) -> Vec<RawStreamGroup> {
+    [
+        (config.download, true, false),
+        (config.both, true, true),
+        (config.upload, false, false),
+        (config.both, false, true),
+    ]
+    .into_iter()
+    .filter(|group| group.0)
+    .map(|(_, download, both)| RawStreamGroup {
+        download,
+        both,
+        streams: (0..config.streams)
+            .map(|_| RawStream { data: Vec::new() })
+            .collect(),
+    })
+    .collect()
+}
+
+/// Spawns a task of a test, which reports its error to `failed`.
+fn spawn_task<T: Send + 'static>(
+    failed: &Failed,
+    task: impl Future<Output = Result<T, Box<dyn Error>>> + Send + 'static,
+) -> JoinHandle<Option<T>> {
+    let failed = failed.clone();
+    tokio::spawn(async move {
+        match task.await {
+            Ok(value) => Some(value),
+            Err(error) => {
+                failed.send(error.to_string()).ok();
+                None
+            }
+        }
+    })
 }
 
-async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult, Box<dyn Error>> {
-    let control = net::TcpStream::connect((server, config.port)).await?;
-    control.set_nodelay(true)?;
+/// Waits for a task from `spawn_task`.
+async fn finished<T>(task: JoinHandle<Option<T>>) -> Result<T, Box<dyn Error>> {
+    task.await?.ok_or_else(|| "Test task failed".into())
+}
+
+/// Runs a test, writing its result to `journal` as it goes if given. Returns the result
+/// along with whether all of it is in the journal. The test ends with the first error
+/// of any of its tasks.
+async fn test_async(
+    config: Config,
+    server: &str,
+    metadata: RawMetadata,
+    journal: Option<&Path>,
+    msg: Msg,
+) -> Result<(RawResult, bool), Box<dyn Error>> {
+    let (failed, mut failures) = unbounded_channel();
+    let test = test_steps(config, server, metadata, journal, msg, failed).fuse();
+    pin_mut!(test);
+    select! {
+        result = test => result.map_err(|error| match failures.try_recv() {
+            // The error of the task which caused the test to fail.
+            Ok(failure) => failure.into(),
+            Err(_) => error,
+        }),
+        failure = failures.recv().fuse() => Err(failure.unwrap_or_default().into()),
+    }
+}
+
+async fn test_steps(
+    config: Config,
+    server: &str,
+    mut metadata: RawMetadata,
+    journal: Option<&Path>,
+    msg: Msg,
+    failed: Failed,
+) -> Result<(RawResult, bool), Box<dyn Error>> {
+    let connector = Connector::new(config.tls, server)?;
+
+    let control = connector.connect((server, config.port)).await?;
+
+    metadata.server = Some(server.to_owned());
 
     let server = control.peer_addr()?;
 
-    msg(&format!("Connected to server {}", server));
+    metadata.server_address = Some(server.ip().to_string());
 
-    let (rx, tx) = control.into_split();
+    let tls = control.tls();
+
+    match &tls {
+        Some(tls) => msg(&format!(
+            "Connected to server {} using {} with {}",
+            server, tls.protocol, tls.cipher_suite
+        )),
+        None => msg(&format!("Connected to server {}", server)),
+    }
+
+    let (rx, tx) = tokio::io::split(control);
     let mut control_rx = FramedRead::new(rx, codec());
     let mut control_tx = FramedWrite::new(tx, codec());
 
     hello(&mut control_tx, &mut control_rx).await?;
 
-    send(&mut control_tx, &ClientMessage::NewClient).await?;
-
     let setup_start = Instant::now();
 
-    let reply: ServerMessage = receive(&mut control_rx).await?;
-    let id = match reply {
-        ServerMessage::NewClient(Some(id)) => id,
-        ServerMessage::NewClient(None) => return Err("Server was unable to create client".into()),
-        _ => return Err(format!("Unexpected message {:?}", reply).into()),
-    };
+    metadata.start_time = Some(chrono::Utc::now());
+
+    let (session, server_timestamps, server_version) =
+        new_client(&mut control_tx, &mut control_rx, config.psk).await?;
+
+    metadata.server_version = Some(server_version);
 
     let local_udp = if server.is_ipv6() {
         SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
@@ -330,13 +541,14 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
     let mut ping_index = 0;
 
     let (latency, server_time_offset, mut control_rx) = measure_latency(
-        id,
+        session,
         &mut ping_index,
         &mut control_tx,
         control_rx,
         server,
         local_udp,
         setup_start,
+        &failed,
     )
     .await?;
 
@@ -347,11 +559,56 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
 
     let udp_socket = Arc::new(net::UdpSocket::bind(local_udp).await?);
     udp_socket.connect(server).await?;
+    let client_timestamps = timestamp::enable(&udp_socket);
     let udp_socket2 = udp_socket.clone();
 
+    // Everything known before the test starts.
+    let base = RawResult {
+        version: RawHeader::default().version,
+        generated_by: format!("Crusader {}", env!("CARGO_PKG_VERSION")),
+        config: RawConfig {
+            stagger: config.stream_stagger,
+            load_duration: config.load_duration,
+            grace_duration: config.grace_duration,
+            ping_interval: config.ping_interval,
+            bandwidth_interval: config.bandwidth_interval,
+            tls,
+        },
+        ipv6: server.is_ipv6(),
+        load_termination_timeout: false,
+        server_overload: false,
+        server_latency: latency,
+        client_timestamps,
+        server_timestamps,
+        start: Duration::ZERO,
+        duration: Duration::ZERO,
+        stream_groups: empty_stream_groups(&config),
+        pings: Vec::new(),
+        metadata,
+        inferred: RawInferred::default(),
+    };
+
+    let journal = match journal {
+        Some(path) => match Journal::create(path, base.clone()) {
+            Ok(journal) => Some(journal),
+            Err(error) => {
+                msg(&format!(
+                    "Unable to write raw data during the test: {}",
+                    error
+                ));
+                None
+            }
+        },
+        None => None,
+    };
+    let recorder = journal
+        .as_ref()
+        .map(|journal| journal.recorder())
+        .unwrap_or_default();
+
     let data = Arc::new(data());
 
-    let loading_streams: u32 = config.streams.try_into().unwrap();
+    let loading_streams: u32 = config.streams.try_into()?;
 
     let grace = config.grace_duration;
     let load_duration = config.load_duration;
@@ -377,7 +634,8 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
         loader_count += config.streams;
         upload_loaders(
             all_loaders.clone(),
-            id,
+            session,
+            &connector,
             server,
             0,
             config,
@@ -386,6 +644,7 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
             state_rx.clone(),
             TestState::LoadFromClient,
             upload_done_tx.clone(),
+            &failed,
         );
     }
 
@@ -393,7 +652,8 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
         loader_count += config.streams;
         upload_loaders(
             all_loaders.clone(),
-            id,
+            session,
+            &connector,
             server,
             1,
             config,
@@ -402,6 +662,7 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
             state_rx.clone(),
             TestState::LoadFromBoth,
             upload_done_tx.clone(),
+            &failed,
         );
     }
 
@@ -410,13 +671,16 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
         download_loaders(
             state.clone(),
             all_loaders.clone(),
-            id,
+            session,
+            &connector,
             server,
             2,
             config,
             setup_start,
             state_rx.clone(),
             TestState::LoadFromServer,
+            recorder.clone(),
+            &failed,
         )
     });
 
@@ -425,13 +689,16 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
         download_loaders(
             state.clone(),
             all_loaders.clone(),
-            id,
+            session,
+            &connector,
             server,
             3,
             config,
             setup_start,
             state_rx.clone(),
             TestState::LoadFromBoth,
+            recorder.clone(),
+            &failed,
         )
     });
 
@@ -448,13 +715,14 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
     let (scheduled_load_tx, mut scheduled_load_rx) = channel(4);
 
     let state_ = state.clone();
-    let measures = tokio::spawn(async move {
+    let recorder_ = recorder.clone();
+    let measures = spawn_task(&failed, async move {
         let mut bandwidth = Vec::new();
         let mut latencies = Vec::new();
         let overload_;
 
         loop {
-            let reply: ServerMessage = receive(&mut control_rx).await.unwrap();
+            let reply: ServerMessage = receive(&mut control_rx).await?;
             match reply {
                 ServerMessage::MeasureStreamDone { stream, timeout } => {
                     if timeout {
@@ -472,10 +740,31 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
                     time,
                     bytes,
                 } => {
-                    bandwidth.push((stream, time, bytes));
+                    recorder_.record(RawRecord::Sample {
+                        download: false,
+                        both: stream.group == 1,
+                        stream: stream.id,
+                        point: RawPoint {
+                            time: Duration::from_micros(time.wrapping_add(server_time_offset)),
+                            bytes,
+                        },
+                    });
+                    if !recorder_.journaling() {
+                        bandwidth.push((stream, time, bytes));
+                    }
                 }
                 ServerMessage::LatencyMeasures(measures) => {
-                    latencies.extend(measures.into_iter());
+                    for measure in &measures {
+                        recorder_.record(RawRecord::PingReceived {
+                            index: measure.index,
+                            time: Duration::from_micros(
+                                measure.time.wrapping_add(server_time_offset),
+                            ),
+                        });
+                    }
+                    if !recorder_.journaling() {
+                        latencies.extend(measures.into_iter());
+                    }
                 }
                 ServerMessage::MeasurementsDone { overload } => {
                     overload_ = overload;
@@ -486,9 +775,9 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
                         .downloads
                         .lock()
                         .remove(&stream)
-                        .unwrap()
+                        .ok_or("Expected download stream")?
                         .send(())
-                        .unwrap();
+                        .map_err(|_| "Unable to notify reader of load completion")?;
                 }
                 ServerMessage::ScheduledLoads { groups: _, time } => {
                     let time = Duration::from_micros(time.wrapping_add(server_time_offset));
@@ -496,40 +785,50 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
                         .send(ScheduledLoads {
                             time: setup_start + time,
                         })
-                        .await
-                        .unwrap()
+                        .await?
                 }
-                _ => panic!("Unexpected message {:?}", reply),
+                _ => return Err(format!("Unexpected message {:?}", reply).into()),
             };
         }
 
-        (latencies, bandwidth, overload_)
+        Ok((latencies, bandwidth, overload_))
     });
 
     let ping_start_index = ping_index;
-    let ping_send = tokio::spawn(ping_send(
-        ping_index,
-        id,
-        state_rx.clone(),
-        setup_start,
-        udp_socket2.clone(),
-        ping_interval,
-        estimated_duration,
-    ));
+    let ping_send = spawn_task(
+        &failed,
+        ping_send(
+            ping_index,
+            session,
+            state_rx.clone(),
+            setup_start,
+            udp_socket2.clone(),
+            ping_interval,
+            estimated_duration,
+            recorder.clone(),
+        ),
+    );
 
-    let ping_recv = tokio::spawn(ping_recv(
-        state_rx.clone(),
-        setup_start,
-        udp_socket2.clone(),
-        ping_interval,
-        estimated_duration,
-    ));
+    let ping_recv = spawn_task(
+        &failed,
+        ping_recv(
+            state_rx.clone(),
+            setup_start,
+            udp_socket2.clone(),
+            session.token,
+            client_timestamps,
+            ping_interval,
+            estimated_duration,
+            recorder.clone(),
+        ),
+    );
 
     time::sleep(Duration::from_millis(50)).await;
 
     let start = Instant::now();
+    recorder.record(RawRecord::Start(start.duration_since(setup_start)));
 
-    state_tx.send((TestState::Grace1, start)).unwrap();
+    state_tx.send((TestState::Grace1, start))?;
     time::sleep(grace).await;
 
     let load_delay = (Duration::from_millis(50) + latency).as_micros() as u64;
@@ -543,14 +842,12 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
             },
         )
         .await?;
-        let load = scheduled_load_rx.recv().await.unwrap();
-        state_tx
-            .send((TestState::LoadFromServer, load.time))
-            .unwrap();
+        let load = scheduled_load_rx.recv().await.ok_or("Expected loads")?;
+        state_tx.send((TestState::LoadFromServer, load.time))?;
         msg(&format!("Testing download..."));
-        let _ = semaphore.acquire_many(loading_streams).await.unwrap();
+        let _ = semaphore.acquire_many(loading_streams).await?;
 
-        state_tx.send((TestState::Grace2, Instant::now())).unwrap();
+        state_tx.send((TestState::Grace2, Instant::now()))?;
         time::sleep(grace).await;
     }
 
@@ -563,10 +860,8 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
             },
         )
         .await?;
-        let load = scheduled_load_rx.recv().await.unwrap();
-        state_tx
-            .send((TestState::LoadFromClient, load.time))
-            .unwrap();
+        let load = scheduled_load_rx.recv().await.ok_or("Expected loads")?;
+        state_tx.send((TestState::LoadFromClient, load.time))?;
         msg(&format!("Testing upload..."));
 
         for _ in 0..config.streams {
@@ -574,12 +869,9 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
             send(&mut control_tx, &ClientMessage::LoadComplete { stream }).await?;
         }
 
-        let _ = upload_semaphore
-            .acquire_many(loading_streams)
-            .await
-            .unwrap();
+        let _ = upload_semaphore.acquire_many(loading_streams).await?;
 
-        state_tx.send((TestState::Grace3, Instant::now())).unwrap();
+        state_tx.send((TestState::Grace3, Instant::now()))?;
         time::sleep(grace).await;
     }
 
@@ -592,8 +884,8 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
             },
         )
         .await?;
-        let load = scheduled_load_rx.recv().await.unwrap();
-        state_tx.send((TestState::LoadFromBoth, load.time)).unwrap();
+        let load = scheduled_load_rx.recv().await.ok_or("Expected loads")?;
+        state_tx.send((TestState::LoadFromBoth, load.time))?;
         msg(&format!("Testing both download and upload..."));
 
         for _ in 0..config.streams {
@@ -601,36 +893,31 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
             send(&mut control_tx, &ClientMessage::LoadComplete { stream }).await?;
         }
 
-        let _ = semaphore.acquire_many(loading_streams).await.unwrap();
-        let _ = both_upload_semaphore
-            .acquire_many(loading_streams)
-            .await
-            .unwrap();
+        let _ = semaphore.acquire_many(loading_streams).await?;
+        let _ = both_upload_semaphore.acquire_many(loading_streams).await?;
 
-        state_tx.send((TestState::Grace4, Instant::now())).unwrap();
+        state_tx.send((TestState::Grace4, Instant::now()))?;
         time::sleep(grace).await;
     }
 
-    state_tx.send((TestState::End, Instant::now())).unwrap();
+    state_tx.send((TestState::End, Instant::now()))?;
 
     // Wait for pings to return
     time::sleep(Duration::from_millis(500)).await;
-    state_tx
-        .send((TestState::EndPingRecv, Instant::now()))
-        .unwrap();
+    state_tx.send((TestState::EndPingRecv, Instant::now()))?;
 
     let duration = start.elapsed();
 
-    let pings_sent = ping_send.await?;
+    let pings_sent = finished(ping_send).await?;
     send(&mut control_tx, &ClientMessage::StopMeasurements).await?;
     send(&mut control_tx, &ClientMessage::Done).await?;
 
-    let mut pongs = ping_recv.await?;
+    let mut pongs = finished(ping_recv).await?;
 
-    let (mut latencies, bandwidth, server_overload) = measures.await?;
+    let (mut latencies, bandwidth, server_overload) = finished(measures).await?;
 
-    let download_bytes = wait_on_download_loaders(download).await;
-    let both_download_bytes = wait_on_download_loaders(both_download).await;
+    let download_bytes = wait_on_download_loaders(download).await?;
+    let both_download_bytes = wait_on_download_loaders(both_download).await?;
 
     latencies.sort_by_key(|d| d.index);
     pongs.sort_by_key(|d| d.0.index);
@@ -724,14 +1011,6 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
         })
     });
 
-    let raw_config = RawConfig {
-        stagger: config.stream_stagger,
-        load_duration: config.load_duration,
-        grace_duration: config.grace_duration,
-        ping_interval: config.ping_interval,
-        bandwidth_interval: config.bandwidth_interval,
-    };
-
     if server_overload {
         msg(&format!(
             "Warning: Server overload detected during test. Result should be discarded."
@@ -746,86 +1025,98 @@ async fn test_async(config: Config, server: &str, msg: Msg) -> Result<RawResult,
         ));
     }
 
-    let start = start.duration_since(setup_start);
+    if let Some(journal) = journal {
+        // The measurements are only in the journal.
+        let (raw_result, error) = journal
+            .finish_result(RawRecord::End {
+                duration,
+                load_termination_timeout,
+                server_overload,
+            })
+            .await?;
+        if let Some(error) = &error {
+            msg(&format!(
+                "Unable to write raw data during the test: {}",
+                error
+            ));
+        }
+        return Ok((raw_result, error.is_none()));
+    }
 
     let raw_result = RawResult {
-        version: RawHeader::default().version,
-        generated_by: format!("Crusader {}", env!("CARGO_PKG_VERSION")),
-        config: raw_config,
-        ipv6: server.is_ipv6(),
         load_termination_timeout,
         server_overload,
-        server_latency: latency,
-        start,
+        start: start.duration_since(setup_start),
         duration,
         stream_groups: raw_streams,
         pings,
+        ..base
     };
 
-    Ok(raw_result)
+    Ok((raw_result, false))
 }
 
 async fn measure_latency(
-    id: u64,
+    session: Session,
     ping_index: &mut u64,
-    mut control_tx: &mut FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>,
-    mut control_rx: FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
+    mut control_tx: &mut FramedWrite<WriteHalf<Connection>, LengthDelimitedCodec>,
+    mut control_rx: FramedRead<ReadHalf<Connection>, LengthDelimitedCodec>,
     server: SocketAddr,
     local_udp: SocketAddr,
     setup_start: Instant,
+    failed: &Failed,
 ) -> Result<
     (
         Duration,
         u64,
-        FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
+        FramedRead<ReadHalf<Connection>, LengthDelimitedCodec>,
     ),
     Box<dyn Error>,
 > {
     send(&mut control_tx, &ClientMessage::GetMeasurements).await?;
 
-    let latencies = tokio::spawn(async move {
+    let latencies = spawn_task(failed, async move {
         let mut latencies = Vec::new();
 
         loop {
-            let reply: ServerMessage = receive(&mut control_rx).await.unwrap();
+            let reply: ServerMessage = receive(&mut control_rx).await?;
             match reply {
                 ServerMessage::LatencyMeasures(measures) => {
                     latencies.extend(measures.into_iter());
                 }
                 ServerMessage::MeasurementsDone { .. } => break,
-                _ => panic!("Unexpected message {:?}", reply),
+                _ => return Err(format!("Unexpected message {:?}", reply).into()),
             };
         }
 
-        (latencies, control_rx)
+        Ok((latencies, control_rx))
     });
 
     let udp_socket = Arc::new(net::UdpSocket::bind(local_udp).await?);
     udp_socket.connect(server).await?;
+    let timestamps = timestamp::enable(&udp_socket);
     let udp_socket2 = udp_socket.clone();
 
     let samples = 50;
 
     let ping_start_index = *ping_index;
-    let ping_send = tokio::spawn(ping_measure_send(
-        ping_start_index,
-        id,
-        setup_start,
-        udp_socket,
-        samples,
-    ));
+    let ping_send = spawn_task(
+        failed,
+        ping_measure_send(ping_start_index, session, setup_start, udp_socket, samples),
+    );
 
-    let ping_recv = tokio::spawn(ping_measure_recv(setup_start, udp_socket2, samples));
+    let ping_recv = spawn_task(
+        failed,
+        ping_measure_recv(setup_start, udp_socket2, session.token, timestamps, samples),
+    );
 
-    let (sent, recv) = join!(ping_send, ping_recv);
+    let (sent, new_ping_index) = finished(ping_send).await?;
+    *ping_index = new_ping_index;
+    let mut recv = finished(ping_recv).await?;
 
     send(&mut control_tx, &ClientMessage::StopMeasurements).await?;
 
-    let (mut latencies, control_rx) = latencies.await?;
-
-    let (sent, new_ping_index) = sent.unwrap();
-    *ping_index = new_ping_index;
-    let mut recv = recv.unwrap();
+    let (mut latencies, control_rx) = finished(latencies).await?;
 
     latencies.sort_by_key(|d| d.index);
     recv.sort_by_key(|d| d.0.index);
@@ -863,11 +1154,11 @@ async fn measure_latency(
 
 async fn ping_measure_send(
     mut index: u64,
-    id: u64,
+    session: Session,
     setup_start: Instant,
     socket: Arc<UdpSocket>,
     samples: u32,
-) -> (Vec<Duration>, u64) {
+) -> Result<(Vec<Duration>, u64), Box<dyn Error>> {
     let mut storage = Vec::with_capacity(samples as usize);
     let mut buf = [0; 64];
 
@@ -878,27 +1169,29 @@ async fn ping_measure_send(
 
         let current = setup_start.elapsed();
 
-        let ping = Ping { id, index };
+        let ping = Ping::new(session.id, index, &session.token);
 
         index += 1;
 
         let mut cursor = Cursor::new(&mut buf[..]);
-        bincode::serialize_into(&mut cursor, &ping).unwrap();
+        bincode::serialize_into(&mut cursor, &ping)?;
         let buf = &cursor.get_ref()[0..(cursor.position() as usize)];
 
-        socket.send(buf).await.unwrap();
+        socket.send(buf).await?;
 
         storage.push(current);
     }
 
-    (storage, index)
+    Ok((storage, index))
 }
 
 async fn ping_measure_recv(
     setup_start: Instant,
     socket: Arc<UdpSocket>,
+    token: SessionToken,
+    timestamps: TimestampSource,
     samples: u32,
-) -> Vec<(Ping, Duration)> {
+) -> Result<Vec<(Ping, Duration)>, Box<dyn Error>> {
     let mut storage = Vec::with_capacity(samples as usize);
     let mut buf = [0; 64];
 
@@ -907,7 +1200,7 @@ async fn ping_measure_recv(
 
     loop {
         let result = {
-            let packet = socket.recv(&mut buf).fuse();
+            let packet = timestamp::recv(&socket, &mut buf, timestamps).fuse();
             pin_mut!(packet);
 
             select! {
@@ -916,15 +1209,18 @@ async fn ping_measure_recv(
             }
         };
 
-        let current = setup_start.elapsed();
-        let len = result.unwrap();
+        let (len, received) = result?;
+        let current = received.saturating_duration_since(setup_start);
         let buf = &mut buf[..len];
-        let ping: Ping = bincode::deserialize(buf).unwrap();
+        let ping = match bincode::deserialize::<Ping>(buf) {
+            Ok(ping) if ping.is_valid(&token) => ping,
+            _ => continue,
+        };
 
         storage.push((ping, current));
     }
 
-    storage
+    Ok(storage)
 }
 
 pub fn save_raw(result: &RawResult, name: &str) -> String {
@@ -934,24 +1230,25 @@ pub fn save_raw(result: &RawResult, name: &str) -> String {
 }
 
 fn setup_loaders(
-    id: u64,
+    session: Session,
+    connector: &Connector,
     server: SocketAddr,
     count: u64,
-) -> Vec<JoinHandle<Framed<TcpStream, LengthDelimitedCodec>>> {
+    failed: &Failed,
+) -> Vec<JoinHandle<Option<Framed<Connection, LengthDelimitedCodec>>>> {
     (0..count)
         .map(|_| {
-            tokio::spawn(async move {
-                let stream = TcpStream::connect(server)
+            let connector = connector.clone();
+            spawn_task(failed, async move {
+                let stream = connector
+                    .connect(server)
                     .await
-                    .expect("unable to bind TCP socket");
-                stream.set_nodelay(true).unwrap();
+                    .map_err(|error| format!("Unable to connect loader: {}", error))?;
                 let mut stream = Framed::new(stream, codec());
-                hello_combined(&mut stream).await.unwrap();
-                send(&mut stream, &ClientMessage::Associate(id))
-                    .await
-                    .unwrap();
+                hello_combined(&mut stream).await?;
+                send(&mut stream, &ClientMessage::Associate(session)).await?;
 
-                stream
+                Ok(stream)
             })
         })
         .collect()
@@ -959,7 +1256,8 @@ fn setup_loaders(
 
 fn upload_loaders(
     all_loaders: Arc<Semaphore>,
-    id: u64,
+    session: Session,
+    connector: &Connector,
     server: SocketAddr,
     group: u32,
     config: Config,
@@ -968,16 +1266,17 @@ fn upload_loaders(
     state_rx: watch::Receiver<(TestState, Instant)>,
     state: TestState,
     done: Sender<TestStream>,
+    failed: &Failed,
 ) {
-    let loaders = setup_loaders(id, server, config.streams);
+    let loaders = setup_loaders(session, connector, server, config.streams, failed);
 
     for (i, loader) in loaders.into_iter().enumerate() {
         let mut state_rx = state_rx.clone();
         let data = data.clone();
         let all_loaders = all_loaders.clone();
         let done = done.clone();
-        tokio::spawn(async move {
-            let mut stream = loader.await.unwrap();
+        spawn_task(failed, async move {
+            let mut stream = finished(loader).await?;
 
             let delay = config.stream_stagger * i as u32 + stagger_offset;
 
@@ -995,32 +1294,21 @@ fn upload_loaders(
                     bandwidth_interval: config.bandwidth_interval.as_micros() as u64,
                 },
             )
-            .await
-            .unwrap();
-            let reply: ServerMessage = receive(&mut stream).await.unwrap();
+            .await?;
+            let reply: ServerMessage = receive(&mut stream).await?;
             match reply {
                 ServerMessage::WaitingForLoad => (),
-                _ => panic!("Unexpected message {:?}", reply),
+                _ => return Err(format!("Unexpected message {:?}", reply).into()),
             };
 
-            send(&mut stream, &ClientMessage::SendByte).await.unwrap();
+            send(&mut stream, &ClientMessage::SendByte).await?;
 
-            // Wait for a pending read byte
-            {
-                let mut stream_rx = stream.get_mut().split().0;
-                loop {
-                    let _ = stream_rx.read(&mut []).await.unwrap();
-                    match time::timeout(Duration::from_millis(10), stream_rx.peek(&mut [0])).await {
-                        Ok(Ok(1)) => break,
-                        Err(_) | Ok(Ok(_)) => (),
-                        Ok(Err(err)) => panic!("{:?}", err),
-                    }
-                }
-            }
+            // Wait for the byte from the server, which is encrypted with TLS
+            stream.get_mut().read_u8().await?;
 
             all_loaders.add_permits(1);
 
-            let start = wait_for_state(&mut state_rx, state).await + MEASURE_DELAY + delay;
+            let start = wait_for_state(&mut state_rx, state).await? + MEASURE_DELAY + delay;
 
             time::sleep_until(start).await;
 
@@ -1029,42 +1317,45 @@ fn upload_loaders(
                 data.as_ref(),
                 start + config.load_duration,
             )
-            .await
-            .unwrap();
+            .await?;
 
-            done.send(test_stream).await.unwrap();
+            done.send(test_stream).await?;
+            Ok(())
         });
     }
 }
 
 async fn wait_on_download_loaders(
-    download: Option<(Arc<Semaphore>, Vec<JoinHandle<Vec<(u64, u64)>>>)>,
-) -> Option<Vec<Vec<(u64, u64)>>> {
+    download: Option<(Arc<Semaphore>, Vec<JoinHandle<Option<Vec<(u64, u64)>>>>)>,
+) -> Result<Option<Vec<Vec<(u64, u64)>>>, Box<dyn Error>> {
     match download {
         Some((_, result)) => {
-            let bytes: Vec<_> = stream::iter(result)
-                .then(|data| async move { data.await.unwrap() })
-                .collect()
-                .await;
-            Some(bytes)
+            let mut bytes = Vec::new();
+            for data in result {
+                bytes.push(finished(data).await?);
+            }
+            Ok(Some(bytes))
         }
-        None => None,
+        None => Ok(None),
     }
 }
 
 fn download_loaders(
     state: Arc<State>,
     all_loaders: Arc<Semaphore>,
-    id: u64,
+    session: Session,
+    connector: &Connector,
     server: SocketAddr,
     group: u32,
     config: Config,
     setup_start: Instant,
     state_rx: watch::Receiver<(TestState, Instant)>,
     test_state: TestState,
-) -> (Arc<Semaphore>, Vec<JoinHandle<Vec<(u64, u64)>>>) {
+    recorder: Recorder,
+    failed: &Failed,
+) -> (Arc<Semaphore>, Vec<JoinHandle<Option<Vec<(u64, u64)>>>>) {
     let semaphore = Arc::new(Semaphore::new(0));
-    let loaders = setup_loaders(id, server, config.streams);
+    let loaders = setup_loaders(session, connector, server, config.streams, failed);
 
     let loaders = loaders
         .into_iter()
@@ -1074,9 +1365,10 @@ fn download_loaders(
             let state = state.clone();
             let semaphore = semaphore.clone();
             let all_loaders = all_loaders.clone();
+            let recorder = recorder.clone();
 
-            tokio::spawn(async move {
-                let mut stream = loader.await.unwrap();
+            spawn_task(failed, async move {
+                let mut stream = finished(loader).await?;
 
                 let mut buffer = Vec::with_capacity(512 * 1024);
                 buffer.extend((0..buffer.capacity()).map(|_| 0));
@@ -1096,21 +1388,21 @@ fn download_loaders(
                         delay: (MEASURE_DELAY + delay).as_micros() as u64,
                     },
                 )
-                .await
-                .unwrap();
+                .await?;
 
-                let reply: ServerMessage = receive(&mut stream).await.unwrap();
+                let reply: ServerMessage = receive(&mut stream).await?;
                 match reply {
                     ServerMessage::WaitingForByte => (),
-                    _ => panic!("Unexpected message {:?}", reply),
+                    _ => return Err(format!("Unexpected message {:?}", reply).into()),
                 };
 
-                stream.get_mut().write_u8(1).await.unwrap();
+                stream.get_mut().write_u8(1).await?;
+                stream.get_mut().flush().await?;
 
-                let reply: ServerMessage = receive(&mut stream).await.unwrap();
+                let reply: ServerMessage = receive(&mut stream).await?;
                 match reply {
                     ServerMessage::WaitingForLoad => (),
-                    _ => panic!("Unexpected message {:?}", reply),
+                    _ => return Err(format!("Unexpected message {:?}", reply).into()),
                 };
 
                 let stream = stream.into_inner();
@@ -1127,7 +1419,7 @@ fn download_loaders(
 
                 all_loaders.add_permits(1);
 
-                let start = wait_for_state(&mut state_rx, test_state).await + delay;
+                let start = wait_for_state(&mut state_rx, test_state).await? + delay;
 
                 time::sleep_until(start).await;
 
@@ -1139,11 +1431,20 @@ fn download_loaders(
 
                         let current_time = Instant::now();
                         let current_bytes = bytes_.load(Ordering::Acquire);
-
-                        measures.push((
-                            current_time.duration_since(setup_start).as_micros() as u64,
-                            current_bytes,
-                        ));
+                        let time = current_time.duration_since(setup_start).as_micros() as u64;
+
+                        recorder.record(RawRecord::Sample {
+                            download: true,
+                            both: group == 3,
+                            stream: i as u32,
+                            point: RawPoint {
+                                time: Duration::from_micros(time),
+                                bytes: current_bytes,
+                            },
+                        });
+                        if !recorder.journaling() {
+                            measures.push((time, current_bytes));
+                        }
 
                         if done_.load(Ordering::Acquire) {
                             break;
@@ -1159,8 +1460,7 @@ fn download_loaders(
                     start + MEASURE_DELAY + config.load_duration,
                     reading_done_rx,
                 )
-                .await
-                .unwrap();
+                .await?;
 
                 if timeout {
                     state.timeout.store(true, Ordering::SeqCst);
@@ -1170,7 +1470,7 @@ fn download_loaders(
 
                 semaphore.add_permits(1);
 
-                measures.await.unwrap()
+                Ok(measures.await?)
             })
         })
         .collect();
@@ -1180,31 +1480,44 @@ fn download_loaders(
 async fn wait_for_state(
     state_rx: &mut watch::Receiver<(TestState, Instant)>,
     state: TestState,
-) -> Instant {
+) -> Result<Instant, Box<dyn Error>> {
     loop {
         {
             let current = state_rx.borrow_and_update();
             if current.0 == state {
-                return current.1;
+                return Ok(current.1);
             }
         }
-        state_rx.changed().await.unwrap();
+        state_rx.changed().await?;
+    }
+}
+
+/// Room for the pings of a test, unless `recorder` keeps them in a journal instead.
+fn ping_storage<T>(
+    recorder: &Recorder,
+    interval: Duration,
+    estimated_duration: Duration,
+) -> Vec<T> {
+    if recorder.journaling() {
+        return Vec::new();
     }
+    Vec::with_capacity(
+        ((estimated_duration.as_secs_f64() + 2.0) * (1000.0 / interval.as_millis() as f64) * 1.5)
+            as usize,
+    )
 }
 
 async fn ping_send(
     mut ping_index: u64,
-    id: u64,
+    session: Session,
     state_rx: watch::Receiver<(TestState, Instant)>,
     setup_start: Instant,
     socket: Arc<UdpSocket>,
     interval: Duration,
     estimated_duration: Duration,
-) -> Vec<Duration> {
-    let mut storage = Vec::with_capacity(
-        ((estimated_duration.as_secs_f64() + 2.0) * (1000.0 / interval.as_millis() as f64) * 1.5)
-            as usize,
-    );
+    recorder: Recorder,
+) -> Result<Vec<Duration>, Box<dyn Error>> {
+    let mut storage = ping_storage(&recorder, interval, estimated_duration);
     let mut buf = [0; 64];
 
     let mut interval = time::interval(interval);
@@ -1218,36 +1531,40 @@ async fn ping_send(
 
         let current = setup_start.elapsed();
 
-        let ping = Ping {
-            id,
+        let ping = Ping::new(session.id, ping_index, &session.token);
+
+        recorder.record(RawRecord::PingSent {
             index: ping_index,
-        };
+            sent: current,
+        });
 
         ping_index += 1;
 
         let mut cursor = Cursor::new(&mut buf[..]);
-        bincode::serialize_into(&mut cursor, &ping).unwrap();
+        bincode::serialize_into(&mut cursor, &ping)?;
         let buf = &cursor.get_ref()[0..(cursor.position() as usize)];
 
-        socket.send(buf).await.expect("unable to udp ping");
+        socket.send(buf).await?;
 
-        storage.push(current);
+        if !recorder.journaling() {
+            storage.push(current);
+        }
     }
 
-    storage
+    Ok(storage)
 }
 
 async fn ping_recv(
     mut state_rx: watch::Receiver<(TestState, Instant)>,
     setup_start: Instant,
     socket: Arc<UdpSocket>,
+    token: SessionToken,
+    timestamps: TimestampSource,
     interval: Duration,
     estimated_duration: Duration,
-) -> Vec<(Ping, Duration)> {
-    let mut storage = Vec::with_capacity(
-        ((estimated_duration.as_secs_f64() + 2.0) * (1000.0 / interval.as_millis() as f64) * 1.5)
-            as usize,
-    );
+    recorder: Recorder,
+) -> Result<Vec<(Ping, Duration)>, Box<dyn Error>> {
+    let mut storage = ping_storage(&recorder, interval, estimated_duration);
     let mut buf = [0; 64];
 
     let end = wait_for_state(&mut state_rx, TestState::EndPingRecv).fuse();
@@ -1255,7 +1572,7 @@ async fn ping_recv(
 
     loop {
         let result = {
-            let packet = socket.recv(&mut buf).fuse();
+            let packet = timestamp::recv(&socket, &mut buf, timestamps).fuse();
             pin_mut!(packet);
 
             select! {
@@ -1264,15 +1581,24 @@ async fn ping_recv(
             }
         };
 
-        let current = setup_start.elapsed();
-        let len = result.unwrap();
+        let (len, received) = result?;
+        let current = received.saturating_duration_since(setup_start);
         let buf = &mut buf[..len];
-        let ping: Ping = bincode::deserialize(buf).unwrap();
+        let ping = match bincode::deserialize::<Ping>(buf) {
+            Ok(ping) if ping.is_valid(&token) => ping,
+            _ => continue,
+        };
 
-        storage.push((ping, current));
+        recorder.record(RawRecord::PingReturned {
+            index: ping.index,
+            time: current,
+        });
+        if !recorder.journaling() {
+            storage.push((ping, current));
+        }
     }
 
-    storage
+    Ok(storage)
 }
 
 pub fn timed(name: &str) -> String {
@@ -1297,21 +1623,57 @@ pub(crate) fn unique(name: &str, ext: &str) -> String {
     }
 }
 
-pub fn test(config: Config, plot: PlotConfig, host: &str) {
+/// Runs a test and saves the result and a plot of it. The measured fields of
+/// `metadata` are filled in by the test.
+pub fn test(config: Config, plot: PlotConfig, host: &str, metadata: RawMetadata) {
     let rt = tokio::runtime::Runtime::new().unwrap();
-    let result = rt
-        .block_on(test_async(config, host, Arc::new(|msg| println!("{msg}"))))
-        .unwrap();
-    println!("Writing data...");
-    let raw = save_raw(&result, "data");
+    let journal = config.incremental.then(|| unique("data", "crr"));
+    if let Some(journal) = &journal {
+        println!("Writing raw data to {} during the test", journal);
+    }
+    let (result, journaled) = rt
+        .block_on(test_async(
+            config,
+            host,
+            metadata,
+            journal.as_deref().map(Path::new),
+            Arc::new(|msg| println!("{msg}")),
+        ))
+        .unwrap_or_else(|error| match &journal {
+            Some(journal) => panic!("Test failed: {}. Partial raw data is in {}", error, journal),
+            None => panic!("Test failed: {}", error),
+        });
+    let raw = match journal.filter(|_| journaled) {
+        Some(journal) => journal,
+        None => {
+            println!("Writing data...");
+            save_raw(&result, "data")
+        }
+    };
     println!("Saved raw data as {}", raw);
     let file = save_graph(&plot, &result.to_test_result(), "plot");
     println!("Saved plot as {}", file);
 }
 
+/// Runs a test, returning its error so the caller can carry on. The result is
+/// written to `journal` during the test if given, and whether all of it made it
+/// there is returned with it.
+pub(crate) fn run_test(
+    config: Config,
+    host: &str,
+    metadata: RawMetadata,
+    journal: Option<&Path>,
+) -> Result<(RawResult, bool), String> {
+    let rt = tokio::runtime::Runtime::new().map_err(|error| error.to_string())?;
+    let msg = Arc::new(|msg: &str| println!("{msg}"));
+    rt.block_on(test_async(config, host, metadata, journal, msg))
+        .map_err(|error| error.to_string())
+}
+
 pub fn test_callback(
     config: Config,
     host: &str,
+    metadata: RawMetadata,
     msg: Arc<dyn Fn(&str) + Send + Sync>,
     done: Box<dyn FnOnce(Option<Result<RawResult, String>>) + Send>,
 ) -> oneshot::Sender<()> {
@@ -1322,8 +1684,9 @@ pub fn test_callback(
 
         done(rt.block_on(async move {
             let mut result = task::spawn(async move {
-                test_async(config, &host, msg)
+                test_async(config, &host, metadata, None, msg)
                     .await
+                    .map(|(result, _)| result)
                     .map_err(|error| error.to_string())
             })
             .fuse();
@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use {
+    std::mem,
+    std::os::unix::io::AsRawFd,
+    std::ptr,
+    std::time::{Duration, SystemTime, UNIX_EPOCH},
+    tokio::io::Interest,
+};
+
+/// Where the receive times of UDP pings come from. Send times are always taken in
+/// user space just before the ping is sent.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum TimestampSource {
+    /// Taken in user space once the receiving task has woken up.
+    #[default]
+    Software,
+    /// Taken by the kernel when the packet arrived (`SO_TIMESTAMPNS`).
+    Kernel,
+}
+
+impl TimestampSource {
+    pub(crate) fn min(self, other: TimestampSource) -> TimestampSource {
+        if self == TimestampSource::Kernel && other == TimestampSource::Kernel {
+            TimestampSource::Kernel
+        } else {
+            TimestampSource::Software
+        }
+    }
+}
+
+impl fmt::Display for TimestampSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimestampSource::Software => write!(f, "software"),
+            TimestampSource::Kernel => write!(f, "kernel receive"),
+        }
+    }
+}
+
+/// Tries to enable kernel receive timestamps on `socket`, returning the source
+/// that `recv` and `recv_from` will use for it.
+pub(crate) fn enable(socket: &UdpSocket) -> TimestampSource {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let enable: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_TIMESTAMPNS,
+                &enable as *const libc::c_int as *const libc::c_void,
+                mem::size_of_val(&enable) as libc::socklen_t,
+            )
+        };
+        if result == 0 {
+            return TimestampSource::Kernel;
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = socket;
+
+    TimestampSource::Software
+}
+
+pub(crate) async fn recv(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    source: TimestampSource,
+) -> io::Result<(usize, Instant)> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if source == TimestampSource::Kernel {
+        return recv_kernel(socket, buf)
+            .await
+            .map(|(len, _, time)| (len, time));
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = source;
+
+    let len = socket.recv(buf).await?;
+    Ok((len, Instant::now()))
+}
+
+pub(crate) async fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    source: TimestampSource,
+) -> io::Result<(usize, SocketAddr, Instant)> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if source == TimestampSource::Kernel {
+        return recv_kernel(socket, buf).await;
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = source;
+
+    let (len, src) = socket.recv_from(buf).await?;
+    Ok((len, src, Instant::now()))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+async fn recv_kernel(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Instant)> {
+    loop {
+        socket.readable().await?;
+
+        match socket.try_io(Interest::READABLE, || recvmsg(socket, buf)) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn recvmsg(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Instant)> {
+    // Use `u64` for the alignment `cmsghdr` requires.
+    let mut control = [0u64; 16];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+
+    let ((len, timestamp), addr) = unsafe {
+        socket2::SockAddr::init(|storage, storage_len| {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_name = storage.cast();
+            msg.msg_namelen = *storage_len;
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = mem::size_of_val(&control) as _;
+
+            let len = libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            *storage_len = msg.msg_namelen;
+
+            let mut timestamp = None;
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET
+                    && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPNS
+                {
+                    timestamp = Some(ptr::read_unaligned(
+                        libc::CMSG_DATA(cmsg) as *const libc::timespec
+                    ));
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+
+            Ok((len as usize, timestamp))
+        })?
+    };
+
+    let addr = addr
+        .as_socket()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+
+    let now = Instant::now();
+    let time = timestamp
+        .and_then(|timestamp| to_instant(timestamp, now))
+        .unwrap_or(now);
+
+    Ok((len, addr, time))
+}
+
+/// Maps a `CLOCK_REALTIME` kernel timestamp onto the monotonic `Instant` clock
+/// by measuring how long ago it was.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn to_instant(timestamp: libc::timespec, now: Instant) -> Option<Instant> {
+    let kernel = UNIX_EPOCH
+        + Duration::new(
+            timestamp.tv_sec.try_into().ok()?,
+            timestamp.tv_nsec.try_into().ok()?,
+        );
+    let age = SystemTime::now().duration_since(kernel).ok()?;
+    now.checked_sub(age)
+}
@@ -0,0 +1,346 @@
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{
+    Certificate, ClientConfig, CommonState, PrivateKey, RootCertStore, ServerConfig, ServerName,
+};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+
+use crate::file_format::RawTls;
+
+/// How the client verifies the server's certificate.
+#[derive(Copy, Clone, Debug)]
+pub enum ClientTls {
+    /// Verify against the system's root certificates.
+    System,
+    /// Only accept a certificate with this SHA-256 fingerprint.
+    Pinned([u8; 32]),
+}
+
+impl ClientTls {
+    /// Parses a hex encoded SHA-256 fingerprint, optionally separated by colons.
+    pub fn pinned(fingerprint: &str) -> Result<Self, Box<dyn Error>> {
+        let digits: Vec<u8> = fingerprint
+            .chars()
+            .filter(|c| *c != ':')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<_>>()
+            .ok_or("Invalid certificate fingerprint")?;
+        if digits.len() != 64 {
+            return Err("Certificate fingerprint must be 32 bytes".into());
+        }
+        let mut bytes = [0; 32];
+        for (byte, pair) in bytes.iter_mut().zip(digits.chunks(2)) {
+            *byte = pair[0] << 4 | pair[1];
+        }
+        Ok(ClientTls::Pinned(bytes))
+    }
+
+    fn config(&self) -> Result<ClientConfig, Box<dyn Error>> {
+        let builder = ClientConfig::builder().with_safe_defaults();
+        let config = match self {
+            ClientTls::System => {
+                let mut roots = RootCertStore::empty();
+                for cert in rustls_native_certs::load_native_certs()? {
+                    roots.add(&Certificate(cert.0)).ok();
+                }
+                builder.with_root_certificates(roots).with_no_client_auth()
+            }
+            ClientTls::Pinned(fingerprint) => builder
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier(*fingerprint)))
+                .with_no_client_auth(),
+        };
+        Ok(config)
+    }
+}
+
+struct PinnedVerifier([u8; 32]);
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.0 {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificateData(
+                "Certificate does not match the pinned fingerprint".to_owned(),
+            ))
+        }
+    }
+}
+
+fn fingerprint(cert: &Certificate) -> [u8; 32] {
+    Sha256::digest(&cert.0).into()
+}
+
+/// A certificate and key used by the server.
+#[derive(Clone)]
+pub struct ServerTls {
+    config: Arc<ServerConfig>,
+    fingerprint: [u8; 32],
+}
+
+impl ServerTls {
+    /// Loads a PEM encoded certificate chain and private key.
+    pub fn load(cert: &Path, key: &Path) -> Result<Self, Box<dyn Error>> {
+        let certs: Vec<_> = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+
+        let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key)?))?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+                _ => None,
+            })
+            .ok_or("No private key found")?;
+
+        Self::new(certs, key)
+    }
+
+    /// Generates a self-signed certificate. Clients have to pin its fingerprint.
+    pub fn self_signed() -> Result<Self, Box<dyn Error>> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])?;
+        Self::new(
+            vec![Certificate(cert.serialize_der()?)],
+            PrivateKey(cert.serialize_private_key_der()),
+        )
+    }
+
+    fn new(certs: Vec<Certificate>, key: PrivateKey) -> Result<Self, Box<dyn Error>> {
+        let fingerprint = fingerprint(certs.first().ok_or("No certificate found")?);
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        Ok(ServerTls {
+            config: Arc::new(config),
+            fingerprint,
+        })
+    }
+
+    /// The SHA-256 fingerprint of the certificate in hex.
+    pub fn fingerprint(&self) -> String {
+        self.fingerprint
+            .iter()
+            .fold(String::new(), |mut result, byte| {
+                write!(result, "{:02x}", byte).unwrap();
+                result
+            })
+    }
+}
+
+/// Opens control and loader connections, with TLS if configured.
+#[derive(Clone)]
+pub(crate) struct Connector {
+    tls: Option<(TlsConnector, ServerName)>,
+}
+
+impl Connector {
+    pub fn new(tls: Option<ClientTls>, host: &str) -> Result<Self, Box<dyn Error>> {
+        let tls = tls
+            .map(|tls| -> Result<_, Box<dyn Error>> {
+                let name = match tls {
+                    ClientTls::System => ServerName::try_from(host)
+                        .map_err(|_| format!("Invalid TLS server name {}", host))?,
+                    // The name isn't checked for pinned certificates.
+                    ClientTls::Pinned(..) => ServerName::try_from(host)
+                        .or_else(|_| ServerName::try_from("localhost"))
+                        .unwrap(),
+                };
+                Ok((TlsConnector::from(Arc::new(tls.config()?)), name))
+            })
+            .transpose()?;
+        Ok(Connector { tls })
+    }
+
+    pub async fn connect(&self, addr: impl tokio::net::ToSocketAddrs) -> io::Result<Connection> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        match &self.tls {
+            Some((connector, name)) => {
+                let stream = connector.connect(name.clone(), stream).await?;
+                Ok(Connection::Tls(Box::new(stream.into())))
+            }
+            None => Ok(Connection::Plain(stream)),
+        }
+    }
+}
+
+/// How long a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub(crate) struct Acceptor {
+    tls: Option<TlsAcceptor>,
+}
+
+impl Acceptor {
+    pub fn new(tls: Option<ServerTls>) -> Self {
+        Acceptor {
+            tls: tls.map(|tls| TlsAcceptor::from(tls.config)),
+        }
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<Connection> {
+        stream.set_nodelay(true)?;
+        match &self.tls {
+            Some(acceptor) => {
+                let stream = time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                    .await
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out")
+                    })??;
+                Ok(Connection::Tls(Box::new(stream.into())))
+            }
+            None => Ok(Connection::Plain(stream)),
+        }
+    }
+}
+
+/// A TCP connection which may be wrapped in TLS.
+pub(crate) enum Connection {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection {
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Connection::Plain(stream) => stream,
+            Connection::Tls(stream) => stream.get_ref().0,
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    /// Describes the negotiated TLS parameters.
+    pub fn tls(&self) -> Option<RawTls> {
+        match self {
+            Connection::Plain(..) => None,
+            Connection::Tls(stream) => Some(describe(stream.get_ref().1)),
+        }
+    }
+}
+
+fn describe(state: &CommonState) -> RawTls {
+    let suite = state.negotiated_cipher_suite();
+    let cipher_suite = suite
+        .map(|suite| format!("{:?}", suite.suite()))
+        .unwrap_or_default();
+
+    // Each record has a 5 byte header and a 16 byte authentication tag.
+    // TLS 1.3 adds the inner content type and TLS 1.2 AES-GCM an explicit nonce.
+    let record_overhead = match state.protocol_version() {
+        Some(rustls::ProtocolVersion::TLSv1_3) => 5 + 16 + 1,
+        _ if cipher_suite.contains("CHACHA20") => 5 + 16,
+        _ => 5 + 16 + 8,
+    };
+
+    RawTls {
+        protocol: state
+            .protocol_version()
+            .map(|version| format!("{:?}", version))
+            .unwrap_or_default(),
+        cipher_suite,
+        record_overhead,
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pinned(fingerprint: &str) -> Result<[u8; 32], String> {
+        match ClientTls::pinned(fingerprint) {
+            Ok(ClientTls::Pinned(bytes)) => Ok(bytes),
+            Ok(ClientTls::System) => unreachable!(),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    #[test]
+    fn pinned_parses_fingerprints() {
+        let bytes: Vec<u8> = (0..32).collect();
+        let plain: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let colons: Vec<_> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        assert_eq!(pinned(&plain).unwrap().to_vec(), bytes);
+        assert_eq!(pinned(&colons.join(":")).unwrap().to_vec(), bytes);
+    }
+
+    #[test]
+    fn pinned_rejects_invalid_fingerprints() {
+        let length = Err("Certificate fingerprint must be 32 bytes".to_owned());
+        assert_eq!(pinned("abc"), length);
+        assert_eq!(pinned(&"a".repeat(63)), length);
+        assert_eq!(pinned(&"a".repeat(66)), length);
+        assert_eq!(
+            pinned(&"g".repeat(64)),
+            Err("Invalid certificate fingerprint".to_owned())
+        );
+    }
+}
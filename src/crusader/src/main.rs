@@ -1,10 +1,17 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::time::Duration;
 
 use clap::{Parser, Subcommand};
-use crusader_lib::file_format::RawResult;
-use crusader_lib::protocol;
+use crusader_lib::edit::Anonymization;
+use crusader_lib::file_format::{RawMetadata, RawResult};
+use crusader_lib::latency;
+use crusader_lib::plot::{Phase, PlotFormat, RateFilter, Smoothing, TestResult};
+use crusader_lib::protocol::{self, PreSharedKey};
+use crusader_lib::schedule::{self, Cron, Schedule};
+use crusader_lib::serve;
 use crusader_lib::test::{Config, PlotConfig};
+use crusader_lib::tls::{ClientTls, ServerTls};
 
 #[derive(Parser)]
 struct Cli {
@@ -18,19 +25,92 @@ struct PlotArgs {
     plot_transferred: bool,
     #[clap(long)]
     plot_split_bandwidth: bool,
+    /// Add a chart of Jain's fairness index of the streams
+    #[clap(long)]
+    plot_fairness: bool,
+    /// Plot each lost ping instead of the share of pings lost over time
+    #[clap(long)]
+    plot_loss_ticks: bool,
+    /// Add latency CDF charts for each phase
+    #[clap(long)]
+    plot_cdf: bool,
+    /// Add latency histograms for each phase
+    #[clap(long)]
+    plot_histogram: bool,
+    /// Mark the median and 99th percentile latency of each phase
+    #[clap(long)]
+    plot_percentiles: bool,
+    /// Use a logarithmic latency axis
+    #[clap(long)]
+    plot_log_latency: bool,
+    /// Fixed maximum of the latency axis
+    #[clap(long, value_name = "MILLISECONDS", parse(try_from_str = positive_time))]
+    plot_max_latency: Option<f64>,
+    /// Fixed maximum of bandwidth axes
+    #[clap(long, value_name = "MBPS")]
+    plot_max_bandwidth: Option<f64>,
+    /// Smooth bandwidth with a moving average over this window
+    #[clap(long, value_name = "MILLISECONDS", parse(try_from_str = positive_time))]
+    plot_average: Option<f64>,
+    /// Smooth bandwidth with an exponentially weighted moving average with this time constant
+    #[clap(
+        long,
+        value_name = "MILLISECONDS",
+        conflicts_with = "plot-average",
+        parse(try_from_str = positive_time)
+    )]
+    plot_ewma: Option<f64>,
+    /// Resample bandwidth to this interval
+    #[clap(long, value_name = "MILLISECONDS", parse(try_from_str = positive_time))]
+    plot_interval: Option<f64>,
+    /// Only plot from this time after the start of the test
+    #[clap(long, value_name = "SECONDS", parse(try_from_str = time))]
+    plot_from: Option<f64>,
+    /// Only plot until this time after the start of the test
+    #[clap(long, value_name = "SECONDS", parse(try_from_str = time))]
+    plot_to: Option<f64>,
     #[clap(long)]
     plot_width: Option<u64>,
     #[clap(long)]
     plot_height: Option<u64>,
+    /// Image format of plots, unless the output file name has an extension
+    #[clap(
+        long,
+        default_value = "png",
+        value_name = "png|svg|pdf",
+        parse(try_from_str)
+    )]
+    plot_format: PlotFormat,
 }
 
 impl PlotArgs {
     fn config(&self) -> PlotConfig {
+        let millis = |millis: f64| Duration::from_secs_f64(millis / 1000.0);
+        let smoothing = match (self.plot_average, self.plot_ewma) {
+            (Some(window), _) => Smoothing::MovingAverage(millis(window)),
+            (_, Some(time_constant)) => Smoothing::Ewma(millis(time_constant)),
+            _ => Smoothing::None,
+        };
         PlotConfig {
             transferred: self.plot_transferred,
             split_bandwidth: self.plot_split_bandwidth,
+            fairness: self.plot_fairness,
+            loss_ticks: self.plot_loss_ticks,
+            cdf: self.plot_cdf,
+            histogram: self.plot_histogram,
+            percentiles: self.plot_percentiles,
+            log_latency: self.plot_log_latency,
+            max_latency: self.plot_max_latency.map(millis),
+            max_bandwidth: self.plot_max_bandwidth,
+            rates: RateFilter {
+                interval: self.plot_interval.map(millis),
+                smoothing,
+            },
+            crop_start: self.plot_from.map(Duration::from_secs_f64),
+            crop_end: self.plot_to.map(Duration::from_secs_f64),
             width: self.plot_width,
             height: self.plot_height,
+            format: self.plot_format,
         }
     }
 }
@@ -40,6 +120,18 @@ enum Commands {
     Serve {
         #[clap(long, default_value_t = protocol::PORT)]
         port: u16,
+        /// Only serve clients which know this key
+        #[clap(long, value_name = "KEY")]
+        psk: Option<String>,
+        /// Use TLS with this PEM encoded certificate chain
+        #[clap(long, value_name = "FILE", requires = "tls-key")]
+        tls_cert: Option<PathBuf>,
+        /// Private key for the TLS certificate
+        #[clap(long, value_name = "FILE", requires = "tls-cert")]
+        tls_key: Option<PathBuf>,
+        /// Use TLS with a generated certificate, which clients have to pin
+        #[clap(long, conflicts_with = "tls-cert")]
+        tls_self_signed: bool,
     },
     Test {
         server: String,
@@ -63,14 +155,365 @@ enum Commands {
         latency_sample_rate: u64,
         #[clap(long, default_value_t = 20, value_name = "MILLISECONDS")]
         bandwidth_sample_rate: u64,
+        /// Key the server requires
+        #[clap(long, value_name = "KEY")]
+        psk: Option<String>,
+        /// Use TLS, verifying the server against the system's root certificates
+        #[clap(long)]
+        tls: bool,
+        /// Use TLS, only accepting a server certificate with this SHA-256 fingerprint
+        #[clap(long, value_name = "FINGERPRINT")]
+        tls_pin: Option<String>,
+        /// Run the test this many times, saving the results into a session directory
+        #[clap(long, value_name = "N")]
+        repeat: Option<u64>,
+        /// Time between the start of repeated runs, like `15m` or `1h 30m`
+        #[clap(long, value_name = "DURATION", parse(try_from_str = humantime::parse_duration))]
+        interval: Option<Duration>,
+        /// Run on a cron-like schedule of minute, hour, day of month, month and day of week, like `*/15 * * * *`
+        #[clap(long, value_name = "CRON", parse(try_from_str))]
+        schedule: Option<Cron>,
+        /// Attempts at each repeated run before it's recorded as failed
+        #[clap(long, default_value_t = 3)]
+        attempts: u64,
+        /// Don't save plots of repeated runs
+        #[clap(long)]
+        no_plot: bool,
+        /// Write the result file while the test runs, so it survives the test being
+        /// interrupted. Useful for long tests
+        #[clap(long)]
+        incremental: bool,
+        /// Short description of the test, shown on plots
+        #[clap(long)]
+        label: Option<String>,
+        /// Notes stored with the result, can be given multiple times
+        #[clap(long = "note", value_name = "NOTE")]
+        notes: Vec<String>,
         #[clap(flatten)]
         plot: PlotArgs,
     },
+    /// Record the latency to a server without loading the network, until Ctrl-C is pressed
+    Monitor {
+        server: String,
+        #[clap(long, default_value_t = protocol::PORT)]
+        port: u16,
+        #[clap(long, default_value_t = 5, value_name = "MILLISECONDS")]
+        latency_sample_rate: u64,
+        /// Stop after this long, like `8h` or `30m`
+        #[clap(long, value_name = "DURATION", parse(try_from_str = humantime::parse_duration))]
+        duration: Option<Duration>,
+        /// Key the server requires
+        #[clap(long, value_name = "KEY")]
+        psk: Option<String>,
+        /// Use TLS, verifying the server against the system's root certificates
+        #[clap(long)]
+        tls: bool,
+        /// Use TLS, only accepting a server certificate with this SHA-256 fingerprint
+        #[clap(long, value_name = "FINGERPRINT")]
+        tls_pin: Option<String>,
+        /// Short description of the session, shown on plots
+        #[clap(long)]
+        label: Option<String>,
+    },
     Plot {
         data: PathBuf,
         #[clap(flatten)]
         plot: PlotArgs,
     },
+    /// Plot multiple results on shared axes
+    Compare {
+        #[clap(required = true, min_values = 2)]
+        data: Vec<PathBuf>,
+        /// Legend label for each result in order, instead of its file name
+        #[clap(long = "label", value_name = "LABEL")]
+        labels: Vec<String>,
+        #[clap(long, default_value = "compare")]
+        output: String,
+        #[clap(flatten)]
+        plot: PlotArgs,
+    },
+    /// Save results as a HTML page with interactive charts
+    Report {
+        #[clap(required = true)]
+        data: Vec<PathBuf>,
+        /// Label for each result in order, instead of its file name
+        #[clap(long = "label", value_name = "LABEL")]
+        labels: Vec<String>,
+        /// Defaults to the name of the file with a single result
+        #[clap(long)]
+        output: Option<String>,
+    },
+    /// Rewrite result files in the latest format, keeping the originals as backups
+    Upgrade {
+        #[clap(required = true)]
+        data: Vec<PathBuf>,
+    },
+    /// Join, crop or filter results and save them as a new result file
+    Edit {
+        /// Results from the same session to join in order
+        #[clap(required = true)]
+        data: Vec<PathBuf>,
+        /// Drop everything before this time
+        #[clap(long, value_name = "SECONDS", parse(try_from_str = time))]
+        from: Option<f64>,
+        /// Drop everything after this time
+        #[clap(long, value_name = "SECONDS", parse(try_from_str = time))]
+        to: Option<f64>,
+        /// Keep only these loads: download, upload or both
+        #[clap(long, value_name = "LOAD")]
+        keep: Vec<Phase>,
+        /// Drop these loads: download, upload or both
+        #[clap(long, value_name = "LOAD", conflicts_with = "keep")]
+        drop: Vec<Phase>,
+        /// Defaults to the name of the first file
+        #[clap(long)]
+        output: Option<String>,
+    },
+    /// Remove addresses, hostnames, labels and notes from result files before sharing them
+    Anonymize {
+        #[clap(required = true)]
+        data: Vec<PathBuf>,
+        /// Replace them with hashes instead, which match across the given files
+        #[clap(long)]
+        hash: bool,
+        /// Secret for the hashes, so they also match across separate runs
+        #[clap(long, value_name = "SECRET")]
+        hash_key: Option<String>,
+        /// Move the start time by this many days
+        #[clap(
+            long,
+            value_name = "DAYS",
+            allow_hyphen_values = true,
+            parse(try_from_str = days)
+        )]
+        shift_days: Option<chrono::Duration>,
+    },
+    /// Convert flent data files to result files
+    Import {
+        #[clap(required = true)]
+        data: Vec<PathBuf>,
+    },
+    /// Convert result files to flent data files
+    Export {
+        #[clap(required = true)]
+        data: Vec<PathBuf>,
+    },
+    /// Show what a result file contains and check it for problems
+    Info { data: PathBuf },
+    /// Look for servers on the local network
+    Discover {
+        #[clap(long, default_value_t = protocol::PORT)]
+        port: u16,
+        #[clap(
+            long,
+            default_value_t = 1.0,
+            value_name = "SECONDS",
+            parse(try_from_str = positive_time)
+        )]
+        timeout: f64,
+    },
+}
+
+/// Parses a time in any unit which is zero or more.
+fn time(value: &str) -> Result<f64, String> {
+    let time: f64 = value.parse().map_err(|error| format!("{}", error))?;
+    Duration::try_from_secs_f64(time)
+        .map_err(|_| format!("Expected a time of zero or more, got {}", value))?;
+    Ok(time)
+}
+
+/// Parses a time in any unit which is more than zero.
+fn positive_time(value: &str) -> Result<f64, String> {
+    match time(value) {
+        Ok(time) if time > 0.0 => Ok(time),
+        _ => Err(format!("Expected a time of more than zero, got {}", value)),
+    }
+}
+
+/// Parses a number of days, which may be negative.
+fn days(value: &str) -> Result<chrono::Duration, String> {
+    // `chrono::Duration` is limited to `i64::MAX` milliseconds either way.
+    const MAX_DAYS: u64 = i64::MAX as u64 / (24 * 60 * 60 * 1000);
+    let days: i64 = value.parse().map_err(|error| format!("{}", error))?;
+    if days.unsigned_abs() > MAX_DAYS {
+        return Err(format!("{} days is out of range", value));
+    }
+    Ok(chrono::Duration::days(days))
+}
+
+/// Loads a result file, or converts a flent data file.
+fn load(path: &Path) -> RawResult {
+    let name = path.to_string_lossy();
+    if name.ends_with(".flent") || name.ends_with(".flent.gz") {
+        return crusader_lib::flent::import(path)
+            .unwrap_or_else(|error| panic!("Unable to import {}: {}", path.display(), error));
+    }
+    RawResult::load(path)
+        .unwrap_or_else(|error| panic!("Unable to load {}: {}", path.display(), error))
+}
+
+/// Loads results along with their label, which defaults to the file name.
+fn load_results(data: &[PathBuf], labels: &[String]) -> (Vec<String>, Vec<TestResult>) {
+    let results = data
+        .iter()
+        .map(|path| load(path).to_test_result())
+        .collect();
+    let names = data
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            labels.get(i).cloned().unwrap_or_else(|| {
+                path.file_stem()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            })
+        })
+        .collect();
+    (names, results)
+}
+
+fn info(path: &Path) {
+    let result = match RawResult::load(path) {
+        Ok(result) => result,
+        Err(error) => {
+            eprintln!("Unable to load {}: {}", path.display(), error);
+            process::exit(1);
+        }
+    };
+
+    let seconds = |duration: Duration| format!("{:.2} s", duration.as_secs_f64());
+    let millis = |duration: Duration| format!("{:.2} ms", duration.as_secs_f64() * 1000.0);
+
+    println!("File format version: {}", result.version);
+    let inferred = &result.inferred;
+    if let Some(version) = inferred.original_version {
+        if version != result.version {
+            println!("Upgraded from format version: {}", version);
+        }
+    }
+    if !inferred.fields().is_empty() {
+        println!("Inferred: {}", inferred.fields().join(", "));
+    }
+    if !result.generated_by.is_empty() {
+        println!("Generated by: {}", result.generated_by);
+    }
+
+    let metadata = &result.metadata;
+    if let Some(time) = metadata.start_time {
+        println!("Started: {}", time.format("%Y-%m-%d %H:%M:%S UTC"));
+    }
+    let fields = [
+        ("Server", &metadata.server),
+        ("Server address", &metadata.server_address),
+        ("Server version", &metadata.server_version),
+        ("Client hostname", &metadata.client_hostname),
+        ("Client OS", &metadata.client_os),
+        ("Client version", &metadata.client_version),
+        ("Label", &metadata.label),
+        ("Notes", &metadata.notes),
+    ];
+    for (name, value) in fields {
+        if let Some(value) = value {
+            println!("{}: {}", name, value);
+        }
+    }
+    for edit in &metadata.edits {
+        println!("Edited: {}", edit);
+    }
+
+    println!(
+        "Duration: {}, loads start at {}",
+        seconds(result.duration),
+        seconds(result.start)
+    );
+    println!(
+        "Load duration: {}, grace duration: {}, stream stagger: {}",
+        seconds(result.config.load_duration),
+        seconds(result.config.grace_duration),
+        seconds(result.config.stagger)
+    );
+    println!(
+        "Ping interval: {}, bandwidth interval: {}",
+        millis(result.config.ping_interval),
+        millis(result.config.bandwidth_interval)
+    );
+    println!(
+        "Connections: {} over IPv{}{}",
+        result.streams(),
+        if result.ipv6 { 6 } else { 4 },
+        result
+            .config
+            .tls
+            .as_ref()
+            .map(|tls| format!(" using {} with {}", tls.protocol, tls.cipher_suite))
+            .unwrap_or_default()
+    );
+    println!("Server latency: {}", millis(result.server_latency));
+
+    println!("Stream groups: {}", result.stream_groups.len());
+    let test_result = result.to_test_result();
+    for (group, test_group) in result.stream_groups.iter().zip(&test_result.stream_groups) {
+        println!(
+            "  {}: {} streams, {} samples",
+            group.name(),
+            group.streams.len(),
+            group
+                .streams
+                .iter()
+                .map(|stream| stream.data.len())
+                .sum::<usize>()
+        );
+        for (i, stats) in test_result.stream_stats(test_group).iter().enumerate() {
+            println!(
+                "    Stream {}: {:.2} Mbps mean, {:.2} Mbps min, {:.2} Mbps max",
+                i + 1,
+                stats.mean,
+                stats.min,
+                stats.max
+            );
+        }
+    }
+
+    let answered = result
+        .pings
+        .iter()
+        .filter(|ping| ping.latency.and_then(|latency| latency.total).is_some())
+        .count();
+    let lost_down = result
+        .pings
+        .iter()
+        .filter(|ping| ping.latency.is_some_and(|latency| latency.total.is_none()))
+        .count();
+    println!(
+        "Pings: {} sent, {} answered, {} lost",
+        result.pings.len(),
+        answered,
+        result.pings.len() - answered
+    );
+    if !result.inferred.loss_direction {
+        println!(
+            "  {} lost on the way to the server, {} on the way back",
+            result.pings.len() - answered - lost_down,
+            lost_down
+        );
+    }
+
+    if result.server_overload {
+        println!("Warning: Server overload detected during test");
+    }
+    if result.load_termination_timeout {
+        println!("Warning: Load termination timed out");
+    }
+
+    let problems = result.problems();
+    if problems.is_empty() {
+        println!("No problems found");
+    } else {
+        println!("Problems:");
+        for problem in problems {
+            println!("  {}", problem);
+        }
+    }
 }
 
 fn main() {
@@ -92,6 +535,17 @@ fn main() {
             stream_stagger,
             grace_duration,
             load_duration,
+            ref psk,
+            tls,
+            ref tls_pin,
+            repeat,
+            interval,
+            ref schedule,
+            attempts,
+            no_plot,
+            incremental,
+            ref label,
+            ref notes,
         } => {
             let mut config = Config {
                 port,
@@ -104,6 +558,14 @@ fn main() {
                 both: true,
                 ping_interval: Duration::from_millis(latency_sample_rate),
                 bandwidth_interval: Duration::from_millis(bandwidth_sample_rate),
+                psk: psk.as_deref().map(PreSharedKey::new),
+                tls: match tls_pin {
+                    Some(fingerprint) => {
+                        Some(ClientTls::pinned(fingerprint).expect("Invalid TLS pin"))
+                    }
+                    None => tls.then_some(ClientTls::System),
+                },
+                incremental,
             };
 
             if download || upload || both {
@@ -112,13 +574,81 @@ fn main() {
                 config.both = both;
             }
 
-            crusader_lib::test::test(config, plot.config(), server);
+            let metadata = RawMetadata {
+                label: label.clone(),
+                notes: (!notes.is_empty()).then(|| notes.join("\n")),
+                ..RawMetadata::client()
+            };
+
+            if repeat.is_some() || interval.is_some() || schedule.is_some() {
+                let schedule = Schedule {
+                    runs: repeat,
+                    interval: interval.unwrap_or_default(),
+                    cron: schedule.clone(),
+                    attempts: attempts.max(1),
+                    retry_delay: Duration::from_secs(10),
+                };
+                schedule::run(
+                    config,
+                    (!no_plot).then(|| plot.config()),
+                    server,
+                    metadata,
+                    &schedule,
+                )
+                .expect("Unable to run session");
+            } else {
+                crusader_lib::test::test(config, plot.config(), server, metadata);
+            }
         }
-        Commands::Serve { port } => {
-            crusader_lib::serve::serve(*port);
+        &Commands::Monitor {
+            ref server,
+            port,
+            latency_sample_rate,
+            duration,
+            ref psk,
+            tls,
+            ref tls_pin,
+            ref label,
+        } => {
+            let config = latency::Config {
+                port,
+                ping_interval: Duration::from_millis(latency_sample_rate),
+                psk: psk.as_deref().map(PreSharedKey::new),
+                tls: match tls_pin {
+                    Some(fingerprint) => {
+                        Some(ClientTls::pinned(fingerprint).expect("Invalid TLS pin"))
+                    }
+                    None => tls.then_some(ClientTls::System),
+                },
+            };
+            let metadata = RawMetadata {
+                label: label.clone(),
+                ..RawMetadata::client()
+            };
+            latency::monitor(config, server, metadata, duration);
+        }
+        Commands::Serve {
+            port,
+            psk,
+            tls_cert,
+            tls_key,
+            tls_self_signed,
+        } => {
+            let tls = match (tls_cert, tls_key) {
+                (Some(cert), Some(key)) => {
+                    Some(ServerTls::load(cert, key).expect("Unable to load TLS certificate"))
+                }
+                _ => tls_self_signed
+                    .then(|| ServerTls::self_signed().expect("Unable to generate TLS certificate")),
+            };
+            crusader_lib::serve::serve(serve::Config {
+                port: *port,
+                psk: psk.as_deref().map(PreSharedKey::new),
+                tls,
+            });
         }
         Commands::Plot { data, plot } => {
-            let result = RawResult::load(data).expect("Unable to load data");
+            let result = load(data);
             let file = crusader_lib::plot::save_graph(
                 &plot.config(),
                 &result.to_test_result(),
@@ -128,5 +658,168 @@ fn main() {
             );
             println!("Saved plot as {}", file);
         }
+        Commands::Compare {
+            data,
+            labels,
+            output,
+            plot,
+        } => {
+            let (names, results) = load_results(data, labels);
+            let results: Vec<_> = names
+                .iter()
+                .map(|name| name.as_str())
+                .zip(results.iter())
+                .collect();
+            let file = crusader_lib::plot::save_comparison(&plot.config(), &results, output);
+            println!("Saved plot as {}", file);
+            for file in
+                crusader_lib::plot::save_comparison_distributions(&plot.config(), &results, output)
+            {
+                println!("Saved plot as {}", file);
+            }
+        }
+        Commands::Report {
+            data,
+            labels,
+            output,
+        } => {
+            let (names, results) = load_results(data, labels);
+            let results: Vec<_> = names
+                .iter()
+                .map(|name| name.as_str())
+                .zip(results.iter())
+                .collect();
+            let output = output.clone().unwrap_or_else(|| {
+                if names.len() == 1 {
+                    names[0].clone()
+                } else {
+                    "report".to_owned()
+                }
+            });
+            let file = crusader_lib::report::save_report(&results, &output);
+            println!("Saved report as {}", file);
+        }
+        Commands::Info { data } => info(data),
+        Commands::Upgrade { data } => {
+            for path in data {
+                match RawResult::upgrade_file(path) {
+                    Ok(Some(version)) => println!(
+                        "Upgraded {} from format version {}",
+                        path.display(),
+                        version
+                    ),
+                    Ok(None) => println!("{} is already in the latest format", path.display()),
+                    Err(error) => println!("Unable to upgrade {}: {}", path.display(), error),
+                }
+            }
+        }
+        Commands::Import { data } => {
+            for path in data {
+                match crusader_lib::flent::import(path) {
+                    Ok(result) => {
+                        let name = path.file_name().unwrap_or_default().to_string_lossy();
+                        let name = name.trim_end_matches(".gz").trim_end_matches(".flent");
+                        let file = crusader_lib::test::save_raw(&result, name);
+                        println!("Saved raw data as {}", file);
+                    }
+                    Err(error) => println!("Unable to import {}: {}", path.display(), error),
+                }
+            }
+        }
+        Commands::Edit {
+            data,
+            from,
+            to,
+            keep,
+            drop,
+            output,
+        } => {
+            let results: Vec<_> = data.iter().map(|path| load(path)).collect();
+            let mut result = if results.len() > 1 {
+                RawResult::concat(&results)
+            } else {
+                Ok(results[0].clone())
+            };
+            if from.is_some() || to.is_some() {
+                result = result.and_then(|result| {
+                    result.crop(
+                        Duration::from_secs_f64(from.unwrap_or(0.0)),
+                        to.map(Duration::from_secs_f64).unwrap_or(result.duration),
+                    )
+                });
+            }
+            if !keep.is_empty() || !drop.is_empty() {
+                let phases: Vec<_> = Phase::ALL
+                    .into_iter()
+                    .filter(|phase| keep.is_empty() || keep.contains(phase))
+                    .filter(|phase| !drop.contains(phase))
+                    .collect();
+                result = result.and_then(|result| result.keep_phases(&phases));
+            }
+            let result = result.unwrap_or_else(|error| {
+                eprintln!("Unable to edit results: {}", error);
+                process::exit(1);
+            });
+            let output = output.clone().unwrap_or_else(|| {
+                data[0]
+                    .file_stem()
+                    .map(|name| format!("{} edited", name.to_string_lossy()))
+                    .unwrap_or_else(|| "edited".to_owned())
+            });
+            let file = crusader_lib::test::save_raw(&result, &output);
+            println!("Saved raw data as {}", file);
+        }
+        Commands::Anonymize {
+            data,
+            hash,
+            hash_key,
+            shift_days,
+        } => {
+            let hash_key = match hash_key {
+                Some(key) => Some(key.as_bytes().to_vec()),
+                None => hash.then(Anonymization::random_key),
+            };
+            let anonymization = Anonymization {
+                hash_key,
+                time_shift: *shift_days,
+            };
+            for path in data {
+                match load(path).anonymize(&anonymization) {
+                    Ok(result) => {
+                        let name = path
+                            .file_stem()
+                            .map(|name| format!("{} anonymized", name.to_string_lossy()))
+                            .unwrap_or_else(|| "anonymized".to_owned());
+                        let file = crusader_lib::test::save_raw(&result, &name);
+                        println!("Saved {} as {}", path.display(), file);
+                    }
+                    Err(error) => println!("Unable to anonymize {}: {}", path.display(), error),
+                }
+            }
+        }
+        Commands::Export { data } => {
+            for path in data {
+                let name = path
+                    .file_stem()
+                    .and_then(|name| name.to_str())
+                    .map(|name| name.trim_end_matches(".flent"))
+                    .unwrap_or("flent");
+                match crusader_lib::flent::save(&load(path), name) {
+                    Ok(file) => println!("Saved flent data as {}", file),
+                    Err(error) => println!("Unable to export {}: {}", path.display(), error),
+                }
+            }
+        }
+        Commands::Discover { port, timeout } => {
+            let servers =
+                crusader_lib::discovery::discover(*port, Duration::from_secs_f64(*timeout))
+                    .expect("Unable to look for servers");
+            if servers.is_empty() {
+                println!("No servers found");
+            }
+            for server in servers {
+                println!("{}", server);
+            }
+        }
     }
 }